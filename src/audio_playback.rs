@@ -1,66 +1,424 @@
-use super::{DummyTimeSource, MAX_DIRS, MAX_FILES, MAX_VOLUMES, SD};
-use audio_parser::AudioFile;
+use crate::crossfade::{CROSSFADE, Fade};
+use crate::decoder::g711::sample_8bit_to_i16;
+use crate::decoder::{Decoder, SampleFormat};
+use crate::downmix::Downmix;
+use crate::dynamics::Dynamics;
+use crate::eq::Equalizer;
+use crate::frame::StereoFrame;
+use crate::i2s::{DEVICE_RATE, I2sOut};
+use crate::replaygain::REPLAY_GAIN;
+use crate::resample::Resampler;
+use crate::volume::{Gain, ONE_Q15, VOLUME, scale_frames};
+use core::future::Future;
 use core::mem;
-use defmt::{error, info, panic, warn};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use defmt::{Format, error, info, warn};
 use embassy_futures::join::join;
-use embassy_rp::{peripherals::PIO0, pio_programs::i2s::PioI2sOut};
+use embassy_rp::peripherals::PIO0;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Channel, Receiver};
 use embassy_time::{Duration, Instant, Timer, with_timeout};
 
 const BUFFER_SIZE: usize = 512;
+// 32bit and float samples are the widest the conversion below handles
+const MAX_BYTES_PER_SAMPLE: usize = 4;
+// Files with more channels are read in several chunks per buffer
+const READ_CHANNELS: usize = 2;
+// Run the dac at `DEVICE_RATE` for every track instead of retuning it per track
+const ALWAYS_RESAMPLE: bool = false;
 
+const TRANSPORT_QUEUE: usize = 4;
+
+// Commands for the track that is playing
+pub static TRANSPORT: Channel<CriticalSectionRawMutex, Transport, TRANSPORT_QUEUE> = Channel::new();
+// Whether the dac is quiet, shared with the ui
+pub static PAUSED: AtomicBool = AtomicBool::new(true);
+// Milliseconds into the playing track, so the buttons can seek from there
+pub static POSITION: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Pause,
+    Resume,
+    Stop,
+    // Jump to a time in milliseconds from the start of the track
+    Seek(u32),
+}
+
+// Where the track after the playing one comes from, so it can start without a gap
+pub trait TrackSource<'a> {
+    // Opens the track that follows the playing one
+    fn open_next(&mut self) -> impl Future<Output = Option<Decoder<'a>>>;
+    // The track from `open_next` started playing
+    fn advance(&mut self);
+}
+
+// Why `play_file` returned
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Ended {
+    Finished,
+    Stopped,
+}
+
+// Decoded frames waiting to be converted to the dac sample rate
+pub struct ResampleState {
+    resampler: Resampler,
+    frames: [StereoFrame; BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl ResampleState {
+    pub fn new(from: u32, to: u32) -> Option<Self> {
+        Some(Self {
+            resampler: Resampler::new(from, to)?,
+            frames: [StereoFrame::SILENCE; BUFFER_SIZE],
+            start: 0,
+            len: 0,
+        })
+    }
+}
+
+// The next track fading in over the end of the playing one, it is decoded alongside it
+struct Crossfading {
+    fade: Fade,
+    convert: Converter,
+    resample: Option<ResampleState>,
+    eq: Equalizer,
+    frames: [StereoFrame; BUFFER_SIZE],
+    // frames of the next track mixed in so far
+    played: u64,
+}
+
+// TPDF dither for reducing 24bit, 32bit and float samples to the 16bit dac word
+pub struct Dither {
+    state: u32,
+}
+
+impl Dither {
+    pub fn new() -> Self {
+        Self { state: 0x9E37_79B9 }
+    }
+
+    // xorshift32
+    fn next(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    // Rounds a full scale sample to 16bit with +-1 lsb of triangular noise
+    fn requantize(&mut self, sample: i32) -> i16 {
+        let random = self.next();
+        // the sum of two uniform values has a triangular distribution
+        let noise = (random & 0xFFFF) as i32 + (random >> 16) as i32 - 0xFFFF;
+        (sample.saturating_add(noise).saturating_add(0x8000) >> 16) as i16
+    }
+}
+
+// How the decoded bytes of a track are turned into dac frames
+pub struct Converter {
+    bit_depth: u16,
+    channels: u16,
+    format: SampleFormat,
+    dither: Dither,
+    // only used for files with more than two channels
+    downmix: Option<Downmix>,
+}
+
+impl Converter {
+    pub fn new(audio_file: &Decoder) -> Self {
+        let channels = audio_file.channels();
+        Self {
+            bit_depth: audio_file.bit_depth(),
+            channels,
+            format: audio_file.sample_format(),
+            dither: Dither::new(),
+            downmix: (channels > 2).then(|| Downmix::new(channels, audio_file.channel_mask())),
+        }
+    }
+}
+
+// Plays `audio_file` and the tracks following it that have the same sample rate back to back.
+// `audio_file` is swapped for each following track, the one after them is left in `upcoming`
 pub async fn play_file<'a>(
-    i2s: &mut PioI2sOut<'static, PIO0, 0>,
-    audio_file: &mut AudioFile<'a, SD, DummyTimeSource, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
-) {
+    i2s: &mut I2sOut<'static, PIO0, 0>,
+    audio_file: &mut Decoder<'a>,
+    upcoming: &mut Option<Decoder<'a>>,
+    tracks: &mut impl TrackSource<'a>,
+    transport: Receiver<'_, CriticalSectionRawMutex, Transport, TRANSPORT_QUEUE>,
+) -> Ended {
     // create two audio buffers (back and front) which will take turns being
     // filled with new audio data and being sent to the pio fifo using dma
     // *2 is buffer swapping not stereo
-    let mut buf = [0u32; BUFFER_SIZE * 2];
+    let mut buf = [StereoFrame::SILENCE; BUFFER_SIZE * 2];
     let (mut back_buffer, mut front_buffer) = buf.split_at_mut(BUFFER_SIZE);
 
-    let sample_rate = audio_file.sample_rate;
-    let bit_depth = audio_file.bit_depth;
-    let channels = audio_file.num_channels;
+    let sample_rate = audio_file.sample_rate();
+    let bit_depth = audio_file.bit_depth();
+    let channels = audio_file.channels();
     info!(
         "Audio info:  {}hz, {}bit, {} channels",
         sample_rate, bit_depth, channels
     );
 
+    // Retune the dac for this track, or resample if the pio cannot generate its rate
+    let requested_rate = if ALWAYS_RESAMPLE {
+        DEVICE_RATE
+    } else {
+        sample_rate
+    };
+    if let Err(e) = i2s.set_sample_rate(requested_rate) {
+        warn!(
+            "Cannot output {}hz ({}), resampling to {}hz",
+            sample_rate, e, DEVICE_RATE
+        );
+        if let Err(e) = i2s.set_sample_rate(DEVICE_RATE) {
+            // with another system clock the pio may not hit `DEVICE_RATE` either,
+            // the resampler then converts to the rate it gets closest to
+            warn!("Cannot output {}hz ({}) either", DEVICE_RATE, e);
+            if let Err(e) = i2s.set_closest_sample_rate(DEVICE_RATE) {
+                error!("Failed to set sample rate: {}", e);
+                return Ended::Finished;
+            }
+        }
+    }
+    let dac_rate = i2s.sample_rate();
+    let mut resample = None;
+    if dac_rate != sample_rate {
+        resample = ResampleState::new(sample_rate, dac_rate);
+        if resample.is_none() {
+            error!("Cannot resample {}hz to {}hz", sample_rate, dac_rate);
+            return Ended::Finished;
+        }
+    }
+    let mut convert = Converter::new(audio_file);
+    let mut eq = Equalizer::new(dac_rate);
+    let mut gain = Gain::new(
+        VOLUME.level(),
+        REPLAY_GAIN.gain_q15(&audio_file.replay_gain()),
+    );
+    let mut dynamics = Dynamics::new(dac_rate);
+    let mut crossfade: Option<Crossfading> = None;
+    // the next track is only opened for a crossfade once per track
+    let mut fade_tried = false;
+    // frames of the playing track sent to the dac
+    let mut played: u64 = 0;
+    POSITION.store(0, Ordering::Relaxed);
+
     // Calculate the time needed to fill the buffer based on sample rate and buffer size
     let expected_fill_time =
-        Duration::from_millis((BUFFER_SIZE * 1000) as u64 / sample_rate as u64);
+        Duration::from_millis((BUFFER_SIZE * 1000) as u64 / i2s.sample_rate() as u64);
     info!(
         "Expected time to fill audio buffer: {}ms",
         expected_fill_time.as_millis()
     );
 
-    fill_back(audio_file, &mut front_buffer, bit_depth, channels).await;
+    fill_back(
+        audio_file,
+        &mut front_buffer,
+        &mut convert,
+        &mut resample,
+        &mut eq,
+    )
+    .await;
+    dynamics.process(&mut gain, &mut front_buffer);
+    let mut paused = false;
+    PAUSED.store(false, Ordering::Relaxed);
     loop {
-        let start = Instant::now();
-        if audio_file.read >= audio_file.end {
-            info!("Reached end of audio file");
-            break;
+        // handle transport commands, while paused nothing happens until the next one
+        loop {
+            let command = if paused {
+                transport.receive().await
+            } else {
+                match transport.try_receive() {
+                    Ok(command) => command,
+                    Err(_) => break,
+                }
+            };
+            info!("[PLAYBACK] {}", command);
+            match command {
+                Transport::Pause if !paused => {
+                    paused = true;
+                    PAUSED.store(true, Ordering::Relaxed);
+                    // the dac holds the last word it was sent, so leave it on silence.
+                    // The front buffer is kept and plays first on resume
+                    i2s.write(&[StereoFrame::SILENCE; 8]).await;
+                }
+                Transport::Pause => (),
+                Transport::Resume => {
+                    paused = false;
+                    PAUSED.store(false, Ordering::Relaxed);
+                }
+                Transport::Stop => {
+                    PAUSED.store(true, Ordering::Relaxed);
+                    return Ended::Stopped;
+                }
+                Transport::Seek(ms) => {
+                    if let Err(e) = audio_file.seek(ms).await {
+                        error!("Failed to seek to {}ms: {}", ms, e);
+                    }
+                    played = ms as u64 * dac_rate as u64 / 1000;
+                    POSITION.store(ms, Ordering::Relaxed);
+                    // the next track starts over once the end comes up again
+                    fade_tried = false;
+                    if crossfade.take().is_some() {
+                        if let Some(next) = upcoming.take() {
+                            next.close().await;
+                        }
+                    }
+                    // drop the audio from before the seek
+                    if let Some(state) = &mut resample {
+                        state.start = 0;
+                        state.len = 0;
+                    }
+                    fill_back(
+                        audio_file,
+                        &mut front_buffer,
+                        &mut convert,
+                        &mut resample,
+                        &mut eq,
+                    )
+                    .await;
+                    dynamics.process(&mut gain, &mut front_buffer);
+                }
+            }
         }
 
+        let start = Instant::now();
+        // the front buffer holds the end of the track
+        let last = audio_file.is_finished();
+
         // Write the front buffer data to the i2s DMA while the back buffer is being filled.
         let dma_future = i2s.write(&mut front_buffer);
 
         // Read the next chunk of data into the back buffer asynchronously while sending front buffer.
         let back_buffer_fut = async {
-            if let Err(_) = with_timeout(
+            if last {
+                return;
+            }
+            // equalized once the buffer is complete, the next track may still take over its end
+            let filled = match with_timeout(
                 expected_fill_time,
-                fill_back(audio_file, &mut back_buffer, bit_depth, channels),
+                read_back(audio_file, &mut back_buffer, &mut convert, &mut resample),
             )
             .await
             {
-                info!("Filling with silence due to timeout.");
-                // Fill with silence bc reading took too long
-                back_buffer.fill(0);
+                Ok(filled) => filled,
+                Err(_) => {
+                    info!("Filling with silence due to timeout.");
+                    // Fill with silence bc reading took too long
+                    back_buffer.fill(StereoFrame::SILENCE);
+                    back_buffer.len()
+                }
+            };
+
+            // start fading the next track in once the end of this one is within the crossfade
+            let fade_secs = CROSSFADE.seconds() as u64;
+            let remaining = audio_file.remaining_frames();
+            if let Some(remaining) = remaining.filter(|remaining| {
+                fade_secs > 0
+                    && crossfade.is_none()
+                    && !fade_tried
+                    && *remaining <= fade_secs * sample_rate as u64
+            }) {
+                fade_tried = true;
+                if upcoming.is_none() {
+                    *upcoming = tracks.open_next().await;
+                }
+                // tracks that need the dac retuned play after this one has ended instead
+                if let Some(next) = upcoming
+                    .as_ref()
+                    .filter(|next| next.sample_rate() == sample_rate)
+                {
+                    // the fade starts with this buffer
+                    let length = filled as u64 + remaining * dac_rate as u64 / sample_rate as u64;
+                    crossfade = Some(Crossfading {
+                        fade: Fade::new(length as u32),
+                        convert: Converter::new(next),
+                        resample: resample
+                            .as_ref()
+                            .and_then(|_| ResampleState::new(sample_rate, dac_rate)),
+                        eq: Equalizer::new(dac_rate),
+                        frames: [StereoFrame::SILENCE; BUFFER_SIZE],
+                        played: 0,
+                    });
+                }
             }
+            if let (Some(fading), Some(next)) = (&mut crossfade, upcoming.as_mut()) {
+                equalize(&mut eq, &mut back_buffer);
+                fill_back(
+                    next,
+                    &mut fading.frames,
+                    &mut fading.convert,
+                    &mut fading.resample,
+                    &mut fading.eq,
+                )
+                .await;
+                // the gain stage applies the ReplayGain of the playing track,
+                // bring the next one to its own loudness relative to that
+                let outgoing = REPLAY_GAIN.gain_q15(&audio_file.replay_gain()).max(1);
+                let incoming = REPLAY_GAIN.gain_q15(&next.replay_gain());
+                let relative = incoming as i64 * ONE_Q15 as i64 / outgoing as i64;
+                scale_frames(&mut fading.frames, relative as i32);
+                fading.fade.mix(&mut back_buffer, &fading.frames);
+                fading.played += back_buffer.len() as u64;
+
+                // the playing track faded out, carry on with the one that faded in
+                if audio_file.is_finished() {
+                    mem::replace(audio_file, upcoming.take().unwrap())
+                        .close()
+                        .await;
+                    tracks.advance();
+                    let fading = crossfade.take().unwrap();
+                    convert = fading.convert;
+                    resample = fading.resample;
+                    eq = fading.eq;
+                    played = fading.played;
+                    fade_tried = false;
+                }
+            } else {
+                // the track ended in this buffer, open the next one while the front buffer drains
+                // and carry on with it in the same buffer when the dac can stay at its rate
+                if filled < back_buffer.len() && audio_file.is_finished() {
+                    if upcoming.is_none() {
+                        *upcoming = tracks.open_next().await;
+                    }
+                    if let Some(next) = upcoming.take_if(|next| next.sample_rate() == sample_rate) {
+                        mem::replace(audio_file, next).close().await;
+                        tracks.advance();
+                        convert = Converter::new(audio_file);
+                        played = 0;
+                        fade_tried = false;
+                        read_back(
+                            audio_file,
+                            &mut back_buffer[filled..],
+                            &mut convert,
+                            &mut resample,
+                        )
+                        .await;
+                    }
+                }
+                equalize(&mut eq, &mut back_buffer);
+            }
+
+            // pick up volume changes from the ui, the limiter comes last so nothing clips
+            gain.set_level(
+                VOLUME.level(),
+                REPLAY_GAIN.gain_q15(&audio_file.replay_gain()),
+            );
+            dynamics.process(&mut gain, &mut back_buffer);
         };
 
         // Execute the two tasks concurrently.
         join(back_buffer_fut, dma_future).await;
+        played += BUFFER_SIZE as u64;
+        POSITION.store((played * 1000 / dac_rate as u64) as u32, Ordering::Relaxed);
+        if last {
+            info!("Reached end of audio file");
+            break;
+        }
 
         // Synchronize the timing with the sample rate (e.g., 48kHz, 44.1kHz)
         // Calculate the time elapsed since starting this loop
@@ -78,64 +436,140 @@ pub async fn play_file<'a>(
         // Wait for the next buffer to be ready
         Timer::after(delay_duration).await;
     }
+    Ended::Finished
 }
 
+// Returns how many frames are audio, the rest is silence after the end of the track
 pub async fn fill_back(
-    file_reader: &mut AudioFile<'_, SD, DummyTimeSource, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
-    back_buffer: &mut [u32],
-    bit_depth: u16,
-    channels: u16,
-) {
-    assert!(channels <= 2); // the buffer below assumes mono or stereo only
-    let mut read_buf = [0u8; BUFFER_SIZE * 4]; // 2 for 16bit audio & 2 for stereo
-    let mut read_slice = &mut read_buf[..(BUFFER_SIZE * ((bit_depth / 8) * channels) as usize)];
-
-    // read a frame of audio data from the sd card
-    if let Err(e) = file_reader.read_exact(&mut read_slice).await {
-        // Probably bc future was canceled
-        error!("Failed to read next audio buffer: {}", e)
+    file_reader: &mut Decoder<'_>,
+    back_buffer: &mut [StereoFrame],
+    convert: &mut Converter,
+    resample: &mut Option<ResampleState>,
+    eq: &mut Equalizer,
+) -> usize {
+    let filled = read_back(file_reader, back_buffer, convert, resample).await;
+    equalize(eq, back_buffer);
+    filled
+}
+
+// `fill_back` without the EQ, for a buffer that is not complete yet
+async fn read_back(
+    file_reader: &mut Decoder<'_>,
+    back_buffer: &mut [StereoFrame],
+    convert: &mut Converter,
+    resample: &mut Option<ResampleState>,
+) -> usize {
+    match resample {
+        Some(state) => resample_frames(file_reader, back_buffer, convert, state).await,
+        None => read_frames(file_reader, back_buffer, convert).await,
     }
+}
 
-    // decode if necisary
-    // ...
+// The silence after the end of a track is filtered too, so the filters ring out
+fn equalize(eq: &mut Equalizer, frames: &mut [StereoFrame]) {
+    eq.update();
+    eq.process(frames);
+}
 
-    to_uniform_stereo_32(read_slice, back_buffer, bit_depth, channels);
+// Returns how many frames are audio, like `fill_back`
+async fn resample_frames(
+    file_reader: &mut Decoder<'_>,
+    back_buffer: &mut [StereoFrame],
+    convert: &mut Converter,
+    state: &mut ResampleState,
+) -> usize {
+    // the resampler consumes a different number of frames than it produces,
+    // so keep the decoded frames around until they are used up
+    let mut produced = 0;
+    while produced < back_buffer.len() {
+        if state.start == state.len {
+            if file_reader.is_finished() {
+                back_buffer[produced..].fill(StereoFrame::SILENCE);
+                return produced;
+            }
+            state.len = read_frames(file_reader, &mut state.frames, convert).await;
+            state.start = 0;
+        }
 
-    // add gain if needed
-    // back_buffer.iter_mut().for_each(|sample| {
-    //     let left = apply_gain((*sample >> 16) as u16, gain);
-    //     let right = apply_gain(*sample as u16, gain);
-    //     *sample = (left as u32) << 16 | right as u32;
-    // });
+        let (consumed, written) = state.resampler.process(
+            &state.frames[state.start..state.len],
+            &mut back_buffer[produced..],
+        );
+        state.start += consumed;
+        produced += written;
+    }
+    produced
 }
 
-// converts any bit rate and channel into 32bit stereo audio
-fn to_uniform_stereo_32(in_buf: &mut [u8], out_buf: &mut [u32], bit_depth: u16, channels: u16) {
-    if channels > 2 {
-        panic!("unsupported number of channels")
+// Returns how many frames are audio, like `fill_back`
+async fn read_frames(
+    file_reader: &mut Decoder<'_>,
+    back_buffer: &mut [StereoFrame],
+    convert: &mut Converter,
+) -> usize {
+    let mut read_buf = [0u8; BUFFER_SIZE * MAX_BYTES_PER_SAMPLE * READ_CHANNELS];
+    let frame_len = (convert.bit_depth / 8 * convert.channels) as usize;
+
+    let mut filled = 0;
+    for frames in back_buffer.chunks_mut(read_buf.len() / frame_len) {
+        let mut read_slice = &mut read_buf[..frames.len() * frame_len];
+
+        // read a frame of audio data from the sd card
+        match file_reader.read_exact(&mut read_slice).await {
+            Ok(read) => filled += read / frame_len,
+            Err(e) => {
+                // playing on would repeat stale samples, end the track with silence instead
+                error!("Failed to read next audio buffer, ending the track: {}", e);
+                file_reader.finish();
+                back_buffer[filled..].fill(StereoFrame::SILENCE);
+                return filled;
+            }
+        }
+
+        to_stereo_frames(read_slice, frames, convert);
+    }
+    filled
+}
+
+// converts any bit rate and channel count into 16bit stereo frames
+fn to_stereo_frames(in_buf: &mut [u8], out_buf: &mut [StereoFrame], convert: &mut Converter) {
+    let (bit_depth, channels, format) = (convert.bit_depth, convert.channels, convert.format);
+    let dither = &mut convert.dither;
+
+    // surround audio is mixed down before it is requantized
+    if let Some(downmix) = &convert.downmix {
+        let bytes = (bit_depth / 8) as usize;
+        out_buf
+            .iter_mut()
+            .zip(in_buf.chunks(bytes * channels as usize))
+            .for_each(|(dma, read)| {
+                let frame = read
+                    .chunks(bytes)
+                    .map(|sample| sample_to_i32(sample, format));
+                let (left, right) = downmix.mix(frame);
+                *dma = StereoFrame::new(dither.requantize(left), dither.requantize(right));
+            });
+        return;
     }
 
     match bit_depth {
-        // unsigned 8bit audio
+        // unsigned 8bit, µ-law and A-law audio
         8 => {
             if channels == 1 {
                 out_buf
                     .iter_mut()
                     .zip(in_buf.as_ref())
                     .for_each(|(dma, read)| {
-                        // because u8 is so quiet after conversion add gain to normalize it
-                        let read_boosted = apply_gain(*read as u16, 128.) as u32;
-                        *dma = read_boosted << 16 | read_boosted;
+                        *dma = StereoFrame::mono(sample_8bit_to_i16(*read, format));
                     });
             } else if channels == 2 {
                 out_buf
                     .iter_mut()
                     .zip(in_buf.as_ref().chunks(2)) // get both L&R interleaved samples
                     .for_each(|(dma, read)| {
-                        // because u8 is so quiet after conversion add gain to normalize it
-                        let l_read_boosted = apply_gain(read[0] as u16, 128.) as u32;
-                        let r_read_boosted = apply_gain(read[1] as u16, 128.) as u32;
-                        *dma = l_read_boosted << 16 | r_read_boosted;
+                        let l_read = sample_8bit_to_i16(read[0], format);
+                        let r_read = sample_8bit_to_i16(read[1], format);
+                        *dma = StereoFrame::new(l_read, r_read);
                     });
             }
         }
@@ -146,8 +580,7 @@ fn to_uniform_stereo_32(in_buf: &mut [u8], out_buf: &mut [u32], bit_depth: u16,
                     .iter_mut()
                     .zip(in_buf.as_ref().chunks(2))
                     .for_each(|(dma, read)| {
-                        let read = i16::from_le_bytes([read[0], read[1]]) as u32;
-                        *dma = (read) << 16 | read;
+                        *dma = StereoFrame::mono(i16::from_le_bytes([read[0], read[1]]));
                     });
             } else if channels == 2 {
                 out_buf
@@ -156,22 +589,44 @@ fn to_uniform_stereo_32(in_buf: &mut [u8], out_buf: &mut [u32], bit_depth: u16,
                     .for_each(|(dma, read)| {
                         let l_read = i16::from_le_bytes([read[0], read[1]]);
                         let r_read = i16::from_le_bytes([read[2], read[3]]);
-                        *dma = (l_read as u32) << 16 | r_read as u32;
+                        *dma = StereoFrame::new(l_read, r_read);
                     });
             }
         }
-        _ => {
-            panic!("unsupported bit depth")
+        // signed 24bit, 32bit and float audio, dithered down to 16bit
+        24 | 32 => {
+            let bytes = (bit_depth / 8) as usize;
+            out_buf
+                .iter_mut()
+                .zip(in_buf.chunks(bytes * channels as usize))
+                .for_each(|(dma, read)| {
+                    let left = dither.requantize(sample_to_i32(&read[..bytes], format));
+                    *dma = if channels == 2 {
+                        StereoFrame::new(
+                            left,
+                            dither.requantize(sample_to_i32(&read[bytes..], format)),
+                        )
+                    } else {
+                        StereoFrame::mono(left)
+                    };
+                });
         }
+        // other formats are rejected when the file is opened
+        _ => out_buf.fill(StereoFrame::SILENCE),
     }
 }
 
-fn apply_gain(sample: u16, gain: f32) -> u16 {
-    // Scale the sample by the gain value
-    let scaled_sample = sample as f32 * gain;
-
-    // Convert the scaled sample back to an integer using rounding
-    let rounded_sample = (scaled_sample + 0.5) as u16;
-
-    return rounded_sample;
+// Reads a little endian integer or 32bit float sample as a full scale i32
+fn sample_to_i32(bytes: &[u8], format: SampleFormat) -> i32 {
+    match (format, bytes.len()) {
+        (_, 1) => (sample_8bit_to_i16(bytes[0], format) as i32) << 16,
+        (_, 2) => (i16::from_le_bytes([bytes[0], bytes[1]]) as i32) << 16,
+        (SampleFormat::Float, _) => {
+            let sample = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            // out of range samples clip and nan turns into silence
+            (sample.clamp(-1.0, 1.0) * i32::MAX as f32) as i32
+        }
+        (_, 3) => i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]),
+        _ => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    }
 }
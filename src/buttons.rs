@@ -0,0 +1,217 @@
+// Push buttons on the front of the player, each one wired from its gpio to ground
+
+use crate::audio_playback::{PAUSED, POSITION, TRANSPORT, Transport};
+use crate::queue::{COMMANDS, Command, IDLE, Repeat, SHUFFLE, Shuffle};
+use crate::volume::VOLUME;
+use core::sync::atomic::Ordering;
+use defmt::{Format, info};
+use embassy_futures::join::{join, join3, join4};
+use embassy_rp::Peripheral;
+use embassy_rp::gpio::{Input, Pin, Pull};
+use embassy_time::{Duration, Timer, with_timeout};
+
+// Contacts bounce for a few ms after they close
+const DEBOUNCE: Duration = Duration::from_millis(20);
+// Held at least this long, a press does something else
+const HOLD: Duration = Duration::from_millis(600);
+// How often a held volume button steps the volume
+const REPEAT: Duration = Duration::from_millis(150);
+// A held skip button seeks by this many ms, and again every `SEEK_REPEAT` until it is let go
+const SEEK_STEP: u32 = 10_000;
+const SEEK_REPEAT: Duration = Duration::from_millis(400);
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Press {
+    Short,
+    Hold,
+}
+
+pub struct Button<'d> {
+    pin: Input<'d>,
+}
+
+impl<'d> Button<'d> {
+    pub fn new(pin: impl Peripheral<P = impl Pin> + 'd) -> Self {
+        Self {
+            pin: Input::new(pin, Pull::Up),
+        }
+    }
+
+    // Waits for the button to be pressed, and for it to be released or held.
+    // A button still held from the last press has to be let go first
+    pub async fn press(&mut self) -> Press {
+        loop {
+            self.pin.wait_for_high().await;
+            self.pin.wait_for_low().await;
+            Timer::after(DEBOUNCE).await;
+            if self.pin.is_low() {
+                break;
+            }
+        }
+        match with_timeout(HOLD, self.pin.wait_for_high()).await {
+            Ok(()) => {
+                Timer::after(DEBOUNCE).await;
+                Press::Short
+            }
+            Err(_) => Press::Hold,
+        }
+    }
+
+    // Waits until a held button is let go
+    pub async fn release(&mut self) {
+        self.pin.wait_for_high().await;
+        Timer::after(DEBOUNCE).await;
+    }
+}
+
+pub struct Buttons<'d> {
+    pub volume_up: Button<'d>,
+    pub volume_down: Button<'d>,
+    pub play: Button<'d>,
+    pub next: Button<'d>,
+    pub previous: Button<'d>,
+    pub mode: Button<'d>,
+    pub browse: Button<'d>,
+}
+
+impl Buttons<'_> {
+    // Handles the buttons forever
+    pub async fn run(self) {
+        let Self {
+            mut volume_up,
+            mut volume_down,
+            mut play,
+            mut next,
+            mut previous,
+            mut mode,
+            mut browse,
+        } = self;
+        join(
+            join3(
+                volume(&mut volume_up, || VOLUME.up()),
+                volume(&mut volume_down, || VOLUME.down()),
+                start_or_stop(&mut play),
+            ),
+            join4(
+                skip(&mut next, Command::Next, |ms| ms.saturating_add(SEEK_STEP)),
+                skip(&mut previous, Command::Previous, |ms| {
+                    ms.saturating_sub(SEEK_STEP)
+                }),
+                cycle_mode(&mut mode),
+                pick_music(&mut browse),
+            ),
+        )
+        .await;
+    }
+}
+
+// Steps the volume once per press, and over and over while the button is held
+async fn volume(button: &mut Button<'_>, step: impl Fn()) {
+    loop {
+        let press = button.press().await;
+        step();
+        if press == Press::Hold {
+            while with_timeout(REPEAT, button.release()).await.is_err() {
+                step();
+            }
+        }
+        info!("[BUTTONS] volume {}", VOLUME.level());
+    }
+}
+
+// Pauses and resumes the playing track, held it stops and clears the queue.
+// When nothing is queued it starts the whole library
+async fn start_or_stop(button: &mut Button<'_>) {
+    loop {
+        let press = button.press().await;
+        if IDLE.load(Ordering::Relaxed) {
+            if press == Press::Short {
+                COMMANDS.send(Command::PlayAll).await;
+            }
+            continue;
+        }
+        let transport = match press {
+            Press::Short if PAUSED.load(Ordering::Relaxed) => Transport::Resume,
+            Press::Short => Transport::Pause,
+            Press::Hold => Transport::Stop,
+        };
+        TRANSPORT.send(transport).await;
+    }
+}
+
+// Moves through the queue with `command`, held it seeks in the playing track to `seek(position)`
+async fn skip(button: &mut Button<'_>, command: Command, seek: impl Fn(u32) -> u32) {
+    loop {
+        if button.press().await == Press::Short {
+            COMMANDS.send(command).await;
+            continue;
+        }
+        loop {
+            if !IDLE.load(Ordering::Relaxed) {
+                let ms = seek(POSITION.load(Ordering::Relaxed));
+                TRANSPORT.send(Transport::Seek(ms)).await;
+            }
+            if with_timeout(SEEK_REPEAT, button.release()).await.is_ok() {
+                break;
+            }
+        }
+    }
+}
+
+// Steps through repeat off, all and one, held it steps through shuffle off, tracks and albums.
+// The queue picks both up once it moves on from the playing track
+async fn cycle_mode(button: &mut Button<'_>) {
+    let mut repeat = Repeat::Off;
+    // the mode last sent, the one from the settings file until then
+    let mut shuffle = None;
+    loop {
+        let command = match button.press().await {
+            Press::Short => {
+                repeat = match repeat {
+                    Repeat::Off => Repeat::All,
+                    Repeat::All => Repeat::One,
+                    Repeat::One => Repeat::Off,
+                };
+                Command::SetRepeat(repeat)
+            }
+            Press::Hold => {
+                let next = match shuffle.unwrap_or_else(|| SHUFFLE.get().0) {
+                    Shuffle::Off => Shuffle::Tracks,
+                    Shuffle::Tracks => Shuffle::Albums,
+                    Shuffle::Albums => Shuffle::Off,
+                };
+                shuffle = Some(next);
+                Command::SetShuffle(next)
+            }
+        };
+        COMMANDS.send(command).await;
+    }
+}
+
+// Plays the albums of an artist one after another, held it plays everything by the next artist.
+// The queue starts over with the first artist or album once it runs out of them
+async fn pick_music(button: &mut Button<'_>) {
+    let mut artist = 0;
+    let mut album = 0;
+    // the first artist starts out picked, holding the button moves on from it
+    let mut picked = false;
+    loop {
+        let command = match button.press().await {
+            Press::Short => {
+                picked = true;
+                let command = Command::PlayAlbum { artist, album };
+                album = album.wrapping_add(1);
+                command
+            }
+            Press::Hold => {
+                if picked {
+                    artist = artist.wrapping_add(1);
+                }
+                picked = true;
+                album = 0;
+                Command::PlayArtist(artist)
+            }
+        };
+        COMMANDS.send(command).await;
+    }
+}
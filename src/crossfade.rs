@@ -0,0 +1,168 @@
+// Crossfading from the end of one track into the start of the next.
+// The length is a setting shared with the ui like the volume, the mix itself is fixed point
+
+use crate::frame::StereoFrame;
+use crate::resample::{cos_pi_q30, sin_pi_q30};
+use crate::settings::parse_fixed;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+pub const MAX_CROSSFADE_SECS: u8 = 12;
+
+const ONE_Q30: i64 = 1 << 30;
+// Fractional bits of the per frame gain ramp
+const RAMP_BITS: u32 = 15;
+
+// Crossfade length in seconds, 0 plays tracks back to back
+pub static CROSSFADE: Crossfade = Crossfade::new(0);
+
+pub struct Crossfade {
+    seconds: AtomicU8,
+}
+
+impl Crossfade {
+    pub const fn new(seconds: u8) -> Self {
+        Self {
+            seconds: AtomicU8::new(seconds),
+        }
+    }
+
+    pub fn seconds(&self) -> u8 {
+        self.seconds.load(Ordering::Relaxed)
+    }
+
+    // Applies a `crossfade = <seconds>` line of the settings file, false for other keys
+    pub fn set(&self, key: &[u8], value: &[u8]) -> bool {
+        if key != b"crossfade" {
+            return false;
+        }
+        if let Some(seconds) = parse_fixed(value, 0) {
+            let seconds = seconds.clamp(0, MAX_CROSSFADE_SECS as i32) as u8;
+            self.seconds.store(seconds, Ordering::Relaxed);
+        }
+        true
+    }
+}
+
+// Progress of a crossfade, in dac frames
+pub struct Fade {
+    position: u32,
+    length: u32,
+}
+
+impl Fade {
+    pub fn new(length: u32) -> Self {
+        Self {
+            position: 0,
+            length: length.max(1),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.position >= self.length
+    }
+
+    // Mixes `incoming` into `outgoing` and moves the fade on by the length of the buffers.
+    // The gains are worked out for both ends of the buffer and ramped linearly in between
+    pub fn mix(&mut self, outgoing: &mut [StereoFrame], incoming: &[StereoFrame]) {
+        let frames = outgoing.len().min(incoming.len()) as u32;
+        if frames == 0 {
+            return;
+        }
+        let end = self.position.saturating_add(frames);
+        let (out_from, in_from) = gains_q15(self.position, self.length);
+        let (out_to, in_to) = gains_q15(end, self.length);
+
+        let ramp = |from: i32, to: i32| {
+            let step = ((to - from) << RAMP_BITS) / frames as i32;
+            (from << RAMP_BITS, step)
+        };
+        let (mut out_gain, out_step) = ramp(out_from, out_to);
+        let (mut in_gain, in_step) = ramp(in_from, in_to);
+
+        for (out, inc) in outgoing.iter_mut().zip(incoming) {
+            let (gain_out, gain_in) = (out_gain >> RAMP_BITS, in_gain >> RAMP_BITS);
+            *out = StereoFrame::new(
+                mix_sample(out.left(), gain_out, inc.left(), gain_in),
+                mix_sample(out.right(), gain_out, inc.right(), gain_in),
+            );
+            out_gain += out_step;
+            in_gain += in_step;
+        }
+        self.position = end;
+    }
+}
+
+fn mix_sample(outgoing: i16, gain_out: i32, incoming: i16, gain_in: i32) -> i16 {
+    let sum = outgoing as i32 * gain_out + incoming as i32 * gain_in;
+    ((sum + (1 << 14)) >> 15).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+// Q15 gains of the outgoing and incoming track `position` frames into a fade of `length`.
+// Equal power, a quarter turn of cos and sin, keeps unrelated tracks at the same loudness
+pub fn gains_q15(position: u32, length: u32) -> (i32, i32) {
+    let position = position.min(length) as i64;
+    let x = ONE_Q30 / 2 * position / length.max(1) as i64;
+    ((cos_pi_q30(x) >> 15) as i32, (sin_pi_q30(x) >> 15) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FULL: i32 = 1 << 15;
+
+    #[test]
+    fn gains_start_on_the_outgoing_and_end_on_the_incoming_track() {
+        for length in [1, 100, 44_100 * 12] {
+            let (out, inc) = gains_q15(0, length);
+            assert!(out.abs_diff(FULL) <= 1 && inc == 0, "{length}: {out} {inc}");
+            let (out, inc) = gains_q15(length, length);
+            assert!(
+                out.abs() <= 1 && inc.abs_diff(FULL) <= 1,
+                "{length}: {out} {inc}"
+            );
+            // past the end stays at the end
+            assert_eq!(gains_q15(length + 5, length), gains_q15(length, length));
+        }
+    }
+
+    #[test]
+    fn gains_keep_the_power_constant() {
+        let length = 44_100;
+        for position in (0..=length).step_by(441) {
+            let (out, inc) = gains_q15(position, length);
+            let power = (out as i64).pow(2) + (inc as i64).pow(2);
+            let full = (FULL as i64).pow(2);
+            // within 0.01 dB
+            assert!(
+                power.abs_diff(full) * 1000 <= full as u64,
+                "{position}: {power}"
+            );
+        }
+    }
+
+    #[test]
+    fn fade_goes_from_the_outgoing_to_the_incoming_track() {
+        let mut fade = Fade::new(64);
+        let incoming = [StereoFrame::new(-8000, 4000); 64];
+        let mut outgoing = [StereoFrame::new(8000, -4000); 64];
+        fade.mix(&mut outgoing[..32], &incoming[..32]);
+        assert!(!fade.is_done());
+        fade.mix(&mut outgoing[32..], &incoming[32..]);
+        assert!(fade.is_done());
+
+        let first = outgoing[0];
+        assert!(first.left().abs_diff(8000) <= 1 && first.right().abs_diff(-4000) <= 1);
+        // a frame before the end, a little of the outgoing track is still there
+        let last = outgoing[63];
+        assert!(last.left().abs_diff(-8000) <= 400 && last.right().abs_diff(4000) <= 400);
+        assert!(last.left() > -8000 && last.right() < 4000);
+
+        // once done only the incoming track is left
+        let mut outgoing = [StereoFrame::new(8000, -4000); 8];
+        fade.mix(&mut outgoing, &incoming[..8]);
+        for frame in outgoing {
+            assert!(frame.left().abs_diff(-8000) <= 1 && frame.right().abs_diff(4000) <= 1);
+        }
+    }
+}
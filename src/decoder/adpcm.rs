@@ -0,0 +1,296 @@
+// IMA and Microsoft 4bit ADPCM as stored in wav files.
+// Both are split into blocks of `block_align` bytes that start with a header holding the decoder
+// state, so every block decodes on its own into 16bit pcm. The last block of a file may be short
+
+use defmt::Format;
+
+// Largest block that is decoded, voice recorders use 256 to 2048 bytes
+pub const MAX_BLOCK_LEN: usize = 4096;
+// A mono block holds two samples per byte plus the ones stored in its header
+const MAX_BLOCK_SAMPLES: usize = MAX_BLOCK_LEN * 2 + 2;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Ima,
+    Ms,
+}
+
+impl Codec {
+    // Bytes of block header per channel
+    fn header_len(self) -> usize {
+        match self {
+            Codec::Ima => 4,
+            Codec::Ms => 7,
+        }
+    }
+}
+
+pub struct AdpcmDecoder {
+    codec: Codec,
+    channels: usize,
+    block_align: usize,
+    block: [u8; MAX_BLOCK_LEN],
+    // interleaved samples of the last decoded block
+    pcm: [i16; MAX_BLOCK_SAMPLES],
+    pcm_start: usize,
+    pcm_end: usize,
+}
+
+impl AdpcmDecoder {
+    // Returns `None` for layouts the block buffers cannot hold
+    pub fn new(codec: Codec, channels: u16, block_align: u16) -> Option<Self> {
+        let (channels, block_align) = (channels as usize, block_align as usize);
+        if !(1..=2).contains(&channels)
+            || block_align > MAX_BLOCK_LEN
+            || block_align <= codec.header_len() * channels
+        {
+            return None;
+        }
+
+        Some(Self {
+            codec,
+            channels,
+            block_align,
+            block: [0; MAX_BLOCK_LEN],
+            pcm: [0; MAX_BLOCK_SAMPLES],
+            pcm_start: 0,
+            pcm_end: 0,
+        })
+    }
+
+    pub fn block_align(&self) -> usize {
+        self.block_align
+    }
+
+    // Samples per channel in a whole block
+    pub fn samples_per_block(&self) -> usize {
+        let data_len = self.block_align - self.codec.header_len() * self.channels;
+        match self.codec {
+            // the header holds one sample, the data is grouped in 4 bytes per channel
+            Codec::Ima => data_len / (4 * self.channels) * 8 + 1,
+            // the header holds two samples
+            Codec::Ms => data_len * 2 / self.channels + 2,
+        }
+    }
+
+    // Decoded samples that have not been handed out yet
+    pub fn pcm(&self) -> &[i16] {
+        &self.pcm[self.pcm_start..self.pcm_end]
+    }
+
+    pub fn consume(&mut self, samples: usize) {
+        self.pcm_start = (self.pcm_start + samples).min(self.pcm_end);
+    }
+
+    // Buffer the next `len` bytes of the data chunk are read into before calling `decode`
+    pub fn block_mut(&mut self, len: usize) -> &mut [u8] {
+        &mut self.block[..len.min(self.block_align)]
+    }
+
+    // Decodes the first `len` bytes of the block buffer, a short block is decoded as far as it goes
+    pub fn decode(&mut self, len: usize) {
+        let block = &self.block[..len.min(self.block_align)];
+        let written = match self.codec {
+            Codec::Ima => decode_ima(block, self.channels, &mut self.pcm),
+            Codec::Ms => decode_ms(block, self.channels, &mut self.pcm),
+        };
+        self.pcm_start = 0;
+        self.pcm_end = written;
+    }
+}
+
+const IMA_INDEX: [i32; 8] = [-1, -1, -1, -1, 2, 4, 6, 8];
+const IMA_STEP: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449,
+    494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272,
+    2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493,
+    10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+#[derive(Clone, Copy, Default)]
+struct ImaState {
+    predictor: i32,
+    index: i32,
+}
+
+impl ImaState {
+    fn decode(&mut self, nibble: u8) -> i16 {
+        let step = IMA_STEP[self.index as usize];
+        let mut diff = step >> 3;
+        if nibble & 1 != 0 {
+            diff += step >> 2;
+        }
+        if nibble & 2 != 0 {
+            diff += step >> 1;
+        }
+        if nibble & 4 != 0 {
+            diff += step;
+        }
+        if nibble & 8 != 0 {
+            diff = -diff;
+        }
+        self.predictor = (self.predictor + diff).clamp(i16::MIN as i32, i16::MAX as i32);
+        self.index = (self.index + IMA_INDEX[(nibble & 7) as usize]).clamp(0, 88);
+        self.predictor as i16
+    }
+}
+
+fn decode_ima(block: &[u8], channels: usize, pcm: &mut [i16]) -> usize {
+    let header_len = 4 * channels;
+    if block.len() < header_len {
+        return 0;
+    }
+
+    let mut state = [ImaState::default(); 2];
+    for (ch, state) in state.iter_mut().enumerate().take(channels) {
+        let header = &block[ch * 4..];
+        state.predictor = i16::from_le_bytes([header[0], header[1]]) as i32;
+        state.index = (header[2] as i32).min(88);
+        pcm[ch] = state.predictor as i16;
+    }
+
+    // every channel takes turns with 4 bytes holding 8 samples, low nibble first
+    let mut written = channels;
+    for group in block[header_len..].chunks_exact(4 * channels) {
+        for (ch, bytes) in group.chunks_exact(4).enumerate() {
+            for (i, byte) in bytes.iter().enumerate() {
+                pcm[written + 2 * i * channels + ch] = state[ch].decode(byte & 0xF);
+                pcm[written + (2 * i + 1) * channels + ch] = state[ch].decode(byte >> 4);
+            }
+        }
+        written += 8 * channels;
+    }
+    written
+}
+
+// Every ms adpcm file starts its coefficient table with these, files that define more are rare
+const MS_COEFFICIENTS: [[i32; 2]; 7] = [
+    [256, 0],
+    [512, -256],
+    [0, 0],
+    [192, 64],
+    [240, 0],
+    [460, -208],
+    [392, -232],
+];
+// Coefficient pairs a ms adpcm file may define, more than the standard ones are not supported
+pub const MS_PREDICTORS: usize = MS_COEFFICIENTS.len();
+const MS_ADAPTATION: [i32; 16] = [
+    230, 230, 230, 230, 307, 409, 512, 614, 768, 614, 512, 409, 307, 230, 230, 230,
+];
+
+#[derive(Clone, Copy, Default)]
+struct MsState {
+    coefficients: [i32; 2],
+    delta: i32,
+    // the last and second to last sample
+    sample1: i32,
+    sample2: i32,
+}
+
+impl MsState {
+    fn decode(&mut self, nibble: u8) -> i16 {
+        let [c1, c2] = self.coefficients;
+        let predicted = (self.sample1 * c1 + self.sample2 * c2) >> 8;
+        // sign extend the nibble
+        let signed = ((nibble << 4) as i8 >> 4) as i32;
+        let sample = (predicted + signed * self.delta).clamp(i16::MIN as i32, i16::MAX as i32);
+
+        self.sample2 = self.sample1;
+        self.sample1 = sample;
+        self.delta = ((MS_ADAPTATION[nibble as usize] * self.delta) >> 8).max(16);
+        sample as i16
+    }
+}
+
+fn decode_ms(block: &[u8], channels: usize, pcm: &mut [i16]) -> usize {
+    let header_len = 7 * channels;
+    if block.len() < header_len {
+        return 0;
+    }
+
+    // the header holds each field for all channels before the next field
+    let field = |offset: usize, ch: usize| {
+        let at = offset + ch * 2;
+        i16::from_le_bytes([block[at], block[at + 1]]) as i32
+    };
+    let mut state = [MsState::default(); 2];
+    for (ch, state) in state.iter_mut().enumerate().take(channels) {
+        let predictor = block[ch] as usize;
+        state.coefficients = MS_COEFFICIENTS[predictor.min(MS_COEFFICIENTS.len() - 1)];
+        state.delta = field(channels, ch);
+        state.sample1 = field(3 * channels, ch);
+        state.sample2 = field(5 * channels, ch);
+        // the older sample comes first
+        pcm[ch] = state.sample2 as i16;
+        pcm[channels + ch] = state.sample1 as i16;
+    }
+
+    // samples follow interleaved, high nibble first
+    let mut written = 2 * channels;
+    for byte in &block[header_len..] {
+        for nibble in [byte >> 4, byte & 0xF] {
+            pcm[written] = state[written % channels].decode(nibble);
+            written += 1;
+        }
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reference values, as Python's audioop decodes the nibbles of each channel
+    #[test]
+    fn decodes_a_stereo_ima_block() {
+        let mut decoder = AdpcmDecoder::new(Codec::Ima, 2, 16).unwrap();
+        assert_eq!(decoder.samples_per_block(), 9);
+        let block = [
+            // predictor and step index of each channel
+            0x64, 0x00, 20, 0, 0x30, 0xF8, 40, 0, // left, then right
+            0x17, 0x8F, 0x3A, 0xC4, 0x70, 0x09, 0xFF, 0x88,
+        ];
+        decoder.block_mut(block.len()).copy_from_slice(&block);
+        decoder.decode(block.len());
+
+        let left = [100, 193, 232, 51, 25, -93, 57, 233, 20];
+        let right = [
+            -2000, -1958, -1384, -1630, -1556, -2576, -4761, -5073, -5357,
+        ];
+        let pcm = decoder.pcm();
+        assert_eq!(pcm.len(), 18);
+        for i in 0..9 {
+            assert_eq!(pcm[2 * i], left[i], "left {}", i);
+            assert_eq!(pcm[2 * i + 1], right[i], "right {}", i);
+        }
+    }
+
+    #[test]
+    fn decodes_a_mono_ms_block() {
+        let mut decoder = AdpcmDecoder::new(Codec::Ms, 1, 11).unwrap();
+        assert_eq!(decoder.samples_per_block(), 10);
+        let block = [
+            // predictor, delta, the last sample and the one before
+            1, 20, 0, 0x2C, 0x01, 0xC8, 0x00, // then 8 samples, high nibble first
+            0x12, 0x7F, 0x80, 0xE9,
+        ];
+        decoder.block_mut(block.len()).copy_from_slice(&block);
+        decoder.decode(block.len());
+
+        assert_eq!(
+            decoder.pcm(),
+            [200, 300, 420, 574, 840, 1068, 1024, 980, 754, -39]
+        );
+    }
+
+    #[test]
+    fn a_short_block_decodes_as_far_as_it_goes() {
+        let mut decoder = AdpcmDecoder::new(Codec::Ms, 1, 11).unwrap();
+        let block = [1, 20, 0, 0x2C, 0x01, 0xC8, 0x00, 0x12];
+        decoder.block_mut(block.len()).copy_from_slice(&block);
+        decoder.decode(block.len());
+        assert_eq!(decoder.pcm(), [200, 300, 420, 574]);
+    }
+}
@@ -0,0 +1,318 @@
+use super::g711::{ALAW_SILENCE, MULAW_SILENCE};
+use super::{SampleFormat, read_all};
+use crate::file_reader::{SdError, SdFile};
+use defmt::{Format, info};
+
+// Largest part of the COMM chunk that is read, AIFC adds the compression type and its name
+const MAX_COMM_LEN: usize = 22;
+// AIFF orders 5 and 6 channels differently from wav, up to 4 the layouts match
+const MAX_CHANNELS: u16 = 4;
+
+#[derive(Debug, Format)]
+pub enum Error {
+    Sd(SdError),
+    // The file does not start with a FORM/AIFF or FORM/AIFC header
+    NotAiff,
+    MissingCommon,
+    MissingSoundData,
+    // The samples cannot be converted for the dac
+    Unsupported {
+        compression: [u8; 4],
+        bit_depth: u16,
+        channels: u16,
+    },
+}
+
+impl From<SdError> for Error {
+    fn from(e: SdError) -> Self {
+        Error::Sd(e)
+    }
+}
+
+#[derive(Debug, Format, Clone, Copy)]
+pub struct CommonChunk {
+    pub channels: u16,
+    pub frames: u32,
+    pub sample_size: u16,
+    pub sample_rate: u32,
+    // always `NONE` for plain AIFF
+    pub compression: [u8; 4],
+}
+
+impl CommonChunk {
+    pub fn parse(b: &[u8], aifc: bool) -> Option<Self> {
+        let b = b.get(..if aifc { 22 } else { 18 })?;
+        Some(Self {
+            channels: u16::from_be_bytes([b[0], b[1]]),
+            frames: u32::from_be_bytes([b[2], b[3], b[4], b[5]]),
+            sample_size: u16::from_be_bytes([b[6], b[7]]),
+            sample_rate: extended_to_u32(b[8..18].try_into().ok()?)?,
+            compression: if aifc {
+                b[18..22].try_into().ok()?
+            } else {
+                *b"NONE"
+            },
+        })
+    }
+}
+
+// Converts an 80bit ieee 754 extended float to an integer, rounded to nearest
+fn extended_to_u32(b: [u8; 10]) -> Option<u32> {
+    // negative rates make no sense
+    if b[0] & 0x80 != 0 {
+        return None;
+    }
+    let exponent = u16::from_be_bytes([b[0], b[1]]) as i32;
+    let mantissa = u64::from_be_bytes(b[2..].try_into().ok()?);
+    // the mantissa has an explicit integer bit, so its value is mantissa * 2^(exponent - 16383 - 63)
+    let shift = 16383 + 63 - exponent;
+    match shift {
+        1..=63 => u32::try_from(((mantissa >> (shift - 1)) + 1) >> 1).ok(),
+        // 0 is the only value with a zero exponent that is not vanishingly small
+        64.. => Some(0),
+        _ => None,
+    }
+}
+
+pub struct AiffFile<'a> {
+    file: SdFile<'a>,
+    pub format: SampleFormat,
+    pub sample_rate: u32,
+    // the size samples are stored in, 20bit audio is stored left justified in 24bit
+    pub bit_depth: u16,
+    pub num_channels: u16,
+    // file offsets of the next unread sample and the end of the sound data
+    pub read: u32,
+    pub end: u32,
+    // file offset of the first sample
+    start: u32,
+    // samples are big endian unless the AIFC compression type is `sowt`
+    big_endian: bool,
+}
+
+impl<'a> AiffFile<'a> {
+    // Hands the file back with the error when it cannot be played, so it can be closed
+    pub async fn new(file: SdFile<'a>) -> Result<Self, (Error, SdFile<'a>)> {
+        let mut aiff = Self {
+            file,
+            format: SampleFormat::Int,
+            sample_rate: 0,
+            bit_depth: 0,
+            num_channels: 0,
+            read: 0,
+            end: 0,
+            start: 0,
+            big_endian: true,
+        };
+        match aiff.read_header().await {
+            Ok(()) => Ok(aiff),
+            Err(e) => Err((e, aiff.destroy())),
+        }
+    }
+
+    // Reads the chunks up to the sound data, the file is left at the first sample
+    async fn read_header(&mut self) -> Result<(), Error> {
+        let file = &mut self.file;
+        let mut form = [0u8; 12];
+        if read_all(file, &mut form).await? < form.len() || &form[..4] != b"FORM" {
+            return Err(Error::NotAiff);
+        }
+        let aifc = match &form[8..] {
+            b"AIFF" => false,
+            b"AIFC" => true,
+            _ => return Err(Error::NotAiff),
+        };
+
+        // the chunks can come in any order, so look at all of them up to the sound data
+        let mut comm = None;
+        let mut sound = None;
+        while comm.is_none() || sound.is_none() {
+            let mut header = [0u8; 8];
+            if read_all(file, &mut header).await? < header.len() {
+                break;
+            }
+            let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+
+            // chunks are padded to an even length
+            let mut skip = len + (len & 1);
+            match &header[..4] {
+                b"COMM" => {
+                    let mut bytes = [0u8; MAX_COMM_LEN];
+                    let bytes = &mut bytes[..(len as usize).min(MAX_COMM_LEN)];
+                    read_all(file, bytes).await?;
+                    comm = Some(CommonChunk::parse(bytes, aifc).ok_or(Error::MissingCommon)?);
+                    skip -= bytes.len() as u32;
+                }
+                b"SSND" if len >= 8 => {
+                    // the sound data starts after an offset that is almost always 0
+                    let mut offset = [0u8; 8];
+                    read_all(file, &mut offset).await?;
+                    let offset = u32::from_be_bytes([offset[0], offset[1], offset[2], offset[3]]);
+                    let start = file.offset() + offset;
+                    sound = Some((start, (len - 8).saturating_sub(offset)));
+                    skip -= 8;
+                }
+                _ => (),
+            }
+            file.seek_from_current(skip as i32)?;
+        }
+        let comm = comm.ok_or(Error::MissingCommon)?;
+        let (start, sound_len) = sound.ok_or(Error::MissingSoundData)?;
+
+        let unsupported = Error::Unsupported {
+            compression: comm.compression,
+            bit_depth: comm.sample_size,
+            channels: comm.channels,
+        };
+        let bytes = comm.sample_size.div_ceil(8);
+        let (format, bit_depth, big_endian) = match (&comm.compression, bytes) {
+            (b"NONE" | b"twos", 1..=4) => (SampleFormat::Int, bytes * 8, true),
+            (b"sowt", 1..=4) => (SampleFormat::Int, bytes * 8, false),
+            (b"fl32" | b"FL32", _) => (SampleFormat::Float, 32, true),
+            // the sample size is that of the expanded samples
+            (b"ulaw" | b"ULAW", _) => (SampleFormat::MuLaw, 8, false),
+            (b"alaw" | b"ALAW", _) => (SampleFormat::ALaw, 8, false),
+            _ => return Err(unsupported),
+        };
+        if comm.channels == 0 || comm.channels > MAX_CHANNELS || comm.sample_rate == 0 {
+            return Err(unsupported);
+        }
+
+        let frame_len = (bit_depth / 8 * comm.channels) as u32;
+        let available = file.length().saturating_sub(start);
+        let data_len = sound_len
+            .min(available)
+            .min(comm.frames.saturating_mul(frame_len));
+        file.seek_from_start(start)?;
+
+        info!("[AIFF] {}", comm);
+        self.format = format;
+        self.sample_rate = comm.sample_rate;
+        self.bit_depth = bit_depth;
+        self.num_channels = comm.channels;
+        self.read = start;
+        self.end = start + data_len - data_len % frame_len;
+        self.start = start;
+        self.big_endian = big_endian;
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.read >= self.end
+    }
+
+    // Frames left to play
+    pub fn remaining_frames(&self) -> u64 {
+        let frame_len = (self.bit_depth / 8 * self.num_channels) as u32;
+        ((self.end - self.read) / frame_len) as u64
+    }
+
+    // Ends the track early, when its file cannot be read
+    pub fn finish(&mut self) {
+        self.read = self.end;
+    }
+
+    pub fn destroy(self) -> SdFile<'a> {
+        self.file
+    }
+
+    // Moves playback to `frame`, counted from the first sample
+    pub fn seek(&mut self, frame: u32) -> Result<(), Error> {
+        let frame_len = (self.bit_depth / 8 * self.num_channels) as u32;
+        self.read = self
+            .start
+            .saturating_add(frame.saturating_mul(frame_len))
+            .min(self.end);
+        self.file.seek_from_start(self.read)?;
+        Ok(())
+    }
+
+    // Fills `buf` with samples in the same encoding as wav files,
+    // little endian and unsigned for 8bit. Pads with silence once the sound data ends
+    // and returns the length before the padding
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = (buf.len() as u32).min(self.end - self.read) as usize;
+        let read = read_all(&mut self.file, &mut buf[..len]).await?;
+        self.read += read as u32;
+        if read < len {
+            // the file is shorter than its header says
+            self.end = self.read;
+        }
+
+        match (self.format, self.bit_depth) {
+            (SampleFormat::Int, 8) => buf[..read].iter_mut().for_each(|b| *b ^= 0x80),
+            _ if self.big_endian => buf[..read]
+                .chunks_exact_mut((self.bit_depth / 8) as usize)
+                .for_each(|sample| sample.reverse()),
+            _ => (),
+        }
+
+        buf[read..].fill(match self.format {
+            SampleFormat::Int if self.bit_depth == 8 => 0x80,
+            SampleFormat::MuLaw => MULAW_SILENCE,
+            SampleFormat::ALaw => ALAW_SILENCE,
+            _ => 0,
+        });
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extended(exponent: u16, mantissa: u64) -> [u8; 10] {
+        let mut b = [0; 10];
+        b[..2].copy_from_slice(&exponent.to_be_bytes());
+        b[2..].copy_from_slice(&mantissa.to_be_bytes());
+        b
+    }
+
+    #[test]
+    fn reads_common_sample_rates() {
+        // the exponent puts the binary point after the 16 bits of the rate
+        assert_eq!(
+            extended_to_u32(extended(0x400E, 0xAC44 << 48)),
+            Some(44_100)
+        );
+        assert_eq!(
+            extended_to_u32(extended(0x400E, 0xBB80 << 48)),
+            Some(48_000)
+        );
+        assert_eq!(extended_to_u32(extended(0x400B, 0xFA00 << 48)), Some(8_000));
+        assert_eq!(
+            extended_to_u32(extended(0x400F, 0xBB80 << 48)),
+            Some(96_000)
+        );
+    }
+
+    #[test]
+    fn rounds_to_the_nearest_integer() {
+        // 44100.5 and 44099.25
+        assert_eq!(
+            extended_to_u32(extended(0x400E, 0xAC44_8000 << 32)),
+            Some(44_101)
+        );
+        assert_eq!(
+            extended_to_u32(extended(0x400E, 0xAC43_4000 << 32)),
+            Some(44_099)
+        );
+    }
+
+    #[test]
+    fn zero_and_denormals_are_zero() {
+        assert_eq!(extended_to_u32([0; 10]), Some(0));
+        assert_eq!(extended_to_u32(extended(0, 1 << 62)), Some(0));
+        // 0.25
+        assert_eq!(extended_to_u32(extended(0x3FFD, 1 << 63)), Some(0));
+    }
+
+    #[test]
+    fn negative_and_too_large_values_are_rejected() {
+        assert_eq!(extended_to_u32(extended(0xC00E, 0xAC44 << 48)), None);
+        // 2^32
+        assert_eq!(extended_to_u32(extended(0x401F, 1 << 63)), None);
+        assert_eq!(extended_to_u32(extended(0x401E, u64::MAX)), None);
+        assert_eq!(extended_to_u32(extended(0x7FFF, 1 << 63)), None);
+    }
+}
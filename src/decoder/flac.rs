@@ -0,0 +1,877 @@
+use super::FileBuffer;
+use crate::file_reader::{SdError, SdFile};
+use crate::replaygain::{ReplayGain, parse_vorbis_comments};
+use defmt::{Format, info, warn};
+use heapless::Vec;
+
+// Largest block size of the streamable subset at rates up to 48khz
+const MAX_BLOCK_SIZE: usize = 4608;
+const MAX_CHANNELS: usize = 2;
+// Has to hold a whole frame, a verbatim 24bit stereo block of MAX_BLOCK_SIZE with the extra bit of
+// its side channel is just over 27.5KiB
+const INPUT_SIZE: usize = 28 * 1024;
+// Together with the 36KiB of decoded samples a decoder takes about 65KiB, see the size check on
+// `Decoder` for how that fits the RAM
+// Seek points kept from the SEEKTABLE, longer tables are thinned out evenly
+const MAX_SEEK_POINTS: usize = 64;
+const SEEK_POINT_LEN: usize = 18;
+
+#[derive(Debug, Format)]
+pub enum Error {
+    Sd(SdError),
+    // The file does not start with the `fLaC` marker
+    NotFlac,
+    MissingStreamInfo,
+    // The stream does not fit the static decode buffers
+    Unsupported {
+        sample_rate: u32,
+        channels: u8,
+        bits_per_sample: u8,
+        max_block_size: u16,
+    },
+}
+
+impl From<SdError> for Error {
+    fn from(e: SdError) -> Self {
+        Error::Sd(e)
+    }
+}
+
+#[derive(Debug, Format, Clone, Copy, Default)]
+pub struct StreamInfo {
+    pub min_block_size: u16,
+    pub max_block_size: u16,
+    // 0 when unknown
+    pub max_frame_size: u32,
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub bits_per_sample: u8,
+    // 0 when unknown
+    pub total_samples: u64,
+}
+
+impl StreamInfo {
+    const LEN: usize = 34;
+
+    pub fn parse(block: &[u8]) -> Option<Self> {
+        let b = block.get(..Self::LEN)?;
+        Some(Self {
+            min_block_size: u16::from_be_bytes([b[0], b[1]]),
+            max_block_size: u16::from_be_bytes([b[2], b[3]]),
+            max_frame_size: u32::from_be_bytes([0, b[7], b[8], b[9]]),
+            sample_rate: (b[10] as u32) << 12 | (b[11] as u32) << 4 | (b[12] as u32) >> 4,
+            channels: ((b[12] >> 1) & 0b111) + 1,
+            bits_per_sample: ((b[12] & 1) << 4 | b[13] >> 4) + 1,
+            total_samples: ((b[13] & 0xF) as u64) << 32
+                | u32::from_be_bytes([b[14], b[15], b[16], b[17]]) as u64,
+        })
+    }
+}
+
+// Where a frame starts, from the SEEKTABLE metadata block
+#[derive(Debug, Format, Clone, Copy)]
+pub struct SeekPoint {
+    pub sample: u64,
+    // counted from the first frame
+    pub offset: u64,
+}
+
+fn parse_seek_table(block: &[u8], len: usize) -> Vec<SeekPoint, MAX_SEEK_POINTS> {
+    let stride = (len / SEEK_POINT_LEN).div_ceil(MAX_SEEK_POINTS).max(1);
+    block
+        .chunks_exact(SEEK_POINT_LEN)
+        .step_by(stride)
+        .map(|point| SeekPoint {
+            sample: u64::from_be_bytes(point[..8].try_into().unwrap()),
+            offset: u64::from_be_bytes(point[8..16].try_into().unwrap()),
+        })
+        // placeholders mark room left for points
+        .filter(|point| point.sample != u64::MAX)
+        .take(MAX_SEEK_POINTS)
+        .collect()
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    // The frame continues past the end of the buffered data
+    Truncated,
+    // Not a valid frame, most likely a false sync code
+    Invalid,
+}
+
+#[derive(Debug, Format, Clone, Copy)]
+pub struct FrameHeader {
+    // number of the first sample in the frame
+    pub first_sample: u64,
+    pub block_size: usize,
+    pub channels: u8,
+    pub channel_assignment: u8,
+    pub bits_per_sample: u8,
+}
+
+pub struct FlacDecoder<'a> {
+    input: FileBuffer<'a, INPUT_SIZE>,
+    samples: [[i32; MAX_BLOCK_SIZE]; MAX_CHANNELS],
+    block_len: usize,
+    block_pos: usize,
+    block_bits: u8,
+    finished: bool,
+    // samples per channel handed out so far
+    position: u64,
+    // file offset of the first frame, seek points count from here
+    first_frame: u32,
+    seek_points: Vec<SeekPoint, MAX_SEEK_POINTS>,
+    pub info: StreamInfo,
+    pub replay_gain: ReplayGain,
+    pub sample_rate: u32,
+    pub num_channels: u16,
+}
+
+impl<'a> FlacDecoder<'a> {
+    // Hands the file back with the error when it cannot be played, so it can be closed
+    pub async fn new(file: SdFile<'a>) -> Result<Self, (Error, SdFile<'a>)> {
+        let mut decoder = Self {
+            input: FileBuffer::new(file),
+            samples: [[0; MAX_BLOCK_SIZE]; MAX_CHANNELS],
+            block_len: 0,
+            block_pos: 0,
+            block_bits: 0,
+            finished: false,
+            position: 0,
+            first_frame: 0,
+            seek_points: Vec::new(),
+            info: StreamInfo::default(),
+            replay_gain: ReplayGain::default(),
+            sample_rate: 0,
+            num_channels: 0,
+        };
+        match decoder.read_metadata().await {
+            Ok(()) => Ok(decoder),
+            Err(e) => Err((e, decoder.destroy())),
+        }
+    }
+
+    // Reads the metadata blocks, the input is left at the first frame
+    async fn read_metadata(&mut self) -> Result<(), Error> {
+        let input = &mut self.input;
+        input.refill().await?;
+        if !input.buffered().starts_with(b"fLaC") {
+            return Err(Error::NotFlac);
+        }
+        input.consume(4);
+
+        // walk the metadata blocks, only STREAMINFO, SEEKTABLE and the ReplayGain comments are used
+        let mut info = None;
+        let mut seek_points = Vec::new();
+        let mut replay_gain = ReplayGain::default();
+        loop {
+            if input.buffered().len() < 4 + StreamInfo::LEN {
+                input.refill().await?;
+            }
+            let [kind, a, b, c, ..] = *input.buffered() else {
+                return Err(Error::MissingStreamInfo);
+            };
+            let len = u32::from_be_bytes([0, a, b, c]) as usize;
+            input.consume(4);
+
+            match kind & 0x7F {
+                0 => info = StreamInfo::parse(input.buffered()),
+                3 => {
+                    if input.buffered().len() < len {
+                        input.refill().await?;
+                    }
+                    let table = input.buffered();
+                    seek_points = parse_seek_table(&table[..len.min(table.len())], len);
+                }
+                4 => {
+                    if input.buffered().len() < len {
+                        input.refill().await?;
+                    }
+                    let comments = input.buffered();
+                    parse_vorbis_comments(&comments[..len.min(comments.len())], &mut replay_gain);
+                }
+                _ => (),
+            }
+            input.skip(len)?;
+
+            // last metadata block flag
+            if kind & 0x80 != 0 {
+                break;
+            }
+        }
+        let info = info.ok_or(Error::MissingStreamInfo)?;
+
+        if info.sample_rate == 0
+            || info.channels as usize > MAX_CHANNELS
+            || !(8..=24).contains(&info.bits_per_sample)
+            || info.max_block_size as usize > MAX_BLOCK_SIZE
+        {
+            return Err(Error::Unsupported {
+                sample_rate: info.sample_rate,
+                channels: info.channels,
+                bits_per_sample: info.bits_per_sample,
+                max_block_size: info.max_block_size,
+            });
+        }
+
+        info!(
+            "[FLAC] {}hz, {}bit, {} channels, {} samples, {} seek points",
+            info.sample_rate,
+            info.bits_per_sample,
+            info.channels,
+            info.total_samples,
+            seek_points.len()
+        );
+        self.first_frame = input.position();
+        self.seek_points = seek_points;
+        self.block_bits = info.bits_per_sample;
+        self.info = info;
+        self.replay_gain = replay_gain;
+        self.sample_rate = info.sample_rate;
+        self.num_channels = info.channels as u16;
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    // Frames left to play, when the stream info has the length
+    pub fn remaining_frames(&self) -> Option<u64> {
+        let total = self.info.total_samples;
+        (total != 0).then(|| total.saturating_sub(self.position))
+    }
+
+    // Ends the track early, when its file cannot be read
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+
+    pub fn destroy(self) -> SdFile<'a> {
+        self.input.into_file()
+    }
+
+    // Moves playback close to `sample`. The file offset is interpolated between the surrounding
+    // seek points, or the whole stream without a seek table, and decoding resyncs on the next frame
+    pub fn seek(&mut self, sample: u64) -> Result<(), Error> {
+        let total = self.info.total_samples;
+        if total != 0 && sample >= total {
+            self.finished = true;
+            return Ok(());
+        }
+
+        let stream_len = self.input.file_len().saturating_sub(self.first_frame) as u64;
+        let mut before = (0, 0);
+        let mut after = (total, stream_len);
+        for point in &self.seek_points {
+            if point.sample > sample {
+                after = (point.sample, point.offset);
+                break;
+            }
+            before = (point.sample, point.offset);
+        }
+        let offset = if after.0 > before.0 && after.1 > before.1 {
+            before.1 + (after.1 - before.1) * (sample - before.0) / (after.0 - before.0)
+        } else {
+            before.1
+        };
+
+        self.input
+            .seek(self.first_frame + offset.min(stream_len) as u32)?;
+        self.block_len = 0;
+        self.block_pos = 0;
+        self.finished = false;
+        // the next decoded frame tells where playback actually is
+        self.position = sample;
+        Ok(())
+    }
+
+    // Fills `buf` with 16bit little endian pcm, as if it was read from a wav file.
+    // Pads with silence once the stream ends and returns the length before the padding
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let frame_len = 2 * self.num_channels as usize;
+        let mut written = 0;
+        while written + frame_len <= buf.len() {
+            if self.block_pos == self.block_len && (self.finished || !self.decode_block().await?) {
+                self.finished = true;
+                buf[written..].fill(0);
+                return Ok(written);
+            }
+
+            let frame = &mut buf[written..written + frame_len];
+            let shift = self.block_bits as i32 - 16;
+            for (channel, out) in frame.chunks_exact_mut(2).enumerate() {
+                let sample = self.samples[channel][self.block_pos];
+                let sample = if shift >= 0 {
+                    sample >> shift
+                } else {
+                    sample << -shift
+                };
+                out.copy_from_slice(&(sample as i16).to_le_bytes());
+            }
+            self.block_pos += 1;
+            self.position += 1;
+            written += frame_len;
+        }
+        Ok(written)
+    }
+
+    // Decodes the next frame into the sample buffers. Returns false at the end of the stream
+    async fn decode_block(&mut self) -> Result<bool, Error> {
+        loop {
+            if !self.input.is_eof() {
+                self.input.refill().await?;
+            }
+
+            let data = self.input.buffered();
+            let Some(sync) = data
+                .windows(2)
+                .position(|w| w[0] == 0xFF && w[1] & 0xFE == 0xF8)
+            else {
+                if self.input.is_eof() {
+                    return Ok(false);
+                }
+                let searched = data.len().saturating_sub(1);
+                self.input.consume(searched);
+                continue;
+            };
+
+            match decode_frame(&data[sync..], &self.info, &mut self.samples) {
+                Ok((header, len)) => {
+                    self.input.consume(sync + len);
+                    self.block_len = header.block_size;
+                    self.block_pos = 0;
+                    self.block_bits = header.bits_per_sample;
+                    self.position = header.first_sample;
+                    return Ok(true);
+                }
+                // wait for the rest of the frame, unless it can never fit
+                Err(FrameError::Truncated) if sync > 0 && !self.input.is_eof() => {
+                    self.input.consume(sync)
+                }
+                Err(e) => {
+                    if e == FrameError::Truncated && !self.input.is_eof() {
+                        warn!("[FLAC] frame does not fit the input buffer, skipping");
+                    }
+                    self.input.consume(sync + 1)
+                }
+            }
+        }
+    }
+}
+
+// Decodes one frame starting at its sync code into `samples`.
+// Returns the frame header and the length of the frame in bytes
+fn decode_frame(
+    data: &[u8],
+    info: &StreamInfo,
+    samples: &mut [[i32; MAX_BLOCK_SIZE]; MAX_CHANNELS],
+) -> Result<(FrameHeader, usize), FrameError> {
+    let (header, header_len) = parse_header(data, info)?;
+    if header.block_size > MAX_BLOCK_SIZE
+        || header.channels as usize > MAX_CHANNELS
+        || header.channels != info.channels
+        || !(8..=24).contains(&header.bits_per_sample)
+    {
+        return Err(FrameError::Invalid);
+    }
+
+    let block_size = header.block_size;
+    let bits = header.bits_per_sample as u32;
+    let mut reader = BitReader::new(data);
+    reader.pos = header_len * 8;
+    for (channel, out) in samples[..header.channels as usize].iter_mut().enumerate() {
+        // the side channel needs an extra bit
+        let side = match (header.channel_assignment, channel) {
+            (8, 1) | (9, 0) | (10, 1) => 1,
+            _ => 0,
+        };
+        decode_subframe(&mut reader, &mut out[..block_size], bits + side)?;
+    }
+
+    reader.align();
+    let len = reader.pos / 8 + 2;
+    let Some(footer) = data.get(len - 2..len) else {
+        return Err(FrameError::Truncated);
+    };
+    if crc16(&data[..len - 2]) != u16::from_be_bytes([footer[0], footer[1]]) {
+        return Err(FrameError::Invalid);
+    }
+
+    let [left, right] = samples;
+    let (left, right) = (&mut left[..block_size], &mut right[..block_size]);
+    match header.channel_assignment {
+        // left/side
+        8 => right
+            .iter_mut()
+            .zip(left.iter())
+            .for_each(|(side, left)| *side = left.wrapping_sub(*side)),
+        // side/right
+        9 => left
+            .iter_mut()
+            .zip(right.iter())
+            .for_each(|(side, right)| *side = side.wrapping_add(*right)),
+        // mid/side
+        10 => left
+            .iter_mut()
+            .zip(right.iter_mut())
+            .for_each(|(mid, side)| {
+                let full = (*mid << 1) | (*side & 1);
+                *mid = full.wrapping_add(*side) >> 1;
+                *side = full.wrapping_sub(*side) >> 1;
+            }),
+        _ => (),
+    }
+
+    Ok((header, len))
+}
+
+fn parse_header(data: &[u8], info: &StreamInfo) -> Result<(FrameHeader, usize), FrameError> {
+    let mut reader = BitReader::new(data);
+    // 14 bit sync code followed by a reserved zero bit
+    if reader.read(15)? != 0x7FFC {
+        return Err(FrameError::Invalid);
+    }
+    let variable_block_size = reader.read(1)? != 0;
+    let block_size_code = reader.read(4)?;
+    let sample_rate_code = reader.read(4)?;
+    let channel_assignment = reader.read(4)? as u8;
+    let sample_size_code = reader.read(3)?;
+    if reader.read(1)? != 0 {
+        return Err(FrameError::Invalid);
+    }
+
+    // utf8 style coded frame number, or sample number with variable block sizes.
+    // Longer than 6 bytes is a number past any real stream, and leaves no value bits in `first`
+    let first = reader.read(8)? as u8;
+    let ones = first.leading_ones();
+    let extra_bytes = match ones {
+        0 => 0,
+        2..=6 => ones - 1,
+        _ => return Err(FrameError::Invalid),
+    };
+    let mut number = (first & (0xFF >> (ones + 1))) as u64;
+    for _ in 0..extra_bytes {
+        let byte = reader.read(8)?;
+        if byte & 0xC0 != 0x80 {
+            return Err(FrameError::Invalid);
+        }
+        number = number << 6 | (byte & 0x3F) as u64;
+    }
+    let first_sample = match variable_block_size {
+        true => number,
+        // only the last frame may be shorter, all the others have the largest block size
+        false => number * info.max_block_size as u64,
+    };
+
+    let block_size = match block_size_code {
+        0 => return Err(FrameError::Invalid),
+        1 => 192,
+        2..=5 => 576 << (block_size_code - 2),
+        6 => reader.read(8)? + 1,
+        7 => reader.read(16)? + 1,
+        _ => 256 << (block_size_code - 8),
+    } as usize;
+
+    // the sample rate is fixed for the whole stream, it only needs to be skipped
+    let _sample_rate = match sample_rate_code {
+        0 => info.sample_rate,
+        1 => 88_200,
+        2 => 176_400,
+        3 => 192_000,
+        4 => 8_000,
+        5 => 16_000,
+        6 => 22_050,
+        7 => 24_000,
+        8 => 32_000,
+        9 => 44_100,
+        10 => 48_000,
+        11 => 96_000,
+        12 => reader.read(8)? * 1000,
+        13 => reader.read(16)?,
+        14 => reader.read(16)? * 10,
+        _ => return Err(FrameError::Invalid),
+    };
+
+    let bits_per_sample = match sample_size_code {
+        0 => info.bits_per_sample,
+        1 => 8,
+        2 => 12,
+        4 => 16,
+        5 => 20,
+        6 => 24,
+        7 => 32,
+        _ => return Err(FrameError::Invalid),
+    };
+
+    let channels = match channel_assignment {
+        0..=7 => channel_assignment + 1,
+        8..=10 => 2,
+        _ => return Err(FrameError::Invalid),
+    };
+
+    let crc_pos = reader.pos / 8;
+    if reader.read(8)? as u8 != crc8(&data[..crc_pos]) {
+        return Err(FrameError::Invalid);
+    }
+
+    Ok((
+        FrameHeader {
+            first_sample,
+            block_size,
+            channels,
+            channel_assignment,
+            bits_per_sample,
+        },
+        crc_pos + 1,
+    ))
+}
+
+fn decode_subframe(reader: &mut BitReader, out: &mut [i32], bits: u32) -> Result<(), FrameError> {
+    if reader.read(1)? != 0 {
+        return Err(FrameError::Invalid);
+    }
+    let kind = reader.read(6)?;
+    let wasted = match reader.read(1)? {
+        1 => reader.read_unary()? + 1,
+        _ => 0,
+    };
+    if wasted >= bits {
+        return Err(FrameError::Invalid);
+    }
+    let bits = bits - wasted;
+
+    match kind {
+        // constant
+        0 => out.fill(reader.read_signed(bits)?),
+        // verbatim
+        1 => {
+            for sample in out.iter_mut() {
+                *sample = reader.read_signed(bits)?;
+            }
+        }
+        // fixed predictor
+        8..=12 => {
+            let order = (kind - 8) as usize;
+            read_warmup(reader, out, order, bits)?;
+            decode_residual(reader, out, order)?;
+            fixed_predict(out, order);
+        }
+        // linear predictor
+        32..=63 => {
+            let order = (kind - 31) as usize;
+            read_warmup(reader, out, order, bits)?;
+
+            let precision = reader.read(4)? + 1;
+            if precision == 16 {
+                return Err(FrameError::Invalid);
+            }
+            let shift = reader.read_signed(5)?;
+            if shift < 0 {
+                return Err(FrameError::Invalid);
+            }
+            let mut coefficients = [0i32; 32];
+            for coefficient in coefficients[..order].iter_mut() {
+                *coefficient = reader.read_signed(precision)?;
+            }
+
+            decode_residual(reader, out, order)?;
+            // only fall back to 64bit math when the sum could overflow
+            let wide = bits + precision + (32 - (order as u32).leading_zeros()) > 32;
+            lpc_predict(out, &coefficients[..order], shift as u32, wide);
+        }
+        _ => return Err(FrameError::Invalid),
+    }
+
+    if wasted > 0 {
+        out.iter_mut().for_each(|sample| *sample <<= wasted);
+    }
+    Ok(())
+}
+
+fn read_warmup(
+    reader: &mut BitReader,
+    out: &mut [i32],
+    order: usize,
+    bits: u32,
+) -> Result<(), FrameError> {
+    if order > out.len() {
+        return Err(FrameError::Invalid);
+    }
+    for sample in out[..order].iter_mut() {
+        *sample = reader.read_signed(bits)?;
+    }
+    Ok(())
+}
+
+// Reads the rice coded residual into `out` after the `order` warmup samples
+fn decode_residual(
+    reader: &mut BitReader,
+    out: &mut [i32],
+    order: usize,
+) -> Result<(), FrameError> {
+    let (param_bits, escape) = match reader.read(2)? {
+        0 => (4, 0b1111),
+        1 => (5, 0b11111),
+        _ => return Err(FrameError::Invalid),
+    };
+    let partition_order = reader.read(4)?;
+    let partition_len = out.len() >> partition_order;
+    if partition_len << partition_order != out.len() || partition_len < order {
+        return Err(FrameError::Invalid);
+    }
+
+    // the first partition does not contain the warmup samples
+    let mut start = order;
+    for end in (1..=1 << partition_order).map(|partition| partition * partition_len) {
+        let param = reader.read(param_bits)?;
+        if param == escape {
+            let bits = reader.read(5)?;
+            for sample in out[start..end].iter_mut() {
+                *sample = reader.read_signed(bits)?;
+            }
+        } else {
+            for sample in out[start..end].iter_mut() {
+                *sample = reader.read_rice(param)?;
+            }
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+fn fixed_predict(out: &mut [i32], order: usize) {
+    for i in order..out.len() {
+        let prediction = match order {
+            0 => 0,
+            1 => out[i - 1],
+            2 => out[i - 1].wrapping_mul(2).wrapping_sub(out[i - 2]),
+            3 => out[i - 1]
+                .wrapping_mul(3)
+                .wrapping_sub(out[i - 2].wrapping_mul(3))
+                .wrapping_add(out[i - 3]),
+            _ => out[i - 1]
+                .wrapping_mul(4)
+                .wrapping_sub(out[i - 2].wrapping_mul(6))
+                .wrapping_add(out[i - 3].wrapping_mul(4))
+                .wrapping_sub(out[i - 4]),
+        };
+        out[i] = out[i].wrapping_add(prediction);
+    }
+}
+
+fn lpc_predict(out: &mut [i32], coefficients: &[i32], shift: u32, wide: bool) {
+    let order = coefficients.len();
+    for i in order..out.len() {
+        let history = out[i - order..i].iter().rev();
+        let prediction = if wide {
+            let sum: i64 = coefficients
+                .iter()
+                .zip(history)
+                .map(|(c, s)| *c as i64 * *s as i64)
+                .sum();
+            (sum >> shift) as i32
+        } else {
+            let sum = coefficients
+                .iter()
+                .zip(history)
+                .fold(0i32, |sum, (c, s)| sum.wrapping_add(c.wrapping_mul(*s)));
+            sum >> shift
+        };
+        out[i] = out[i].wrapping_add(prediction);
+    }
+}
+
+struct BitReader<'b> {
+    data: &'b [u8],
+    // position in bits
+    pos: usize,
+}
+
+impl<'b> BitReader<'b> {
+    fn new(data: &'b [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    // Reads up to 32 bits msb first
+    fn read(&mut self, bits: u32) -> Result<u32, FrameError> {
+        if bits == 0 {
+            return Ok(0);
+        }
+        let end = self.pos + bits as usize;
+        if end > self.data.len() * 8 {
+            return Err(FrameError::Truncated);
+        }
+
+        let last = (end - 1) / 8;
+        let value = self.data[self.pos / 8..=last]
+            .iter()
+            .fold(0u64, |value, byte| value << 8 | *byte as u64);
+        let unused = (last + 1) * 8 - end;
+        self.pos = end;
+        Ok((value >> unused) as u32 & (u32::MAX >> (32 - bits)))
+    }
+
+    fn read_signed(&mut self, bits: u32) -> Result<i32, FrameError> {
+        if bits == 0 {
+            return Ok(0);
+        }
+        let value = self.read(bits)?;
+        Ok(((value << (32 - bits)) as i32) >> (32 - bits))
+    }
+
+    // Counts the zero bits before the next set bit
+    fn read_unary(&mut self) -> Result<u32, FrameError> {
+        let mut zeros = 0;
+        loop {
+            let byte = *self.data.get(self.pos / 8).ok_or(FrameError::Truncated)?;
+            let offset = (self.pos % 8) as u32;
+            let rest = byte << offset;
+            if rest != 0 {
+                let leading = rest.leading_zeros();
+                self.pos += leading as usize + 1;
+                return Ok(zeros + leading);
+            }
+            zeros += 8 - offset;
+            self.pos += (8 - offset) as usize;
+        }
+    }
+
+    fn read_rice(&mut self, param: u32) -> Result<i32, FrameError> {
+        let high = self.read_unary()?;
+        let value = high << param | self.read(param)?;
+        // zigzag encoded
+        Ok((value >> 1) as i32 ^ -((value & 1) as i32))
+    }
+
+    fn align(&mut self) {
+        self.pos = self.pos.next_multiple_of(8);
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                crc << 1 ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+const CRC16_TABLE: [u16; 256] = {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x8005
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, byte| {
+        crc << 8 ^ CRC16_TABLE[((crc >> 8) as u8 ^ byte) as usize]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bit_reader_reads_msb_first_across_bytes() {
+        let mut reader = BitReader::new(&[0b1011_0011, 0b1100_0101, 0xFF, 0x00]);
+        assert_eq!(reader.read(3), Ok(0b101));
+        assert_eq!(reader.read(7), Ok(0b100_1111));
+        assert_eq!(reader.read_signed(4), Ok(1));
+        assert_eq!(reader.read_signed(2), Ok(1));
+        assert_eq!(reader.read_signed(4), Ok(-1));
+        assert_eq!(reader.read(0), Ok(0));
+        assert_eq!(reader.read(5), Ok(0b1_1110));
+        assert_eq!(reader.read(8), Err(FrameError::Truncated));
+        reader.align();
+        assert_eq!(reader.pos, 32);
+        assert_eq!(reader.read(1), Err(FrameError::Truncated));
+    }
+
+    #[test]
+    fn bit_reader_reads_32_bits_at_any_offset() {
+        let data = [0x5A, 0x12, 0x34, 0x56, 0x78, 0xA5];
+        let mut reader = BitReader::new(&data);
+        reader.pos = 4;
+        assert_eq!(reader.read(32), Ok(0xA1234567));
+        assert_eq!(reader.read_signed(12), Ok(-0x75B));
+    }
+
+    #[test]
+    fn unary_counts_zeros_across_bytes() {
+        let mut reader = BitReader::new(&[0b0000_0000, 0b0001_0100, 0b1000_0000]);
+        assert_eq!(reader.read_unary(), Ok(11));
+        assert_eq!(reader.read_unary(), Ok(1));
+        assert_eq!(reader.read_unary(), Ok(2));
+        assert_eq!(reader.read_unary(), Err(FrameError::Truncated));
+    }
+
+    #[test]
+    fn rice_values_are_zigzag_decoded() {
+        // parameter 2: 5 as 001|10, -3 as 01|01, 0 as 1|00 and -1 as 1|01
+        let mut reader = BitReader::new(&[0b0011_0010, 0b1100_1010]);
+        assert_eq!(reader.read_rice(2), Ok(5));
+        assert_eq!(reader.read_rice(2), Ok(-3));
+        assert_eq!(reader.read_rice(2), Ok(0));
+        assert_eq!(reader.read_rice(2), Ok(-1));
+        // parameter 0 is plain unary
+        let mut reader = BitReader::new(&[0b0001_0000]);
+        assert_eq!(reader.read_rice(0), Ok(-2));
+    }
+
+    #[test]
+    fn crcs_match_the_check_values() {
+        // CRC-8 with polynomial 0x07 and CRC-16/UMTS, which FLAC frames use
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+        assert_eq!(crc8(&[]), 0);
+        assert_eq!(crc16(&[]), 0);
+    }
+
+    #[test]
+    fn frame_number_past_6_bytes_is_rejected() {
+        let info = StreamInfo {
+            max_block_size: 4096,
+            sample_rate: 44_100,
+            channels: 2,
+            bits_per_sample: 16,
+            ..Default::default()
+        };
+        // fixed 4096 sample blocks at 44.1khz, stereo 16bit
+        let mut header = [
+            0xFF, 0xF8, 0xC9, 0x18, 0xFE, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0,
+        ];
+        assert_eq!(
+            parse_header(&header, &info).err(),
+            Some(FrameError::Invalid)
+        );
+
+        // frame 1, with the crc over the header
+        header[4] = 0x01;
+        header[5] = crc8(&header[..5]);
+        let (header, len) = parse_header(&header, &info).unwrap();
+        assert_eq!(
+            (header.first_sample, header.block_size, len),
+            (4096, 4096, 6)
+        );
+    }
+}
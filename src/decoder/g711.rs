@@ -0,0 +1,113 @@
+// ITU-T G.711 companded 8bit samples, used by telephony and some voice recorders
+
+use super::SampleFormat;
+
+// Silence in each encoding, used to pad the end of a stream
+pub const MULAW_SILENCE: u8 = 0xFF;
+pub const ALAW_SILENCE: u8 = 0xD5;
+
+// Expands a µ-law sample to 16bit linear pcm
+pub fn mulaw_to_i16(sample: u8) -> i16 {
+    // µ-law is stored with every bit inverted
+    let sample = !sample;
+    let exponent = (sample >> 4) & 0b111;
+    let mantissa = (sample & 0xF) as i16;
+    let magnitude = ((mantissa << 3) + 0x84) << exponent;
+    if sample & 0x80 != 0 {
+        0x84 - magnitude
+    } else {
+        magnitude - 0x84
+    }
+}
+
+// Expands an A-law sample to 16bit linear pcm
+pub fn alaw_to_i16(sample: u8) -> i16 {
+    // A-law is stored with every even bit inverted
+    let sample = sample ^ 0x55;
+    let exponent = (sample >> 4) & 0b111;
+    let mantissa = (sample & 0xF) as i16;
+    let magnitude = match exponent {
+        0 => (mantissa << 4) + 8,
+        _ => ((mantissa << 4) + 0x108) << (exponent - 1),
+    };
+    // unlike µ-law a set sign bit is positive
+    if sample & 0x80 != 0 {
+        magnitude
+    } else {
+        -magnitude
+    }
+}
+
+// Expands an 8bit sample of any format to 16bit linear pcm
+pub fn sample_8bit_to_i16(sample: u8, format: SampleFormat) -> i16 {
+    match format {
+        SampleFormat::MuLaw => mulaw_to_i16(sample),
+        SampleFormat::ALaw => alaw_to_i16(sample),
+        // 8bit wav is unsigned, remove the offset before scaling it up to 16bit
+        _ => (sample as i16 - 0x80) << 8,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reference values, as Python's audioop expands them
+    #[test]
+    fn expands_mulaw_like_the_reference() {
+        let expected = [
+            (0x00, -32_124),
+            (0x0F, -16_764),
+            (0x35, -3_260),
+            (0x55, -716),
+            (0x7E, -8),
+            (0x7F, 0),
+            (0x80, 32_124),
+            (0xA5, 6_652),
+            (0xD5, 716),
+            (0xFE, 8),
+            (0xFF, 0),
+        ];
+        for (sample, linear) in expected {
+            assert_eq!(mulaw_to_i16(sample), linear, "{:#04x}", sample);
+        }
+        assert_eq!(mulaw_to_i16(MULAW_SILENCE), 0);
+    }
+
+    #[test]
+    fn expands_alaw_like_the_reference() {
+        let expected = [
+            (0x00, -5_504),
+            (0x0F, -6_784),
+            (0x35, -8_448),
+            (0x55, -8),
+            (0x7E, -880),
+            (0x7F, -848),
+            (0x80, 5_504),
+            (0xA5, 16_896),
+            (0xD5, 8),
+            (0xFE, 880),
+            (0xFF, 848),
+        ];
+        for (sample, linear) in expected {
+            assert_eq!(alaw_to_i16(sample), linear, "{:#04x}", sample);
+        }
+        // A-law has no zero, silence is the smallest positive step
+        assert_eq!(alaw_to_i16(ALAW_SILENCE), 8);
+    }
+
+    #[test]
+    fn the_sign_bit_mirrors_every_sample() {
+        for sample in 0..=0x7F {
+            assert_eq!(mulaw_to_i16(sample), -mulaw_to_i16(sample | 0x80));
+            assert_eq!(alaw_to_i16(sample), -alaw_to_i16(sample | 0x80));
+        }
+    }
+
+    #[test]
+    fn unsigned_8bit_is_centered_on_0x80() {
+        assert_eq!(sample_8bit_to_i16(0x80, SampleFormat::Int), 0);
+        assert_eq!(sample_8bit_to_i16(0x00, SampleFormat::Int), i16::MIN);
+        assert_eq!(sample_8bit_to_i16(0xFF, SampleFormat::Int), 0x7F00);
+    }
+}
@@ -0,0 +1,395 @@
+use crate::file_reader::{SdError, SdFile, has_extension};
+use crate::replaygain::ReplayGain;
+use defmt::{Format, error};
+
+pub mod adpcm;
+pub mod aiff;
+use aiff::AiffFile;
+pub mod flac;
+pub mod g711;
+use flac::FlacDecoder;
+pub mod mp3;
+use mp3::Mp3Decoder;
+pub mod ogg;
+use ogg::Codec;
+pub mod vorbis;
+use vorbis::VorbisDecoder;
+pub mod wav;
+use wav::WavFile;
+
+#[derive(Debug, Format)]
+pub enum Error {
+    Wav(wav::Error),
+    Aiff(aiff::Error),
+    Mp3(mp3::Error),
+    Flac(flac::Error),
+    Vorbis(vorbis::Error),
+    Sd(SdError),
+    // Neither the start of the file nor its extension belong to a known format
+    Unsupported,
+    // A container whose codec cannot be decoded, like Opus in an Ogg file
+    UnsupportedCodec(Codec),
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    Wav,
+    Aiff,
+    Mp3,
+    Flac,
+    Ogg,
+}
+
+impl FileFormat {
+    // Bytes needed from the start of a file to recognize its format
+    const MAGIC_LEN: usize = 12;
+
+    // Recognizes a format from the first bytes of a file
+    pub fn sniff(magic: &[u8]) -> Option<Self> {
+        let format = match magic.get(..4)? {
+            b"RIFF" if magic.get(8..12)? == b"WAVE" => Self::Wav,
+            b"FORM" if matches!(magic.get(8..12)?, b"AIFF" | b"AIFC") => Self::Aiff,
+            b"fLaC" => Self::Flac,
+            b"OggS" => Self::Ogg,
+            [b'I', b'D', b'3', _] => Self::Mp3,
+            // mpeg audio frame sync followed by layer III
+            [0xFF, b, ..] if b & 0xE0 == 0xE0 && (b >> 1) & 0b11 == 0b01 => Self::Mp3,
+            _ => return None,
+        };
+        Some(format)
+    }
+
+    pub fn from_extension(name: &str) -> Option<Self> {
+        const EXTENSIONS: [(&str, FileFormat); 9] = [
+            ("wav", FileFormat::Wav),
+            ("aif", FileFormat::Aiff),
+            ("aiff", FileFormat::Aiff),
+            ("aifc", FileFormat::Aiff),
+            ("mp3", FileFormat::Mp3),
+            ("flac", FileFormat::Flac),
+            ("ogg", FileFormat::Ogg),
+            ("oga", FileFormat::Ogg),
+            ("opus", FileFormat::Ogg),
+        ];
+        EXTENSIONS
+            .iter()
+            .find(|(extension, _)| has_extension(name, extension))
+            .map(|(_, format)| *format)
+    }
+}
+
+// Opens a song with the decoder matching the start of the file, or its extension when that is not
+// recognized. Anything else, like cover art in an album folder, gives `Error::Unsupported`
+pub async fn open<'a>(mut file: SdFile<'a>, name: &str) -> Result<Decoder<'a>, Error> {
+    let mut magic = [0u8; FileFormat::MAGIC_LEN];
+    let len = read_all(&mut file, &mut magic).await.map_err(Error::Sd)?;
+    file.seek_from_start(0).map_err(Error::Sd)?;
+
+    let Some(format) =
+        FileFormat::sniff(&magic[..len]).or_else(|| FileFormat::from_extension(name))
+    else {
+        file.close().await.map_err(Error::Sd)?;
+        return Err(Error::Unsupported);
+    };
+
+    // a file the decoder refuses comes back with the error and is closed here
+    let decoder = match format {
+        FileFormat::Wav => WavFile::new(file)
+            .await
+            .map(Decoder::Wav)
+            .map_err(|(e, file)| (Error::Wav(e), file)),
+        FileFormat::Aiff => AiffFile::new(file)
+            .await
+            .map(Decoder::Aiff)
+            .map_err(|(e, file)| (Error::Aiff(e), file)),
+        FileFormat::Mp3 => Mp3Decoder::new(file)
+            .await
+            .map(Decoder::Mp3)
+            .map_err(|(e, file)| (Error::Mp3(e), file)),
+        FileFormat::Flac => FlacDecoder::new(file)
+            .await
+            .map(Decoder::Flac)
+            .map_err(|(e, file)| (Error::Flac(e), file)),
+        // only vorbis is decoded, an opus stream is just named in the log
+        FileFormat::Ogg => {
+            let mut page = [0u8; ogg::FIRST_PAGE_LEN];
+            let len = read_all(&mut file, &mut page).await;
+            match len.map(|len| Codec::from_first_page(&page[..len])) {
+                Ok(Some(Codec::Vorbis { .. })) => match file.seek_from_start(0) {
+                    Ok(()) => VorbisDecoder::new(file)
+                        .await
+                        .map(Decoder::Vorbis)
+                        .map_err(|(e, file)| (Error::Vorbis(e), file)),
+                    Err(e) => Err((Error::Sd(e), file)),
+                },
+                Ok(Some(codec)) => Err((Error::UnsupportedCodec(codec), file)),
+                Ok(None) => Err((Error::Unsupported, file)),
+                Err(e) => Err((Error::Sd(e), file)),
+            }
+        }
+    };
+    match decoder {
+        Ok(decoder) => Ok(decoder),
+        Err((e, file)) => {
+            file.close().await.map_err(Error::Sd)?;
+            Err(e)
+        }
+    }
+}
+
+// How the samples handed out by `Decoder::read_exact` are encoded
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    // signed, except for 8bit which is unsigned like in wav files
+    Int,
+    // 32bit ieee float
+    Float,
+    // 8bit G.711 companded
+    MuLaw,
+    ALaw,
+}
+
+// A song being played, every decoder hands out interleaved little endian pcm
+pub enum Decoder<'a> {
+    Wav(WavFile<'a>),
+    Aiff(AiffFile<'a>),
+    Mp3(Mp3Decoder<'a>),
+    Flac(FlacDecoder<'a>),
+    Vorbis(VorbisDecoder<'a>),
+}
+
+// The playing and the upcoming track are open next to each other in the player task, FLAC needs
+// the most at about 65KiB each. That leaves half of the RP2040's 264KiB for the queue (16KiB),
+// the playback buffers, BLE and the stack
+const _: () = assert!(2 * size_of::<Decoder>() <= 132 * 1024);
+
+impl<'a> Decoder<'a> {
+    pub fn sample_rate(&self) -> u32 {
+        match self {
+            Decoder::Wav(wav) => wav.sample_rate,
+            Decoder::Aiff(aiff) => aiff.sample_rate,
+            Decoder::Mp3(mp3) => mp3.sample_rate,
+            Decoder::Flac(flac) => flac.sample_rate,
+            Decoder::Vorbis(vorbis) => vorbis.sample_rate,
+        }
+    }
+
+    pub fn bit_depth(&self) -> u16 {
+        match self {
+            Decoder::Wav(wav) => wav.bit_depth,
+            Decoder::Aiff(aiff) => aiff.bit_depth,
+            // compressed formats are decoded to 16bit
+            Decoder::Mp3(_) | Decoder::Flac(_) | Decoder::Vorbis(_) => 16,
+        }
+    }
+
+    pub fn sample_format(&self) -> SampleFormat {
+        match self {
+            Decoder::Wav(wav) => wav.format,
+            Decoder::Aiff(aiff) => aiff.format,
+            Decoder::Mp3(_) | Decoder::Flac(_) | Decoder::Vorbis(_) => SampleFormat::Int,
+        }
+    }
+
+    // Speaker positions of the channels, when the file specifies them
+    pub fn channel_mask(&self) -> Option<u32> {
+        match self {
+            Decoder::Wav(wav) => wav.channel_mask,
+            Decoder::Aiff(_) | Decoder::Mp3(_) | Decoder::Flac(_) | Decoder::Vorbis(_) => None,
+        }
+    }
+
+    pub fn channels(&self) -> u16 {
+        match self {
+            Decoder::Wav(wav) => wav.num_channels,
+            Decoder::Aiff(aiff) => aiff.num_channels,
+            Decoder::Mp3(mp3) => mp3.num_channels,
+            Decoder::Flac(flac) => flac.num_channels,
+            Decoder::Vorbis(vorbis) => vorbis.num_channels,
+        }
+    }
+
+    // Loudness tags of the track, the tags of wav and aiff files are not read
+    pub fn replay_gain(&self) -> ReplayGain {
+        match self {
+            Decoder::Mp3(mp3) => mp3.replay_gain,
+            Decoder::Flac(flac) => flac.replay_gain,
+            Decoder::Vorbis(vorbis) => vorbis.replay_gain,
+            Decoder::Wav(_) | Decoder::Aiff(_) => ReplayGain::default(),
+        }
+    }
+
+    // Frames left to play, `None` when the length of the stream is unknown
+    pub fn remaining_frames(&self) -> Option<u64> {
+        match self {
+            Decoder::Wav(wav) => Some(wav.remaining_frames()),
+            Decoder::Aiff(aiff) => Some(aiff.remaining_frames()),
+            Decoder::Mp3(mp3) => mp3.remaining_frames(),
+            Decoder::Flac(flac) => flac.remaining_frames(),
+            Decoder::Vorbis(vorbis) => vorbis.remaining_frames(),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        match self {
+            Decoder::Wav(wav) => wav.is_finished(),
+            Decoder::Aiff(aiff) => aiff.is_finished(),
+            Decoder::Mp3(mp3) => mp3.is_finished(),
+            Decoder::Flac(flac) => flac.is_finished(),
+            Decoder::Vorbis(vorbis) => vorbis.is_finished(),
+        }
+    }
+
+    // Ends the track early, when its file cannot be read
+    pub fn finish(&mut self) {
+        match self {
+            Decoder::Wav(wav) => wav.finish(),
+            Decoder::Aiff(aiff) => aiff.finish(),
+            Decoder::Mp3(mp3) => mp3.finish(),
+            Decoder::Flac(flac) => flac.finish(),
+            Decoder::Vorbis(vorbis) => vorbis.finish(),
+        }
+    }
+
+    // Fills `buf` with samples and returns how much of it is audio, the rest is silence
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Decoder::Wav(wav) => wav.read_exact(buf).await.map_err(Error::Wav),
+            Decoder::Aiff(aiff) => aiff.read_exact(buf).await.map_err(Error::Aiff),
+            Decoder::Mp3(mp3) => mp3.read_exact(buf).await.map_err(Error::Mp3),
+            Decoder::Flac(flac) => flac.read_exact(buf).await.map_err(Error::Flac),
+            Decoder::Vorbis(vorbis) => vorbis.read_exact(buf).await.map_err(Error::Vorbis),
+        }
+    }
+
+    // Moves playback to `ms` into the track, seeking past the end finishes it
+    pub async fn seek(&mut self, ms: u32) -> Result<(), Error> {
+        let frame = (ms as u64 * self.sample_rate() as u64 / 1000) as u32;
+        match self {
+            Decoder::Wav(wav) => wav.seek(frame).await.map_err(Error::Wav),
+            Decoder::Aiff(aiff) => aiff.seek(frame).map_err(Error::Aiff),
+            Decoder::Mp3(mp3) => mp3.seek(ms).map_err(Error::Mp3),
+            Decoder::Flac(flac) => flac.seek(frame as u64).map_err(Error::Flac),
+            Decoder::Vorbis(vorbis) => vorbis.seek(frame as u64).map_err(Error::Vorbis),
+        }
+    }
+
+    pub async fn close(self) {
+        let file = match self {
+            Decoder::Wav(wav) => wav.destroy(),
+            Decoder::Aiff(aiff) => aiff.destroy(),
+            Decoder::Mp3(mp3) => mp3.destroy(),
+            Decoder::Flac(flac) => flac.destroy(),
+            Decoder::Vorbis(vorbis) => vorbis.destroy(),
+        };
+        // the track is over either way, a file that fails to close is only logged
+        if let Err(e) = file.close().await {
+            error!("Failed to close the track: {}", e);
+        }
+    }
+}
+
+// Buffered reader over a song file.
+// Keeps the unread bytes contiguous so a whole frame can be parsed from `buffered`
+pub struct FileBuffer<'a, const N: usize> {
+    file: SdFile<'a>,
+    buf: [u8; N],
+    start: usize,
+    end: usize,
+    eof: bool,
+}
+
+impl<'a, const N: usize> FileBuffer<'a, N> {
+    pub fn new(file: SdFile<'a>) -> Self {
+        Self {
+            file,
+            buf: [0; N],
+            start: 0,
+            end: 0,
+            eof: false,
+        }
+    }
+
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+
+    pub fn consume(&mut self, len: usize) {
+        self.start = (self.start + len).min(self.end);
+    }
+
+    // The whole file has been read into the buffer, there may still be unread bytes buffered
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.start == 0 && self.end == N
+    }
+
+    // File offset of the first unread byte
+    pub fn position(&self) -> u32 {
+        self.file.offset() - (self.end - self.start) as u32
+    }
+
+    pub fn file_len(&self) -> u32 {
+        self.file.length()
+    }
+
+    // Drops the buffered data and carries on reading at `offset`
+    pub fn seek(&mut self, offset: u32) -> Result<(), SdError> {
+        self.file.seek_from_start(offset)?;
+        self.start = 0;
+        self.end = 0;
+        self.eof = false;
+        Ok(())
+    }
+
+    // Moves the unread data to the front of the buffer and tops it up from the file
+    pub async fn refill(&mut self) -> Result<(), SdError> {
+        self.buf.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+
+        while self.end < N && !self.eof {
+            let read = self.file.read(&mut self.buf[self.end..]).await?;
+            if read == 0 {
+                self.eof = true;
+            }
+            self.end += read;
+        }
+        Ok(())
+    }
+
+    // Skips `len` bytes, seeking past whatever is not buffered yet
+    pub fn skip(&mut self, len: usize) -> Result<(), SdError> {
+        let buffered = self.end - self.start;
+        if len <= buffered {
+            self.start += len;
+            return Ok(());
+        }
+
+        self.file.seek_from_current((len - buffered) as i32)?;
+        self.start = 0;
+        self.end = 0;
+        self.eof = false;
+        Ok(())
+    }
+
+    pub fn into_file(self) -> SdFile<'a> {
+        self.file
+    }
+}
+
+// Reads until `buf` is full or the file ends, returns the number of bytes read
+pub async fn read_all(file: &mut SdFile<'_>, buf: &mut [u8]) -> Result<usize, SdError> {
+    let mut read = 0;
+    while read < buf.len() {
+        let len = file.read(&mut buf[read..]).await?;
+        if len == 0 {
+            break;
+        }
+        read += len;
+    }
+    Ok(read)
+}
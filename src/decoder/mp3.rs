@@ -0,0 +1,665 @@
+// MPEG layer III through minimp3, by way of rmp3. minimp3 is C, see Cargo.toml for why and the
+// cross compiler it needs. Only the frame decoding is left to it, finding the frames, the
+// Xing/VBRI/LAME headers and seeking are done here
+use super::FileBuffer;
+use crate::file_reader::{SdError, SdFile};
+use crate::replaygain::{ReplayGain, parse_id3_txxx};
+use defmt::{Format, info, warn};
+use rmp3::{Frame, MAX_SAMPLES_PER_FRAME, RawDecoder, Sample};
+
+// Largest possible layer III frame, 320kbps at 32khz
+const MAX_FRAME_LEN: usize = 1441;
+// Delay of the mdct filterbank every decoder adds on top of the encoder delay
+const DECODER_DELAY: u32 = 528 + 1;
+// Enough for a couple of the largest possible frames
+const INPUT_SIZE: usize = 4096;
+// Keep at least this much data buffered so the decoder can always see a whole frame
+const REFILL_BELOW: usize = 2048;
+
+#[derive(Debug, Format)]
+pub enum Error {
+    Sd(SdError),
+    // No valid mpeg audio frame was found in the file
+    NoFrames,
+}
+
+impl From<SdError> for Error {
+    fn from(e: SdError) -> Self {
+        Error::Sd(e)
+    }
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Mpeg1,
+    Mpeg2,
+    Mpeg25,
+}
+
+#[derive(Debug, Format, Clone, Copy)]
+pub struct FrameHeader {
+    pub version: Version,
+    pub bitrate: u32,
+    pub sample_rate: u32,
+    pub channels: u16,
+    // length of the whole frame including this header
+    pub frame_len: usize,
+}
+
+impl FrameHeader {
+    // Parses a layer III frame header
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let [b0, b1, b2, b3, ..] = *bytes else {
+            return None;
+        };
+        // 11 bit frame sync
+        if b0 != 0xFF || b1 & 0xE0 != 0xE0 {
+            return None;
+        }
+
+        let version = match (b1 >> 3) & 0b11 {
+            0b00 => Version::Mpeg25,
+            0b10 => Version::Mpeg2,
+            0b11 => Version::Mpeg1,
+            _ => return None,
+        };
+        // only layer III is used for mp3 files
+        if (b1 >> 1) & 0b11 != 0b01 {
+            return None;
+        }
+
+        let bitrate_idx = (b2 >> 4) as usize;
+        let bitrate = match version {
+            Version::Mpeg1 => MPEG1_BITRATES[bitrate_idx],
+            _ => MPEG2_BITRATES[bitrate_idx],
+        } as u32
+            * 1000;
+        // free format and invalid bitrates are not supported
+        if bitrate == 0 {
+            return None;
+        }
+
+        let sample_rate_idx = ((b2 >> 2) & 0b11) as usize;
+        if sample_rate_idx == 3 {
+            return None;
+        }
+        let sample_rate = match version {
+            Version::Mpeg1 => [44_100, 48_000, 32_000],
+            Version::Mpeg2 => [22_050, 24_000, 16_000],
+            Version::Mpeg25 => [11_025, 12_000, 8_000],
+        }[sample_rate_idx];
+
+        let padding = ((b2 >> 1) & 1) as usize;
+        let channels = if b3 >> 6 == 0b11 { 1 } else { 2 };
+        let frame_len = (Self::samples_for(version) / 8) as usize * bitrate as usize
+            / sample_rate as usize
+            + padding;
+
+        Some(Self {
+            version,
+            bitrate,
+            sample_rate,
+            channels,
+            frame_len,
+        })
+    }
+
+    fn samples_for(version: Version) -> u32 {
+        match version {
+            Version::Mpeg1 => 1152,
+            _ => 576,
+        }
+    }
+
+    pub fn samples_per_frame(&self) -> u32 {
+        Self::samples_for(self.version)
+    }
+
+    // Offset from the start of the frame to the end of the side info,
+    // which is where the Xing header lives
+    fn side_info_end(&self) -> usize {
+        4 + match (self.version, self.channels) {
+            (Version::Mpeg1, 1) => 17,
+            (Version::Mpeg1, _) => 32,
+            (_, 1) => 9,
+            (_, _) => 17,
+        }
+    }
+}
+
+const MPEG1_BITRATES: [u16; 16] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 0,
+];
+const MPEG2_BITRATES: [u16; 16] = [
+    0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160, 0,
+];
+
+// Stream length from the Xing/Info or VBRI header in the first frame
+#[derive(Debug, Format, Clone, Copy, Default)]
+pub struct VbrInfo {
+    pub frames: Option<u32>,
+    pub bytes: Option<u32>,
+    // Xing seek table, entry `i` is where `i` percent of the duration starts in 256ths of `bytes`.
+    // Files with a VBRI header get one made from theirs
+    pub toc: Option<[u8; 100]>,
+    // samples the encoder added before and after the audio, from the LAME tag
+    pub gapless: Option<(u16, u16)>,
+}
+
+impl VbrInfo {
+    // Parses the Xing/Info or VBRI header from a frame, if it has one
+    pub fn parse(header: &FrameHeader, frame: &[u8]) -> Option<Self> {
+        let xing = header.side_info_end();
+        match frame.get(xing..xing + 4)? {
+            b"Xing" | b"Info" => {
+                let flags = read_u32_be(frame, xing + 4)?;
+                let mut offset = xing + 8;
+                let mut info = Self::default();
+                if flags & 0x1 != 0 {
+                    info.frames = read_u32_be(frame, offset);
+                    offset += 4;
+                }
+                if flags & 0x2 != 0 {
+                    info.bytes = read_u32_be(frame, offset);
+                    offset += 4;
+                }
+                if flags & 0x4 != 0 {
+                    info.toc = frame.get(offset..offset + 100)?.try_into().ok();
+                    offset += 100;
+                }
+                if flags & 0x8 != 0 {
+                    offset += 4;
+                }
+                // LAME and ffmpeg append their tag straight after the Xing fields
+                if let Some([b'L', b'A', b'M', b'E', ..] | [b'L', b'a', b'v', b'c' | b'f', ..]) =
+                    frame.get(offset..offset + 24)
+                {
+                    let delay = &frame[offset + 21..offset + 24];
+                    info.gapless = Some((
+                        (delay[0] as u16) << 4 | (delay[1] as u16) >> 4,
+                        ((delay[1] & 0xF) as u16) << 8 | delay[2] as u16,
+                    ));
+                }
+                return Some(info);
+            }
+            _ => (),
+        }
+
+        // VBRI is always 32 bytes after the frame header
+        if frame.get(36..40)? == b"VBRI" {
+            let bytes = read_u32_be(frame, 36 + 10);
+            let frames = read_u32_be(frame, 36 + 14);
+            return Some(Self {
+                bytes,
+                frames,
+                toc: bytes.and_then(|bytes| vbri_toc(frame.get(36 + 18..)?, bytes, frames)),
+                gapless: None,
+            });
+        }
+
+        None
+    }
+}
+
+// Turns the VBRI seek table into a Xing one. The VBRI table splits the stream into parts of the
+// same number of frames and holds the size of each part, scaled down by a factor
+fn vbri_toc(table: &[u8], bytes: u32, frames: Option<u32>) -> Option<[u8; 100]> {
+    let field = |at: usize| Some(u16::from_be_bytes([*table.get(at)?, *table.get(at + 1)?]));
+    let (entries, scale) = (field(0)? as usize, field(2)? as u64);
+    let (entry_len, frames_per_entry) = (field(4)? as usize, field(6)? as u64);
+    if entries == 0 || !(1..=4).contains(&entry_len) || frames_per_entry == 0 || bytes == 0 {
+        return None;
+    }
+    let sizes = table.get(8..8 + entries * entry_len)?;
+    let size = |i: usize| {
+        sizes
+            .get(i * entry_len..(i + 1) * entry_len)
+            .map_or(0, |entry| {
+                entry.iter().fold(0, |size, b| size << 8 | *b as u64) * scale
+            })
+    };
+    let frames = frames.map_or(entries as u64 * frames_per_entry, |frames| frames as u64);
+
+    let mut toc = [0; 100];
+    // where `part` starts in the stream
+    let (mut part, mut start) = (0, 0);
+    for (percent, entry) in toc.iter_mut().enumerate() {
+        // the part `percent` of the duration falls into, with 16 fractional bits
+        let position = ((percent as u64 * frames) << 16) / (100 * frames_per_entry);
+        while part < (position >> 16) as usize {
+            start += size(part);
+            part += 1;
+        }
+        // in 65536ths of a byte, rounded to the nearest 256th of `bytes`
+        let offset = (start << 16) + size(part) * (position & 0xFFFF);
+        let bytes = bytes as u64;
+        *entry = ((offset + (bytes << 7)) / (bytes << 8)).min(255) as u8;
+    }
+    Some(toc)
+}
+
+fn read_u32_be(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+// Reads the ReplayGain frames of the ID3v2 tag at the start of `input` and skips the rest of it.
+// `len` is the size of the whole tag from `id3v2_len`
+async fn read_id3v2(
+    input: &mut FileBuffer<'_, INPUT_SIZE>,
+    len: usize,
+) -> Result<ReplayGain, Error> {
+    let mut tags = ReplayGain::default();
+    let header = &input.buffered()[..10];
+    let (version, flags) = (header[3], header[5]);
+    // the frames of an unsynchronised tag would have to be decoded first, that is rare enough
+    if !(2..=4).contains(&version) || flags & 0x80 != 0 {
+        input.skip(len)?;
+        return Ok(tags);
+    }
+    let footer = if flags & 0x10 != 0 { 10 } else { 0 };
+    input.consume(10);
+    let mut left = len - 10 - footer;
+
+    // the extended header of v2.4 counts its own size field, the one of v2.3 does not
+    if flags & 0x40 != 0 {
+        let size = read_u32_be(input.buffered(), 0).unwrap_or(0);
+        let size = match version {
+            4 => syncsafe(size),
+            _ => size as usize + 4,
+        }
+        .min(left);
+        input.skip(size)?;
+        left -= size;
+    }
+
+    // v2.2 has 3 byte frame ids and sizes, the later versions 4 bytes and 2 bytes of flags
+    let header_len = if version == 2 { 6 } else { 10 };
+    while left >= header_len {
+        if input.buffered().len() < header_len {
+            input.refill().await?;
+        }
+        let Some(header) = input.buffered().get(..header_len) else {
+            break;
+        };
+
+        let (id, size) = match version {
+            2 => (
+                &header[..3],
+                u32::from_be_bytes([0, header[3], header[4], header[5]]) as usize,
+            ),
+            3 => (&header[..4], read_u32_be(header, 4).unwrap_or(0) as usize),
+            _ => (&header[..4], syncsafe(read_u32_be(header, 4).unwrap_or(0))),
+        };
+        // the padding after the last frame
+        if id[0] == 0 {
+            break;
+        }
+        let txxx = id == b"TXX" || id == b"TXXX";
+        input.consume(header_len);
+        left -= header_len;
+
+        let size = size.min(left);
+        if txxx && size <= INPUT_SIZE {
+            if input.buffered().len() < size {
+                input.refill().await?;
+            }
+            let frame = input.buffered();
+            parse_id3_txxx(&frame[..size.min(frame.len())], &mut tags);
+        }
+        input.skip(size)?;
+        left -= size;
+    }
+    input.skip(left + footer)?;
+    Ok(tags)
+}
+
+// 7 bits per byte, so the value never looks like a frame sync
+fn syncsafe(value: u32) -> usize {
+    value
+        .to_be_bytes()
+        .iter()
+        .fold(0usize, |size, b| size << 7 | (*b & 0x7F) as usize)
+}
+
+// Size of the ID3v2 tag at the start of a file, including its header and footer
+fn id3v2_len(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < 10 || &bytes[..3] != b"ID3" {
+        return None;
+    }
+    let size = syncsafe(read_u32_be(bytes, 6)?);
+    let footer = if bytes[5] & 0x10 != 0 { 10 } else { 0 };
+    Some(10 + size + footer)
+}
+
+pub struct Mp3Decoder<'a> {
+    input: FileBuffer<'a, INPUT_SIZE>,
+    decoder: RawDecoder,
+    pcm: [Sample; MAX_SAMPLES_PER_FRAME],
+    pcm_start: usize,
+    pcm_end: usize,
+    finished: bool,
+    // samples per channel still to be dropped from the start of the stream
+    skip: u32,
+    // samples per channel of audio in the stream and how many are left to play,
+    // only known for files with a Xing or LAME tag
+    length: Option<u64>,
+    samples_left: Option<u64>,
+    // file offset of the first frame, seek offsets count from here
+    first_frame: u32,
+    samples_per_frame: u32,
+    pub sample_rate: u32,
+    pub num_channels: u16,
+    // average bitrate for vbr files with a Xing or VBRI header, otherwise the first frames bitrate
+    pub bitrate: u32,
+    pub vbr: Option<VbrInfo>,
+    pub replay_gain: ReplayGain,
+}
+
+impl<'a> Mp3Decoder<'a> {
+    // Hands the file back with the error when it cannot be played, so it can be closed
+    pub async fn new(file: SdFile<'a>) -> Result<Self, (Error, SdFile<'a>)> {
+        let mut decoder = Self {
+            input: FileBuffer::new(file),
+            decoder: RawDecoder::new(),
+            pcm: [0; MAX_SAMPLES_PER_FRAME],
+            pcm_start: 0,
+            pcm_end: 0,
+            finished: false,
+            skip: 0,
+            length: None,
+            samples_left: None,
+            first_frame: 0,
+            samples_per_frame: 0,
+            sample_rate: 0,
+            num_channels: 0,
+            bitrate: 0,
+            vbr: None,
+            replay_gain: ReplayGain::default(),
+        };
+        match decoder.read_headers().await {
+            Ok(()) => Ok(decoder),
+            Err(e) => Err((e, decoder.destroy())),
+        }
+    }
+
+    // Reads the tags and the first frame header, the input is left at the first audio frame
+    async fn read_headers(&mut self) -> Result<(), Error> {
+        // skip over the tags before the first frame
+        let input = &mut self.input;
+        input.refill().await?;
+        if let Some(len) = id3v2_len(input.buffered()) {
+            self.replay_gain = read_id3v2(input, len).await?;
+            input.refill().await?;
+        }
+
+        // find the first frame, confirmed by a second frame directly after it
+        let (offset, header) = loop {
+            let data = input.buffered();
+            let found = (0..data.len()).find_map(|i| {
+                let header = FrameHeader::parse(&data[i..])?;
+                let next = FrameHeader::parse(data.get(i + header.frame_len..)?)?;
+                (next.version == header.version && next.sample_rate == header.sample_rate)
+                    .then_some((i, header))
+            });
+            if let Some(found) = found {
+                break found;
+            }
+            if input.is_eof() {
+                return Err(Error::NoFrames);
+            }
+            // keep the tail in case a frame straddles the refill
+            let searched = data.len().saturating_sub(MAX_FRAME_LEN + 4);
+            input.consume(searched);
+            input.refill().await?;
+        };
+        input.consume(offset);
+
+        self.first_frame = input.position();
+        self.samples_per_frame = header.samples_per_frame();
+        self.sample_rate = header.sample_rate;
+        self.num_channels = header.channels;
+        self.bitrate = header.bitrate;
+
+        // the Xing/VBRI frame carries no audio, so use its info and drop it
+        if let Some(vbr) = VbrInfo::parse(&header, self.input.buffered()) {
+            if let (Some(frames), Some(bytes)) = (vbr.frames, vbr.bytes) {
+                if frames > 0 {
+                    self.bitrate = (bytes as u64 * 8 * header.sample_rate as u64
+                        / (frames as u64 * header.samples_per_frame() as u64))
+                        as u32;
+                }
+            }
+            if let Some(frames) = vbr.frames {
+                let total = frames as u64 * header.samples_per_frame() as u64;
+                self.length = Some(total);
+                // trim the silence the encoder added so albums play without gaps
+                if let Some((delay, padding)) = vbr.gapless {
+                    self.skip = delay as u32 + DECODER_DELAY;
+                    self.length = Some(total.saturating_sub(delay as u64 + padding as u64));
+                }
+                self.samples_left = self.length;
+            }
+            self.vbr = Some(vbr);
+            self.input.consume(header.frame_len);
+        }
+
+        info!(
+            "[MP3] {}hz, {} channels, {}bps{}",
+            self.sample_rate,
+            self.num_channels,
+            self.bitrate,
+            if self.vbr.is_some() { " (vbr)" } else { "" }
+        );
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished || self.samples_left == Some(0)
+    }
+
+    // Frames left to play, when the stream length is known
+    pub fn remaining_frames(&self) -> Option<u64> {
+        self.samples_left
+    }
+
+    // Ends the track early, when its file cannot be read
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+
+    pub fn destroy(self) -> SdFile<'a> {
+        self.input.into_file()
+    }
+
+    // Moves playback close to `ms`, using the seek table for vbr files
+    pub fn seek(&mut self, ms: u32) -> Result<(), Error> {
+        let file_len = self.input.file_len().saturating_sub(self.first_frame) as u64;
+        let vbr = self.vbr.unwrap_or_default();
+        let stream_len = vbr
+            .bytes
+            .map_or(file_len, |bytes| (bytes as u64).min(file_len));
+
+        let offset = match (vbr.toc, vbr.frames) {
+            (Some(toc), Some(frames)) if frames > 0 => {
+                let duration_ms =
+                    frames as u64 * self.samples_per_frame as u64 * 1000 / self.sample_rate as u64;
+                // position in 256ths of a percent, interpolated between the table entries
+                let percent = (ms as u64 * 100 * 256 / duration_ms.max(1)).min(100 * 256);
+                let (i, fraction) = ((percent >> 8) as usize, percent & 0xFF);
+                let from = toc.get(i).map_or(256, |entry| *entry as u64);
+                let to = toc.get(i + 1).map_or(256, |entry| *entry as u64);
+                ((from * 256 + to.saturating_sub(from) * fraction) * stream_len) >> 16
+            }
+            // constant bitrate, or vbr without a table where the average bitrate has to do
+            _ => ms as u64 * self.bitrate as u64 / 8000,
+        };
+        if offset >= stream_len {
+            self.finished = true;
+            return Ok(());
+        }
+
+        // the decoder finds the next frame on its own, the bit reservoir of the old position is useless
+        self.input.seek(self.first_frame + offset as u32)?;
+        self.decoder = RawDecoder::new();
+        self.pcm_start = 0;
+        self.pcm_end = 0;
+        self.finished = false;
+        self.skip = 0;
+        let sample = ms as u64 * self.sample_rate as u64 / 1000;
+        self.samples_left = self.length.map(|length| length.saturating_sub(sample));
+        Ok(())
+    }
+
+    // Fills `buf` with 16bit little endian pcm, as if it was read from a wav file.
+    // Pads with silence once the stream ends and returns the length before the padding
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let channels = self.num_channels as usize;
+        let mut written = 0;
+        while written + 2 * channels <= buf.len() {
+            if self.samples_left == Some(0) {
+                // the rest is encoder padding
+                self.finished = true;
+                self.pcm_start = self.pcm_end;
+            }
+            if self.pcm_start == self.pcm_end {
+                if self.finished || !self.decode_frame().await? {
+                    self.finished = true;
+                    buf[written..].fill(0);
+                    return Ok(written);
+                }
+                // drop the encoder delay
+                let skip = (self.skip as usize).min((self.pcm_end - self.pcm_start) / channels);
+                self.pcm_start += skip * channels;
+                self.skip -= skip as u32;
+                continue;
+            }
+
+            let samples = &self.pcm[self.pcm_start..self.pcm_end];
+            let mut count = samples.len().min((buf.len() - written) / 2);
+            if let Some(left) = &mut self.samples_left {
+                // stop before the encoder padding, on a whole frame
+                count = count.min(*left as usize * channels) / channels * channels;
+                *left -= (count / channels) as u64;
+            }
+            for (out, sample) in buf[written..].chunks_exact_mut(2).zip(&samples[..count]) {
+                out.copy_from_slice(&sample.to_le_bytes());
+            }
+            self.pcm_start += count;
+            written += count * 2;
+        }
+        Ok(written)
+    }
+
+    // Decodes the next audio frame into the pcm buffer. Returns false at the end of the stream
+    async fn decode_frame(&mut self) -> Result<bool, Error> {
+        loop {
+            if self.input.buffered().len() < REFILL_BELOW && !self.input.is_eof() {
+                self.input.refill().await?;
+            }
+
+            match self.decoder.next(self.input.buffered(), &mut self.pcm) {
+                Some((Frame::Audio(audio), consumed)) => {
+                    let sample_count = audio.sample_count();
+                    let channels = audio.channels();
+                    let sample_rate = audio.sample_rate();
+                    self.input.consume(consumed);
+                    if sample_count == 0 {
+                        continue;
+                    }
+
+                    if sample_rate != self.sample_rate {
+                        warn!(
+                            "[MP3] sample rate changed mid stream {}hz -> {}hz",
+                            self.sample_rate, sample_rate
+                        );
+                    }
+                    self.pcm_start = 0;
+                    self.pcm_end = self.match_channels(sample_count, channels);
+                    return Ok(true);
+                }
+                Some((Frame::Other(_), consumed)) => self.input.consume(consumed),
+                None if self.input.is_eof() => return Ok(false),
+                None => {
+                    // nothing decodable in a full buffer, drop it and look further on
+                    if self.input.is_full() {
+                        self.input.consume(INPUT_SIZE);
+                    }
+                    self.input.refill().await?;
+                }
+            }
+        }
+    }
+
+    // Converts a decoded frame to the channel count of the stream, returns the number of samples
+    fn match_channels(&mut self, sample_count: usize, channels: u16) -> usize {
+        match (channels, self.num_channels) {
+            (1, 2) => {
+                for i in (0..sample_count).rev() {
+                    self.pcm[i * 2] = self.pcm[i];
+                    self.pcm[i * 2 + 1] = self.pcm[i];
+                }
+                sample_count * 2
+            }
+            (2, 1) => {
+                for i in 0..sample_count {
+                    let (l, r) = (self.pcm[i * 2] as i32, self.pcm[i * 2 + 1] as i32);
+                    self.pcm[i] = ((l + r) / 2) as i16;
+                }
+                sample_count
+            }
+            _ => sample_count * channels as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A VBRI table from after the frame count, with 2 byte entries
+    fn vbri_table(scale: u16, frames_per_entry: u16, sizes: &[u16]) -> Vec<u8> {
+        let mut table = Vec::new();
+        for field in [sizes.len() as u16, scale, 2, frames_per_entry] {
+            table.extend_from_slice(&field.to_be_bytes());
+        }
+        for size in sizes {
+            table.extend_from_slice(&size.to_be_bytes());
+        }
+        table
+    }
+
+    #[test]
+    fn a_vbri_table_of_equal_parts_is_linear() {
+        let table = vbri_table(2, 10, &[500; 4]);
+        let toc = vbri_toc(&table, 4000, Some(40)).unwrap();
+        for (percent, entry) in toc.iter().enumerate() {
+            assert_eq!(*entry as usize, (percent * 256 + 50) / 100, "{}%", percent);
+        }
+    }
+
+    #[test]
+    fn a_vbri_table_follows_the_size_of_each_part() {
+        // the first half of the duration is a quarter of the bytes
+        let toc = vbri_table(1, 5, &[100, 100, 300, 300]);
+        let toc = vbri_toc(&toc, 800, Some(20)).unwrap();
+        assert_eq!(toc[0], 0);
+        assert_eq!(toc[25], 32);
+        assert_eq!(toc[50], 64);
+        // within the third part, 200 bytes and 48% of its 300 bytes in
+        assert_eq!(toc[62], 110);
+        assert_eq!(toc[75], 160);
+        assert_eq!(toc[99], 252);
+    }
+
+    #[test]
+    fn a_broken_vbri_table_is_ignored() {
+        assert!(vbri_toc(&vbri_table(1, 5, &[]), 800, Some(20)).is_none());
+        assert!(vbri_toc(&vbri_table(1, 0, &[100]), 800, Some(20)).is_none());
+        assert!(vbri_toc(&vbri_table(1, 5, &[100]), 0, Some(20)).is_none());
+        // cut off
+        let table = vbri_table(1, 5, &[100, 100]);
+        assert!(vbri_toc(&table[..table.len() - 1], 800, Some(10)).is_none());
+    }
+}
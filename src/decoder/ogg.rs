@@ -0,0 +1,366 @@
+use super::FileBuffer;
+use crate::file_reader::{SdError, SdFile};
+use defmt::{Format, warn};
+
+// Pages are streamed through this, only the page header has to fit at once
+const INPUT_SIZE: usize = 4096;
+// Vorbis audio packets are a few KiB at most and the setup header about 4KiB, larger packets
+// (cover art in the comment header) are dropped
+const MAX_PACKET: usize = 8 * 1024;
+// The last page is looked for this far from the end of the file
+const MAX_TAIL_SCAN: u32 = 64 * 1024;
+// 27 byte fixed header followed by up to 255 lacing values
+pub const MAX_PAGE_HEADER: usize = 27 + 255;
+// Longest identification packet looked at, the vorbis one is 30 bytes
+const MAX_ID_PACKET: usize = 30;
+// Enough of the start of a file to hold its first page up to the identification packet
+pub const FIRST_PAGE_LEN: usize = MAX_PAGE_HEADER + MAX_ID_PACKET;
+
+#[derive(Debug, Format, Clone, Copy)]
+pub enum Codec {
+    Opus {
+        channels: u8,
+        pre_skip: u16,
+        input_sample_rate: u32,
+        output_gain: i16,
+    },
+    Vorbis {
+        channels: u8,
+        sample_rate: u32,
+        nominal_bitrate: i32,
+    },
+}
+
+impl Codec {
+    // Identifies the codec from the first packet of a logical stream
+    pub fn identify(packet: &[u8]) -> Option<Self> {
+        if packet.starts_with(b"OpusHead") && packet.len() >= 19 {
+            return Some(Codec::Opus {
+                channels: packet[9],
+                pre_skip: u16::from_le_bytes([packet[10], packet[11]]),
+                input_sample_rate: u32::from_le_bytes(packet[12..16].try_into().ok()?),
+                output_gain: i16::from_le_bytes([packet[16], packet[17]]),
+            });
+        }
+        if packet.starts_with(b"\x01vorbis") && packet.len() >= 30 {
+            return Some(Codec::Vorbis {
+                channels: packet[11],
+                sample_rate: u32::from_le_bytes(packet[12..16].try_into().ok()?),
+                nominal_bitrate: i32::from_le_bytes(packet[20..24].try_into().ok()?),
+            });
+        }
+        None
+    }
+
+    // The codec of a file from its first page, which holds the identification packet alone
+    pub fn from_first_page(data: &[u8]) -> Option<Self> {
+        let header = PageHeader::parse(data)?;
+        let segments = header.segments as usize;
+        let lacing = data.get(27..27 + segments)?;
+        let len = lacing
+            .iter()
+            .position(|len| *len < 255)
+            .map_or(segments * 255, |last| last * 255 + lacing[last] as usize);
+        let packet = data.get(27 + segments..)?;
+        Self::identify(&packet[..len.min(packet.len())])
+    }
+}
+
+#[derive(Debug, Format, Clone, Copy)]
+pub struct PageHeader {
+    pub continued: bool,
+    pub first: bool,
+    pub last: bool,
+    pub granule_position: i64,
+    pub serial: u32,
+    pub sequence: u32,
+    pub segments: u8,
+}
+
+impl PageHeader {
+    // Parses the fixed part of a page header, the lacing values follow it
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let b = bytes.get(..27)?;
+        if &b[..4] != b"OggS" || b[4] != 0 {
+            return None;
+        }
+        Some(Self {
+            continued: b[5] & 0x1 != 0,
+            first: b[5] & 0x2 != 0,
+            last: b[5] & 0x4 != 0,
+            granule_position: i64::from_le_bytes(b[6..14].try_into().ok()?),
+            serial: u32::from_le_bytes(b[14..18].try_into().ok()?),
+            sequence: u32::from_le_bytes(b[18..22].try_into().ok()?),
+            segments: b[26],
+        })
+    }
+}
+
+// Splits the first logical stream of an ogg file into packets
+pub struct OggReader<'a> {
+    input: FileBuffer<'a, INPUT_SIZE>,
+    serial: Option<u32>,
+    lacing: [u8; 255],
+    segments: usize,
+    segment: usize,
+    // the current page belongs to the stream being read
+    page_wanted: bool,
+    granule_position: i64,
+    last_page: bool,
+    packet: [u8; MAX_PACKET],
+    packet_len: usize,
+    // the packet did not fit and is dropped once it ends
+    packet_dropped: bool,
+    // the packet started before a seek and is dropped once it ends
+    packet_cut: bool,
+}
+
+impl<'a> OggReader<'a> {
+    pub fn new(file: SdFile<'a>) -> Self {
+        Self {
+            input: FileBuffer::new(file),
+            serial: None,
+            lacing: [0; 255],
+            segments: 0,
+            segment: 0,
+            page_wanted: false,
+            granule_position: -1,
+            last_page: false,
+            packet: [0; MAX_PACKET],
+            packet_len: 0,
+            packet_dropped: false,
+            packet_cut: false,
+        }
+    }
+
+    pub fn destroy(self) -> SdFile<'a> {
+        self.input.into_file()
+    }
+
+    // File offset of the next page, once the packets of the current one are read
+    pub fn position(&self) -> u32 {
+        self.input.position()
+    }
+
+    pub fn file_len(&self) -> u32 {
+        self.input.file_len()
+    }
+
+    // Position in samples at the end of the packet returned last, when it is the last packet
+    // finishing on its page. That is the only one a page tells the position of
+    pub fn granule_position(&self) -> Option<u64> {
+        let ends_page = self.lacing[self.segment..self.segments]
+            .iter()
+            .all(|len| *len == 255);
+        (ends_page && self.granule_position >= 0).then_some(self.granule_position as u64)
+    }
+
+    // The packet returned last is on the final page of the stream
+    pub fn is_last_page(&self) -> bool {
+        self.last_page
+    }
+
+    // Carries on reading at `offset`, from the first page that starts after it
+    pub fn seek(&mut self, offset: u32) -> Result<(), SdError> {
+        self.input.seek(offset)?;
+        self.segments = 0;
+        self.segment = 0;
+        self.page_wanted = false;
+        self.packet_len = 0;
+        self.packet_cut = true;
+        Ok(())
+    }
+
+    // Returns the next complete packet of the stream, or None at the end of the file
+    pub async fn next_packet(&mut self) -> Result<Option<&[u8]>, SdError> {
+        self.packet_len = 0;
+        self.packet_dropped = false;
+        loop {
+            if self.segment == self.segments {
+                if !self.next_page().await? {
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            let len = self.lacing[self.segment] as usize;
+            self.segment += 1;
+            self.read_segment(len).await?;
+
+            // a lacing value below 255 ends the packet
+            if len < 255 && self.page_wanted {
+                if !self.packet_dropped && !self.packet_cut {
+                    return Ok(Some(&self.packet[..self.packet_len]));
+                }
+                if self.packet_dropped {
+                    warn!("[OGG] dropped a packet larger than {} bytes", MAX_PACKET);
+                }
+                self.packet_len = 0;
+                self.packet_dropped = false;
+                self.packet_cut = false;
+            }
+        }
+    }
+
+    // Reads the next page header, resyncing on the capture pattern if needed.
+    // Returns false at the end of the file
+    async fn next_page(&mut self) -> Result<bool, SdError> {
+        loop {
+            if self.input.buffered().len() < MAX_PAGE_HEADER && !self.input.is_eof() {
+                self.input.refill().await?;
+            }
+
+            let data = self.input.buffered();
+            let Some(header) = PageHeader::parse(data) else {
+                if data.len() < 27 && self.input.is_eof() {
+                    return Ok(false);
+                }
+                // skip to the next capture pattern
+                let skip = data[1..]
+                    .windows(4)
+                    .position(|w| w == b"OggS")
+                    .map_or(data.len().saturating_sub(3), |pos| pos + 1);
+                self.input.consume(skip);
+                continue;
+            };
+
+            let segments = header.segments as usize;
+            let Some(lacing) = data.get(27..27 + segments) else {
+                return Ok(false);
+            };
+            self.lacing[..segments].copy_from_slice(lacing);
+            self.segments = segments;
+            self.segment = 0;
+            self.input.consume(27 + segments);
+
+            // lock on to the first stream in the file and ignore any others
+            let serial = *self.serial.get_or_insert(header.serial);
+            self.page_wanted = header.serial == serial;
+            if self.page_wanted {
+                self.granule_position = header.granule_position;
+                self.last_page = header.last;
+                if !header.continued {
+                    // a packet cut off by a lost page cannot be completed
+                    self.packet_len = 0;
+                    self.packet_dropped = false;
+                    self.packet_cut = false;
+                }
+            }
+            return Ok(true);
+        }
+    }
+
+    async fn read_segment(&mut self, mut len: usize) -> Result<(), SdError> {
+        while len > 0 {
+            if self.input.buffered().is_empty() {
+                if self.input.is_eof() {
+                    return Ok(());
+                }
+                self.input.refill().await?;
+            }
+
+            let data = self.input.buffered();
+            let chunk = len.min(data.len());
+            if self.page_wanted && !self.packet_dropped && !self.packet_cut {
+                match self
+                    .packet
+                    .get_mut(self.packet_len..self.packet_len + chunk)
+                {
+                    Some(packet) => {
+                        packet.copy_from_slice(&data[..chunk]);
+                        self.packet_len += chunk;
+                    }
+                    None => self.packet_dropped = true,
+                }
+            }
+            self.input.consume(chunk);
+            len -= chunk;
+        }
+        Ok(())
+    }
+
+    // Granule position of the last page of the stream, the length of the stream in samples.
+    // Reading carries on where it was afterwards
+    pub async fn last_granule_position(&mut self) -> Result<Option<u64>, SdError> {
+        let resume = self.input.position();
+        let len = self.input.file_len();
+        let mut end = len;
+        let mut found = None;
+        while found.is_none() && len - end < MAX_TAIL_SCAN {
+            let start = end.saturating_sub(INPUT_SIZE as u32);
+            self.input.seek(start)?;
+            self.input.refill().await?;
+            let data = self.input.buffered();
+            let data = &data[..data.len().min((end - start) as usize)];
+            // the last page header of the stream with a position, packets that do not end on a
+            // page leave it at -1
+            found = (0..data.len().saturating_sub(3))
+                .rev()
+                .filter(|at| &data[*at..*at + 4] == b"OggS")
+                .filter_map(|at| PageHeader::parse(&data[at..]))
+                .find(|header| Some(header.serial) == self.serial && header.granule_position >= 0)
+                .map(|header| header.granule_position as u64);
+            if start == 0 {
+                break;
+            }
+            // the chunks overlap by a header, one cut in two is found in the earlier chunk
+            end = start + 27;
+        }
+        self.input.seek(resume)?;
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The first page of an 8kHz mono file from libvorbis
+    const VORBIS_PAGE: [u8; 58] = [
+        0x4f, 0x67, 0x67, 0x53, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x17,
+        0xc3, 0x83, 0x66, 0x00, 0x00, 0x00, 0x00, 0xcc, 0x48, 0x0d, 0x0f, 0x01, 0x1e, 0x01, 0x76,
+        0x6f, 0x72, 0x62, 0x69, 0x73, 0x00, 0x00, 0x00, 0x00, 0x01, 0x40, 0x1f, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0xb0, 0x36, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x99, 0x01,
+    ];
+
+    #[test]
+    fn vorbis_is_identified_from_the_first_page() {
+        let header = PageHeader::parse(&VORBIS_PAGE).unwrap();
+        assert!(header.first && !header.continued);
+        assert_eq!(header.segments, 1);
+        assert!(matches!(
+            Codec::from_first_page(&VORBIS_PAGE),
+            Some(Codec::Vorbis {
+                channels: 1,
+                sample_rate: 8000,
+                nominal_bitrate: 14000
+            })
+        ));
+    }
+
+    #[test]
+    fn opus_is_identified_from_the_first_page() {
+        let mut page = [0u8; 27 + 1 + 19];
+        page[..4].copy_from_slice(b"OggS");
+        page[5] = 0x2;
+        page[26] = 1;
+        page[27] = 19;
+        page[28..36].copy_from_slice(b"OpusHead");
+        page[36] = 1; // version
+        page[37] = 2;
+        page[38..40].copy_from_slice(&312u16.to_le_bytes());
+        page[40..44].copy_from_slice(&48000u32.to_le_bytes());
+        assert!(matches!(
+            Codec::from_first_page(&page),
+            Some(Codec::Opus {
+                channels: 2,
+                pre_skip: 312,
+                input_sample_rate: 48000,
+                ..
+            })
+        ));
+        // a page cut short of the packet is not mistaken for anything
+        assert!(Codec::from_first_page(&page[..40]).is_none());
+        assert!(Codec::from_first_page(b"OggS").is_none());
+    }
+}
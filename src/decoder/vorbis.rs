@@ -0,0 +1,1609 @@
+// Ogg Vorbis in fixed point. The residue is decoded in Q12, scaled by the floor into a Q24
+// spectrum and turned into samples by an IMDCT built on a complex FFT. The trig and window tables
+// are computed at compile time and live in flash
+use super::ogg::OggReader;
+use crate::file_reader::{SdError, SdFile};
+use crate::replaygain::{ReplayGain, parse_vorbis_comments};
+use crate::resample::sin_pi_q30;
+use defmt::{Format, info};
+use heapless::Vec;
+
+const MAX_CHANNELS: usize = 2;
+// libvorbis uses 2048 sample long blocks from quality 0 up, the 4096 sample blocks of lower
+// qualities would need another 16KiB of block buffers
+const MAX_BLOCKSIZE: usize = 2048;
+const MAX_HALF: usize = MAX_BLOCKSIZE / 2;
+// The setup header of libvorbis has up to 45 codebooks with about 4500 used entries in all
+const MAX_CODEBOOKS: usize = 64;
+const MAX_NODES: usize = 6 * 1024;
+const MAX_MULTIPLICANDS: usize = 512;
+const MAX_FLOORS: usize = 4;
+const MAX_RESIDUES: usize = 4;
+const MAX_MAPPINGS: usize = 4;
+const MAX_MODES: usize = 8;
+const MAX_SUBMAPS: usize = MAX_CHANNELS;
+const MAX_COUPLING_STEPS: usize = MAX_CHANNELS;
+const MAX_CLASSIFICATIONS: usize = 16;
+const MAX_PARTITIONS: usize = 256;
+const FLOOR1_MAX_PARTITIONS: usize = 31;
+const FLOOR1_MAX_CLASSES: usize = 16;
+const FLOOR1_MAX_VALUES: usize = 65;
+
+// Marks a child of a huffman tree node as a codebook entry instead of another node
+const LEAF: u16 = 0x8000;
+const Q12: u32 = 12;
+// A vector value of a codebook stays this far below i32::MAX, so the passes of a residue and the
+// coupling of two channels cannot overflow
+const MAX_VALUE: i64 = i32::MAX as i64 / 16;
+
+#[derive(Debug, Format)]
+pub enum Error {
+    Sd(SdError),
+    // The identification or setup header is missing or malformed
+    BadHeader,
+    // The stream does not fit the static decode buffers
+    Unsupported {
+        sample_rate: u32,
+        channels: u8,
+        blocksize: u16,
+    },
+    // The setup header has more codebooks, floors or residues than there is room for, or uses
+    // floor type 0, which libvorbis has never written
+    SetupTooLarge,
+}
+
+impl From<SdError> for Error {
+    fn from(e: SdError) -> Self {
+        Error::Sd(e)
+    }
+}
+
+// Reads a packet from the least significant bit of each byte up, the way vorbis packs fields.
+// Reads past the end of the packet give None
+struct Bits<'p> {
+    data: &'p [u8],
+    pos: usize,
+}
+
+impl<'p> Bits<'p> {
+    fn new(data: &'p [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    // Reads up to 32 bits
+    fn read(&mut self, count: u32) -> Option<u32> {
+        if count == 0 {
+            return Some(0);
+        }
+        let end = self.pos + count as usize;
+        if end > self.data.len() * 8 {
+            self.pos = self.data.len() * 8;
+            return None;
+        }
+        let mut value = 0u64;
+        for (i, byte) in self.data[self.pos / 8..end.div_ceil(8)].iter().enumerate() {
+            value |= (*byte as u64) << (8 * i);
+        }
+        let value = (value >> (self.pos % 8)) as u32 & (u32::MAX >> (32 - count));
+        self.pos = end;
+        Some(value)
+    }
+
+    fn bit(&mut self) -> Option<usize> {
+        let byte = self.data.get(self.pos / 8)?;
+        let bit = (byte >> (self.pos % 8)) & 1;
+        self.pos += 1;
+        Some(bit as usize)
+    }
+}
+
+// Reads a field of a header packet, which must not run out
+fn read(bits: &mut Bits, count: u32) -> Result<u32, Error> {
+    bits.read(count).ok_or(Error::BadHeader)
+}
+
+// Number of bits needed for `value`
+fn ilog(value: u32) -> u32 {
+    32 - value.leading_zeros()
+}
+
+// The greatest value whose `dimensions`th power is at most `entries`
+fn lookup1_values(entries: u32, dimensions: u16) -> u32 {
+    if dimensions == 1 {
+        return entries;
+    }
+    let mut values = 0u32;
+    while (values + 1)
+        .checked_pow(dimensions as u32)
+        .is_some_and(|power| power <= entries)
+    {
+        values += 1;
+    }
+    values
+}
+
+// The float format of the codebook headers in Q12, None when it does not fit an i32
+fn float_q12(raw: u32) -> Option<i32> {
+    let mantissa = (raw & 0x1F_FFFF) as i64;
+    let exponent = ((raw >> 21) & 0x3FF) as i32 - 788 + Q12 as i32;
+    let value = match exponent {
+        _ if mantissa == 0 => 0,
+        0..=31 => mantissa << exponent,
+        ..0 => mantissa >> (-exponent).min(63),
+        _ => return None,
+    };
+    let value = if raw & 0x8000_0000 != 0 {
+        -value
+    } else {
+        value
+    };
+    i32::try_from(value).ok()
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Codebook {
+    dimensions: u16,
+    // root of the huffman tree in the node pool
+    root: u16,
+    // 0 without vectors, 1 for a lattice of `values` per dimension, 2 for a table of all of them
+    lookup: u8,
+    values: u32,
+    minimum: i32,
+    delta: i32,
+    sequence: bool,
+    // first multiplicand in the pool
+    multiplicands: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Floor {
+    multiplier: u8,
+    partitions: u8,
+    partition_class: [u8; FLOOR1_MAX_PARTITIONS],
+    class_dimensions: [u8; FLOOR1_MAX_CLASSES],
+    class_subclasses: [u8; FLOOR1_MAX_CLASSES],
+    class_masterbook: [u8; FLOOR1_MAX_CLASSES],
+    // one more than the codebook number, 0 when there is none
+    subclass_books: [[u8; 8]; FLOOR1_MAX_CLASSES],
+    values: usize,
+    x: [u16; FLOOR1_MAX_VALUES],
+    // the values in order of x
+    sorted: [u8; FLOOR1_MAX_VALUES],
+    // earlier values with the closest x below and above
+    low_neighbor: [u8; FLOOR1_MAX_VALUES],
+    high_neighbor: [u8; FLOOR1_MAX_VALUES],
+}
+
+impl Floor {
+    fn range(&self) -> i32 {
+        [256, 128, 86, 64][self.multiplier as usize - 1]
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Residue {
+    kind: u8,
+    begin: u32,
+    end: u32,
+    partition_size: u32,
+    classifications: u8,
+    classbook: u8,
+    // one more than the codebook number of each pass, 0 when the pass is skipped
+    books: [[u8; 8]; MAX_CLASSIFICATIONS],
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Mapping {
+    coupling_steps: usize,
+    magnitude: [u8; MAX_COUPLING_STEPS],
+    angle: [u8; MAX_COUPLING_STEPS],
+    mux: [u8; MAX_CHANNELS],
+    submaps: usize,
+    submap_floor: [u8; MAX_SUBMAPS],
+    submap_residue: [u8; MAX_SUBMAPS],
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Mode {
+    long: bool,
+    mapping: u8,
+}
+
+// Everything the setup header describes, with the huffman trees and vector values of all
+// codebooks in shared pools
+struct Setup {
+    codebooks: Vec<Codebook, MAX_CODEBOOKS>,
+    nodes: Vec<[u16; 2], MAX_NODES>,
+    multiplicands: Vec<u16, MAX_MULTIPLICANDS>,
+    floors: Vec<Floor, MAX_FLOORS>,
+    residues: Vec<Residue, MAX_RESIDUES>,
+    mappings: Vec<Mapping, MAX_MAPPINGS>,
+    modes: Vec<Mode, MAX_MODES>,
+}
+
+impl Setup {
+    fn new() -> Self {
+        Self {
+            codebooks: Vec::new(),
+            nodes: Vec::new(),
+            multiplicands: Vec::new(),
+            floors: Vec::new(),
+            residues: Vec::new(),
+            mappings: Vec::new(),
+            modes: Vec::new(),
+        }
+    }
+
+    // Reads the setup header packet
+    fn parse(&mut self, packet: &[u8], channels: usize) -> Result<(), Error> {
+        let bits = &mut Bits::new(packet.get(7..).ok_or(Error::BadHeader)?);
+        for _ in 0..read(bits, 8)? + 1 {
+            let book = self.read_codebook(bits)?;
+            self.codebooks
+                .push(book)
+                .map_err(|_| Error::SetupTooLarge)?;
+        }
+        // time domain transforms are placeholders
+        for _ in 0..read(bits, 6)? + 1 {
+            if read(bits, 16)? != 0 {
+                return Err(Error::BadHeader);
+            }
+        }
+        for _ in 0..read(bits, 6)? + 1 {
+            let floor = self.read_floor(bits)?;
+            self.floors.push(floor).map_err(|_| Error::SetupTooLarge)?;
+        }
+        for _ in 0..read(bits, 6)? + 1 {
+            let residue = self.read_residue(bits)?;
+            self.residues
+                .push(residue)
+                .map_err(|_| Error::SetupTooLarge)?;
+        }
+        for _ in 0..read(bits, 6)? + 1 {
+            let mapping = self.read_mapping(bits, channels)?;
+            self.mappings
+                .push(mapping)
+                .map_err(|_| Error::SetupTooLarge)?;
+        }
+        for _ in 0..read(bits, 6)? + 1 {
+            let long = read(bits, 1)? == 1;
+            let window = read(bits, 16)?;
+            let transform = read(bits, 16)?;
+            let mapping = read(bits, 8)?;
+            if window != 0 || transform != 0 || mapping as usize >= self.mappings.len() {
+                return Err(Error::BadHeader);
+            }
+            let mode = Mode {
+                long,
+                mapping: mapping as u8,
+            };
+            self.modes.push(mode).map_err(|_| Error::SetupTooLarge)?;
+        }
+        // framing bit
+        match read(bits, 1)? {
+            1 => Ok(()),
+            _ => Err(Error::BadHeader),
+        }
+    }
+
+    fn read_codebook(&mut self, bits: &mut Bits) -> Result<Codebook, Error> {
+        if read(bits, 24)? != 0x564342 {
+            return Err(Error::BadHeader);
+        }
+        let dimensions = read(bits, 16)? as u16;
+        let entries = read(bits, 24)?;
+        if dimensions == 0 {
+            return Err(Error::BadHeader);
+        }
+        if entries > LEAF as u32 {
+            return Err(Error::SetupTooLarge);
+        }
+
+        let root = self.nodes.len();
+        self.nodes.push([0; 2]).map_err(|_| Error::SetupTooLarge)?;
+        let mut tree = Tree::new(root);
+        if read(bits, 1)? == 1 {
+            // ordered, runs of entries with lengths counting up
+            let mut len = read(bits, 5)? + 1;
+            let mut entry = 0;
+            while entry < entries {
+                let run = read(bits, ilog(entries - entry))?;
+                if run > entries - entry || len > 32 {
+                    return Err(Error::BadHeader);
+                }
+                for entry in entry..entry + run {
+                    tree.insert(&mut self.nodes, entry as u16, len)?;
+                }
+                entry += run;
+                len += 1;
+            }
+        } else {
+            let sparse = read(bits, 1)? == 1;
+            for entry in 0..entries {
+                if !sparse || read(bits, 1)? == 1 {
+                    let len = read(bits, 5)? + 1;
+                    tree.insert(&mut self.nodes, entry as u16, len)?;
+                }
+            }
+        }
+        tree.finish(&mut self.nodes);
+
+        let mut book = Codebook {
+            dimensions,
+            root: root as u16,
+            lookup: read(bits, 4)? as u8,
+            ..Codebook::default()
+        };
+        match book.lookup {
+            0 => return Ok(book),
+            1 | 2 => (),
+            _ => return Err(Error::BadHeader),
+        }
+        book.minimum = float_q12(read(bits, 32)?).ok_or(Error::SetupTooLarge)?;
+        book.delta = float_q12(read(bits, 32)?).ok_or(Error::SetupTooLarge)?;
+        let value_bits = read(bits, 4)? + 1;
+        book.sequence = read(bits, 1)? == 1;
+        book.values = match book.lookup {
+            1 => lookup1_values(entries, dimensions),
+            _ => entries * dimensions as u32,
+        };
+        if book.values == 0 {
+            return Err(Error::BadHeader);
+        }
+
+        book.multiplicands = self.multiplicands.len() as u16;
+        let mut largest = 0;
+        for _ in 0..book.values {
+            let multiplicand = read(bits, value_bits)? as u16;
+            largest = largest.max(multiplicand);
+            self.multiplicands
+                .push(multiplicand)
+                .map_err(|_| Error::SetupTooLarge)?;
+        }
+        // every value of a vector has to leave the headroom the residue is decoded with
+        let step =
+            book.minimum.unsigned_abs() as i64 + largest as i64 * book.delta.unsigned_abs() as i64;
+        let steps = if book.sequence { dimensions as i64 } else { 1 };
+        if step.saturating_mul(steps) > MAX_VALUE {
+            return Err(Error::SetupTooLarge);
+        }
+        Ok(book)
+    }
+
+    fn read_floor(&mut self, bits: &mut Bits) -> Result<Floor, Error> {
+        let books = self.codebooks.len();
+        // only floor 1, floor 0 was dropped from libvorbis before its 1.0 release
+        if read(bits, 16)? != 1 {
+            return Err(Error::SetupTooLarge);
+        }
+
+        let mut floor = Floor {
+            multiplier: 1,
+            partitions: read(bits, 5)? as u8,
+            partition_class: [0; FLOOR1_MAX_PARTITIONS],
+            class_dimensions: [0; FLOOR1_MAX_CLASSES],
+            class_subclasses: [0; FLOOR1_MAX_CLASSES],
+            class_masterbook: [0; FLOOR1_MAX_CLASSES],
+            subclass_books: [[0; 8]; FLOOR1_MAX_CLASSES],
+            values: 2,
+            x: [0; FLOOR1_MAX_VALUES],
+            sorted: [0; FLOOR1_MAX_VALUES],
+            low_neighbor: [0; FLOOR1_MAX_VALUES],
+            high_neighbor: [0; FLOOR1_MAX_VALUES],
+        };
+        let mut classes = 0;
+        for class in &mut floor.partition_class[..floor.partitions as usize] {
+            *class = read(bits, 4)? as u8;
+            classes = classes.max(*class as usize + 1);
+        }
+        for class in 0..classes {
+            floor.class_dimensions[class] = read(bits, 3)? as u8 + 1;
+            floor.class_subclasses[class] = read(bits, 2)? as u8;
+            if floor.class_subclasses[class] != 0 {
+                floor.class_masterbook[class] = read(bits, 8)? as u8;
+                if floor.class_masterbook[class] as usize >= books {
+                    return Err(Error::BadHeader);
+                }
+            }
+            let subclasses = 1 << floor.class_subclasses[class];
+            for book in &mut floor.subclass_books[class][..subclasses] {
+                *book = read(bits, 8)? as u8;
+                if *book as usize > books {
+                    return Err(Error::BadHeader);
+                }
+            }
+        }
+
+        floor.multiplier = read(bits, 2)? as u8 + 1;
+        let range_bits = read(bits, 4)?;
+        floor.x[1] = 1 << range_bits;
+        for partition in 0..floor.partitions as usize {
+            let class = floor.partition_class[partition] as usize;
+            for _ in 0..floor.class_dimensions[class] {
+                let x = floor.x.get_mut(floor.values).ok_or(Error::BadHeader)?;
+                *x = read(bits, range_bits)? as u16;
+                floor.values += 1;
+            }
+        }
+
+        let values = floor.values;
+        let x = floor.x;
+        for (i, sorted) in floor.sorted[..values].iter_mut().enumerate() {
+            *sorted = i as u8;
+        }
+        floor.sorted[..values].sort_unstable_by_key(|i| x[*i as usize]);
+        if floor.sorted[..values]
+            .windows(2)
+            .any(|pair| x[pair[0] as usize] == x[pair[1] as usize])
+        {
+            return Err(Error::BadHeader);
+        }
+        for i in 2..values {
+            let (mut low, mut high) = (0, 1);
+            for j in 0..i {
+                if x[j] < x[i] && x[j] > x[low] {
+                    low = j;
+                }
+                if x[j] > x[i] && x[j] < x[high] {
+                    high = j;
+                }
+            }
+            floor.low_neighbor[i] = low as u8;
+            floor.high_neighbor[i] = high as u8;
+        }
+        Ok(floor)
+    }
+
+    fn read_residue(&mut self, bits: &mut Bits) -> Result<Residue, Error> {
+        let kind = read(bits, 16)?;
+        if kind > 2 {
+            return Err(Error::BadHeader);
+        }
+        let mut residue = Residue {
+            kind: kind as u8,
+            begin: read(bits, 24)?,
+            end: read(bits, 24)?,
+            partition_size: read(bits, 24)? + 1,
+            classifications: read(bits, 6)? as u8 + 1,
+            classbook: read(bits, 8)? as u8,
+            ..Residue::default()
+        };
+        let classifications = residue.classifications as usize;
+        let partitions = residue.end.saturating_sub(residue.begin) / residue.partition_size;
+        if classifications > MAX_CLASSIFICATIONS || partitions as usize > MAX_PARTITIONS {
+            return Err(Error::SetupTooLarge);
+        }
+        if residue.classbook as usize >= self.codebooks.len() {
+            return Err(Error::BadHeader);
+        }
+
+        let mut cascade = [0u8; MAX_CLASSIFICATIONS];
+        for passes in &mut cascade[..classifications] {
+            let low = read(bits, 3)?;
+            let high = if read(bits, 1)? == 1 {
+                read(bits, 5)?
+            } else {
+                0
+            };
+            *passes = (high << 3 | low) as u8;
+        }
+        for (passes, class_books) in cascade.iter().zip(&mut residue.books) {
+            for (pass, book) in class_books.iter_mut().enumerate() {
+                if passes & (1 << pass) != 0 {
+                    let number = read(bits, 8)? as usize;
+                    // the books of the passes have to carry vectors
+                    if self
+                        .codebooks
+                        .get(number)
+                        .is_none_or(|book| book.lookup == 0)
+                    {
+                        return Err(Error::BadHeader);
+                    }
+                    *book = number as u8 + 1;
+                }
+            }
+        }
+        Ok(residue)
+    }
+
+    fn read_mapping(&mut self, bits: &mut Bits, channels: usize) -> Result<Mapping, Error> {
+        if read(bits, 16)? != 0 {
+            return Err(Error::BadHeader);
+        }
+        let mut mapping = Mapping {
+            submaps: 1,
+            ..Mapping::default()
+        };
+        if read(bits, 1)? == 1 {
+            mapping.submaps = read(bits, 4)? as usize + 1;
+        }
+        if read(bits, 1)? == 1 {
+            mapping.coupling_steps = read(bits, 8)? as usize + 1;
+        }
+        if mapping.submaps > MAX_SUBMAPS || mapping.coupling_steps > MAX_COUPLING_STEPS {
+            return Err(Error::SetupTooLarge);
+        }
+        let channel_bits = ilog(channels as u32 - 1);
+        for step in 0..mapping.coupling_steps {
+            let magnitude = read(bits, channel_bits)? as usize;
+            let angle = read(bits, channel_bits)? as usize;
+            if magnitude == angle || magnitude >= channels || angle >= channels {
+                return Err(Error::BadHeader);
+            }
+            mapping.magnitude[step] = magnitude as u8;
+            mapping.angle[step] = angle as u8;
+        }
+        if read(bits, 2)? != 0 {
+            return Err(Error::BadHeader);
+        }
+        if mapping.submaps > 1 {
+            for mux in &mut mapping.mux[..channels] {
+                *mux = read(bits, 4)? as u8;
+                if *mux as usize >= mapping.submaps {
+                    return Err(Error::BadHeader);
+                }
+            }
+        }
+        for submap in 0..mapping.submaps {
+            // unused time configuration
+            read(bits, 8)?;
+            let floor = read(bits, 8)? as usize;
+            let residue = read(bits, 8)? as usize;
+            if floor >= self.floors.len() || residue >= self.residues.len() {
+                return Err(Error::BadHeader);
+            }
+            mapping.submap_floor[submap] = floor as u8;
+            mapping.submap_residue[submap] = residue as u8;
+        }
+        Ok(mapping)
+    }
+
+    // Decodes the entry number of one codeword, None at the end of the packet or on a codeword
+    // the book does not have
+    fn decode(&self, book: &Codebook, bits: &mut Bits) -> Option<u32> {
+        let mut node = book.root as usize;
+        loop {
+            let child = self.nodes[node][bits.bit()?];
+            if child & LEAF != 0 {
+                return Some((child & !LEAF) as u32);
+            }
+            if child == 0 {
+                return None;
+            }
+            node = child as usize;
+        }
+    }
+
+    // Decodes a codeword and hands the values of its vector to `add` with their dimension, in Q12
+    fn decode_vector(
+        &self,
+        book: &Codebook,
+        bits: &mut Bits,
+        mut add: impl FnMut(usize, i32),
+    ) -> Option<()> {
+        let entry = self.decode(book, bits)?;
+        let multiplicands = &self.multiplicands[book.multiplicands as usize..];
+        let mut index = entry;
+        let mut last = 0;
+        for dimension in 0..book.dimensions as usize {
+            let multiplicand = if book.lookup == 1 {
+                let multiplicand = multiplicands[(index % book.values) as usize];
+                index /= book.values;
+                multiplicand
+            } else {
+                multiplicands[entry as usize * book.dimensions as usize + dimension]
+            };
+            let value = multiplicand as i32 * book.delta + book.minimum + last;
+            if book.sequence {
+                last = value;
+            }
+            add(dimension, value);
+        }
+        Some(())
+    }
+}
+
+// Builds a huffman tree from codeword lengths given in entry order, each entry takes the lowest
+// free codeword of its length as the spec assigns them
+struct Tree {
+    root: usize,
+    // the lowest free codeword of each length, most significant bit first, 0 when there is none
+    available: [u32; 33],
+    used: u32,
+    last: u16,
+}
+
+impl Tree {
+    fn new(root: usize) -> Self {
+        Self {
+            root,
+            available: [0; 33],
+            used: 0,
+            last: 0,
+        }
+    }
+
+    fn insert(
+        &mut self,
+        nodes: &mut Vec<[u16; 2], MAX_NODES>,
+        entry: u16,
+        len: u32,
+    ) -> Result<(), Error> {
+        let len = len as usize;
+        let codeword = if self.used == 0 {
+            // the first entry is all zeros, which leaves the codewords branching off it free
+            for depth in 1..=len {
+                self.available[depth] = 1 << (32 - depth);
+            }
+            0
+        } else {
+            let free = (1..=len)
+                .rev()
+                .find(|depth| self.available[*depth] != 0)
+                .ok_or(Error::BadHeader)?;
+            let codeword = self.available[free];
+            self.available[free] = 0;
+            for depth in free + 1..=len {
+                self.available[depth] = codeword + (1 << (32 - depth));
+            }
+            codeword
+        };
+        self.used += 1;
+        self.last = entry;
+
+        let mut node = self.root;
+        for depth in 1..=len {
+            let bit = (codeword >> (32 - depth)) as usize & 1;
+            let child = nodes[node][bit];
+            if depth == len {
+                nodes[node][bit] = LEAF | entry;
+            } else if child == 0 {
+                let next = nodes.len();
+                nodes.push([0; 2]).map_err(|_| Error::SetupTooLarge)?;
+                nodes[node][bit] = next as u16;
+                node = next;
+            } else {
+                node = child as usize;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self, nodes: &mut [[u16; 2]]) {
+        // a book with a single entry decodes it from one bit of either value
+        if self.used == 1 {
+            nodes[self.root] = [LEAF | self.last; 2];
+        }
+    }
+}
+
+// Decodes the floor 1 amplitudes of a channel. False when the channel is unused, which is also
+// what running out of packet means here
+fn decode_floor(
+    setup: &Setup,
+    floor: &Floor,
+    bits: &mut Bits,
+    y: &mut [u16; FLOOR1_MAX_VALUES],
+) -> bool {
+    let mut decode = || {
+        if bits.bit()? == 0 {
+            return None;
+        }
+        let range_bits = ilog(floor.range() as u32 - 1);
+        y[0] = bits.read(range_bits)? as u16;
+        y[1] = bits.read(range_bits)? as u16;
+
+        let mut offset = 2;
+        for &class in &floor.partition_class[..floor.partitions as usize] {
+            let class = class as usize;
+            let subclass_bits = floor.class_subclasses[class];
+            let mut cval = 0;
+            if subclass_bits > 0 {
+                let book = &setup.codebooks[floor.class_masterbook[class] as usize];
+                cval = setup.decode(book, bits)?;
+            }
+            for _ in 0..floor.class_dimensions[class] {
+                let subclass = cval & ((1 << subclass_bits) - 1);
+                cval >>= subclass_bits;
+                y[offset] = match floor.subclass_books[class][subclass as usize] {
+                    0 => 0,
+                    book => setup.decode(&setup.codebooks[book as usize - 1], bits)? as u16,
+                };
+                offset += 1;
+            }
+        }
+        Some(())
+    };
+    decode().is_some()
+}
+
+// Point on the line from (x0, y0) to (x1, y1) at x, in the integer steps of the spec
+fn render_point(x0: i32, y0: i32, x1: i32, y1: i32, x: i32) -> i32 {
+    let dy = y1 - y0;
+    let offset = dy.abs() * (x - x0) / (x1 - x0);
+    if dy < 0 { y0 - offset } else { y0 + offset }
+}
+
+// Turns the amplitudes into the floor curve and multiplies the residue in `spectrum` by it,
+// which leaves the spectrum in Q24
+fn apply_floor(floor: &Floor, y: &[u16; FLOOR1_MAX_VALUES], spectrum: &mut [i32]) {
+    let range = floor.range();
+    let mut final_y = [0i32; FLOOR1_MAX_VALUES];
+    let mut used = [false; FLOOR1_MAX_VALUES];
+    final_y[0] = y[0] as i32;
+    final_y[1] = y[1] as i32;
+    used[0] = true;
+    used[1] = true;
+
+    for i in 2..floor.values {
+        let low = floor.low_neighbor[i] as usize;
+        let high = floor.high_neighbor[i] as usize;
+        let predicted = render_point(
+            floor.x[low] as i32,
+            final_y[low],
+            floor.x[high] as i32,
+            final_y[high],
+            floor.x[i] as i32,
+        );
+        let value = y[i] as i32;
+        if value == 0 {
+            final_y[i] = predicted;
+            continue;
+        }
+        used[low] = true;
+        used[high] = true;
+        used[i] = true;
+        let high_room = range - predicted;
+        let low_room = predicted;
+        final_y[i] = if value >= 2 * high_room.min(low_room) {
+            if high_room > low_room {
+                value - low_room + predicted
+            } else {
+                predicted - value + high_room - 1
+            }
+        } else if value % 2 == 1 {
+            predicted - (value + 1) / 2
+        } else {
+            predicted + value / 2
+        };
+    }
+
+    let multiplier = floor.multiplier as i32;
+    let scaled = |i: usize| final_y[i].clamp(0, range - 1) * multiplier;
+    let (mut lx, mut ly) = (0, scaled(0));
+    for &i in &floor.sorted[1..floor.values] {
+        let i = i as usize;
+        if used[i] {
+            let (hx, hy) = (floor.x[i] as i32, scaled(i));
+            render_line(lx, ly, hx, hy, spectrum);
+            (lx, ly) = (hx, hy);
+        }
+    }
+    render_line(lx, ly, spectrum.len() as i32, ly, spectrum);
+}
+
+// Multiplies `spectrum` from x0 up to x1 by the floor on the line from y0 to y1
+fn render_line(x0: i32, y0: i32, x1: i32, y1: i32, spectrum: &mut [i32]) {
+    if x1 <= x0 {
+        return;
+    }
+    let dy = y1 - y0;
+    let adx = x1 - x0;
+    let base = dy / adx;
+    let step = if dy < 0 { base - 1 } else { base + 1 };
+    let ady = dy.abs() - base.abs() * adx;
+    let mut y = y0;
+    let mut err = 0;
+    for x in x0..x1.min(spectrum.len() as i32) {
+        if x > x0 {
+            err += ady;
+            if err >= adx {
+                err -= adx;
+                y += step;
+            } else {
+                y += base;
+            }
+        }
+        let sample = &mut spectrum[x as usize];
+        let scaled = (*sample as i64 * FLOOR1_INVERSE_DB[y as usize] as i64) >> (31 + Q12 - 24);
+        *sample = scaled.clamp(-MAX_VALUE, MAX_VALUE) as i32;
+    }
+}
+
+// The spectra a residue is decoded into. Residue type 2 decodes a single vector with the
+// channels interleaved
+struct Vectors<'s> {
+    spectra: &'s mut [[i32; MAX_HALF]; MAX_CHANNELS],
+    channels: [usize; MAX_CHANNELS],
+    count: usize,
+    interleaved: bool,
+}
+
+impl Vectors<'_> {
+    fn add(&mut self, vector: usize, index: usize, value: i32) {
+        let (channel, index) = match (self.interleaved, self.count) {
+            (true, 2) => (self.channels[index & 1], index >> 1),
+            (true, _) => (self.channels[0], index),
+            (false, _) => (self.channels[vector], index),
+        };
+        if let Some(sample) = self.spectra[channel].get_mut(index) {
+            *sample = sample.wrapping_add(value);
+        }
+    }
+}
+
+// Adds a residue to the vectors flagged in `decode`, which are `size` values long. None where
+// the packet ends, the rest of the residue stays at zero
+fn decode_residue(
+    setup: &Setup,
+    residue: &Residue,
+    bits: &mut Bits,
+    size: usize,
+    vectors: &mut Vectors,
+    decode: &[bool],
+    classifications: &mut [[u8; MAX_PARTITIONS]; MAX_CHANNELS],
+) -> Option<()> {
+    let begin = (residue.begin as usize).min(size);
+    let end = (residue.end as usize).min(size);
+    let partition_size = residue.partition_size as usize;
+    let partitions = (end - begin) / partition_size;
+    let classbook = &setup.codebooks[residue.classbook as usize];
+    let classes = residue.classifications as u32;
+    let per_word = classbook.dimensions as usize;
+    let decoded = || (0..decode.len()).filter(|vector| decode[*vector]);
+
+    for pass in 0..8 {
+        let mut partition = 0;
+        while partition < partitions {
+            if pass == 0 {
+                for vector in decoded() {
+                    let mut word = setup.decode(classbook, bits)?;
+                    for i in (0..per_word).rev() {
+                        if let Some(class) = classifications[vector].get_mut(partition + i) {
+                            *class = (word % classes) as u8;
+                        }
+                        word /= classes;
+                    }
+                }
+            }
+            for _ in 0..per_word {
+                if partition == partitions {
+                    break;
+                }
+                let offset = begin + partition * partition_size;
+                for vector in decoded() {
+                    let class = classifications[vector][partition] as usize;
+                    let book = match residue.books[class][pass] {
+                        0 => continue,
+                        book => &setup.codebooks[book as usize - 1],
+                    };
+                    let dimensions = book.dimensions as usize;
+                    if residue.kind == 0 {
+                        // the values of a codeword are spread over the partition
+                        let step = partition_size / dimensions;
+                        for i in 0..step {
+                            setup.decode_vector(book, bits, |d, value| {
+                                vectors.add(vector, offset + i + d * step, value)
+                            })?;
+                        }
+                    } else {
+                        for i in (0..partition_size).step_by(dimensions) {
+                            setup.decode_vector(book, bits, |d, value| {
+                                vectors.add(vector, offset + i + d, value)
+                            })?;
+                        }
+                    }
+                }
+                partition += 1;
+            }
+        }
+    }
+    Some(())
+}
+
+// sin(pi * j / (4 * MAX_HALF)) in Q30 up to a quarter turn, every twiddle factor of the IMDCT
+// is a multiple of that angle
+static SINE: [i32; 2 * MAX_HALF + 1] = {
+    let mut table = [0; 2 * MAX_HALF + 1];
+    let mut j = 0;
+    while j < table.len() {
+        table[j] = sin_pi_q30((j << 30) as i64 / (4 * MAX_HALF) as i64) as i32;
+        j += 1;
+    }
+    table
+};
+
+// The rising slope of the vorbis window, sin(pi / 2 * sin(pi * j / (4 * MAX_HALF))^2) in Q30.
+// A slope of `len` samples takes every (MAX_HALF / len)th odd value
+static WINDOW: [i32; 2 * MAX_HALF] = {
+    let mut table = [0; 2 * MAX_HALF];
+    let mut j = 0;
+    while j < table.len() {
+        let sine = sin_pi_q30((j << 30) as i64 / (4 * MAX_HALF) as i64);
+        table[j] = sin_pi_q30(((sine * sine) >> 30) / 2) as i32;
+        j += 1;
+    }
+    table
+};
+
+// Rising slope of a window `len` samples long at `i`
+fn window(i: usize, len: usize) -> i32 {
+    WINDOW[(2 * i + 1) * (MAX_HALF / len)]
+}
+
+// cos and sin of pi * j / (4 * n) in Q30, for angles up to a half turn
+fn cos_sin(j: usize, n: usize) -> (i32, i32) {
+    let quarter = 2 * MAX_HALF;
+    let k = j * (MAX_HALF / n);
+    if k <= quarter {
+        (SINE[quarter - k], SINE[k])
+    } else {
+        (-SINE[k - quarter], SINE[2 * quarter - k])
+    }
+}
+
+fn mul_q30(a: i32, b: i32) -> i32 {
+    ((a as i64 * b as i64) >> 30) as i32
+}
+
+// (a + bi) * (c - di), a turn back by the angle of (c, d)
+fn rotate(a: i32, b: i32, c: i32, d: i32) -> (i32, i32) {
+    (
+        mul_q30(a, c).wrapping_add(mul_q30(b, d)),
+        mul_q30(b, c).wrapping_sub(mul_q30(a, d)),
+    )
+}
+
+// In place DCT-IV, x[k] = sum of x[j] * cos(pi / n * (j + 1/2) * (k + 1/2)), through an n/2
+// point complex FFT. The IMDCT of vorbis is this transform unfolded to twice the length
+fn dct_iv(x: &mut [i32]) {
+    let n = x.len();
+    let m = n / 2;
+
+    // pair the even inputs with the odd ones from the top, turned back by (4j + 1) / 4n of a half
+    // turn. Slots 2j, 2j+1 and n-2-2j, n-1-2j are only read by j and its mirror m-1-j
+    for j in 0..m / 2 {
+        let mirror = m - 1 - j;
+        let (a, b) = (x[2 * j], x[n - 1 - 2 * j]);
+        let (c, d) = (x[2 * mirror], x[n - 1 - 2 * mirror]);
+        let (cos, sin) = cos_sin(4 * j + 1, n);
+        (x[2 * j], x[2 * j + 1]) = rotate(a, b, cos, sin);
+        let (cos, sin) = cos_sin(4 * mirror + 1, n);
+        (x[2 * mirror], x[2 * mirror + 1]) = rotate(c, d, cos, sin);
+    }
+
+    // radix 2 decimation in time from bit reversed order
+    let bits = m.trailing_zeros();
+    for i in 0..m {
+        let reversed = i.reverse_bits() >> (usize::BITS - bits);
+        if reversed > i {
+            x.swap(2 * i, 2 * reversed);
+            x.swap(2 * i + 1, 2 * reversed + 1);
+        }
+    }
+    let mut len = 2;
+    while len <= m {
+        let half = len / 2;
+        for k in 0..half {
+            // a turn of 2k / len is 16km / len steps of pi / 4n
+            let (cos, sin) = cos_sin(16 * k * (m / len), n);
+            for start in (0..m).step_by(len) {
+                let (a, b) = (2 * (start + k), 2 * (start + k + half));
+                let (re, im) = rotate(x[b], x[b + 1], cos, sin);
+                let (ar, ai) = (x[a], x[a + 1]);
+                x[a] = ar.wrapping_add(re);
+                x[a + 1] = ai.wrapping_add(im);
+                x[b] = ar.wrapping_sub(re);
+                x[b + 1] = ai.wrapping_sub(im);
+            }
+        }
+        len *= 2;
+    }
+
+    // turn back by k / n of a half turn, the real parts are the even outputs and the negated
+    // imaginary parts the odd ones from the top
+    for k in 0..m / 2 {
+        let mirror = m - 1 - k;
+        let (cos, sin) = cos_sin(4 * k, n);
+        let (a, b) = rotate(x[2 * k], x[2 * k + 1], cos, sin);
+        let (cos, sin) = cos_sin(4 * mirror, n);
+        let (c, d) = rotate(x[2 * mirror], x[2 * mirror + 1], cos, sin);
+        x[2 * k] = a;
+        x[n - 1 - 2 * k] = b.wrapping_neg();
+        x[2 * mirror] = c;
+        x[n - 1 - 2 * mirror] = d.wrapping_neg();
+    }
+}
+
+// Sample `i` of the IMDCT of a block twice as long as `half`, which holds its DCT-IV
+fn unfold(half: &[i32], i: usize) -> i32 {
+    let quarter = half.len() / 2;
+    if i < quarter {
+        half[i + quarter]
+    } else if i < 3 * quarter {
+        half[3 * quarter - 1 - i].wrapping_neg()
+    } else {
+        half[i - 3 * quarter].wrapping_neg()
+    }
+}
+
+// The block of the last packet and the end of the one before, which overlap
+struct Synthesis {
+    // residue, then spectrum and then DCT-IV of each channel
+    block: [[i32; MAX_HALF]; MAX_CHANNELS],
+    size: usize,
+    // first sample of the block that is played, where its window starts rising
+    left_start: usize,
+    // the falling part of the window of the block, saved before the next packet is decoded
+    right: Option<(usize, usize)>,
+    previous: [[i32; MAX_HALF]; MAX_CHANNELS],
+    previous_len: usize,
+    classifications: [[u8; MAX_PARTITIONS]; MAX_CHANNELS],
+}
+
+impl Synthesis {
+    fn new() -> Self {
+        Self {
+            block: [[0; MAX_HALF]; MAX_CHANNELS],
+            size: 0,
+            left_start: 0,
+            right: None,
+            previous: [[0; MAX_HALF]; MAX_CHANNELS],
+            previous_len: 0,
+            classifications: [[0; MAX_PARTITIONS]; MAX_CHANNELS],
+        }
+    }
+
+    // Forgets the last block, the next one only starts the overlap again
+    fn reset(&mut self) {
+        self.right = None;
+        self.previous_len = 0;
+    }
+
+    // Decodes an audio packet and returns how many samples from `left_start` on are complete.
+    // None for a packet that is empty, a header or has no valid mode
+    fn decode(
+        &mut self,
+        setup: &Setup,
+        blocksizes: [usize; 2],
+        channels: usize,
+        packet: &[u8],
+    ) -> Option<usize> {
+        let bits = &mut Bits::new(packet);
+        if bits.bit()? != 0 {
+            return None;
+        }
+        let mode_bits = ilog(setup.modes.len() as u32 - 1);
+        let mode = setup.modes.get(bits.read(mode_bits)? as usize)?;
+        let n = blocksizes[mode.long as usize];
+        let (previous_long, next_long) = match mode.long {
+            true => (bits.bit()? == 1, bits.bit()? == 1),
+            false => (false, false),
+        };
+
+        // the falling slope of the last block is all that is still needed of it
+        self.previous_len = 0;
+        if let Some((start, end)) = self.right.take() {
+            for (previous, block) in self.previous.iter_mut().zip(&self.block).take(channels) {
+                let block = &block[..self.size / 2];
+                for (i, sample) in previous[..end - start].iter_mut().enumerate() {
+                    *sample = unfold(block, start + i);
+                }
+            }
+            self.previous_len = end - start;
+        }
+        self.size = n;
+
+        let mapping = &setup.mappings[mode.mapping as usize];
+        let submap = |channel: usize| match mapping.submaps {
+            1 => 0,
+            _ => mapping.mux[channel] as usize,
+        };
+        let mut y = [[0u16; FLOOR1_MAX_VALUES]; MAX_CHANNELS];
+        let mut used = [false; MAX_CHANNELS];
+        for channel in 0..channels {
+            let floor = &setup.floors[mapping.submap_floor[submap(channel)] as usize];
+            used[channel] = decode_floor(setup, floor, bits, &mut y[channel]);
+        }
+        // a coupled pair needs the residue of both channels when either has a floor
+        let mut has_residue = used;
+        for step in 0..mapping.coupling_steps {
+            let (magnitude, angle) = (mapping.magnitude[step], mapping.angle[step]);
+            if used[magnitude as usize] || used[angle as usize] {
+                has_residue[magnitude as usize] = true;
+                has_residue[angle as usize] = true;
+            }
+        }
+
+        for block in &mut self.block[..channels] {
+            block[..n / 2].fill(0);
+        }
+        for submap_number in 0..mapping.submaps {
+            let residue = &setup.residues[mapping.submap_residue[submap_number] as usize];
+            let mut vectors = Vectors {
+                spectra: &mut self.block,
+                channels: [0; MAX_CHANNELS],
+                count: 0,
+                interleaved: residue.kind == 2,
+            };
+            let mut decode = [false; MAX_CHANNELS];
+            for channel in (0..channels).filter(|channel| submap(*channel) == submap_number) {
+                vectors.channels[vectors.count] = channel;
+                decode[vectors.count] = has_residue[channel];
+                vectors.count += 1;
+            }
+            let (size, count) = if vectors.interleaved {
+                // a single vector, decoded when any of the channels is
+                decode[0] = decode[..vectors.count].contains(&true);
+                (n / 2 * vectors.count, 1)
+            } else {
+                (n / 2, vectors.count)
+            };
+            let classifications = &mut self.classifications;
+            // running out of packet leaves the rest of the residue at zero
+            let _ = decode_residue(
+                setup,
+                residue,
+                bits,
+                size,
+                &mut vectors,
+                &decode[..count],
+                classifications,
+            );
+        }
+
+        // inverse coupling, in reverse order
+        for step in (0..mapping.coupling_steps).rev() {
+            let (magnitude, angle) = (mapping.magnitude[step], mapping.angle[step]);
+            for i in 0..n / 2 {
+                let m = self.block[magnitude as usize][i];
+                let a = self.block[angle as usize][i];
+                let (m, a) = match (m > 0, a > 0) {
+                    (true, true) => (m, m.wrapping_sub(a)),
+                    (true, false) => (m.wrapping_add(a), m),
+                    (false, true) => (m, m.wrapping_add(a)),
+                    (false, false) => (m.wrapping_sub(a), m),
+                };
+                self.block[magnitude as usize][i] = m;
+                self.block[angle as usize][i] = a;
+            }
+        }
+
+        for channel in 0..channels {
+            let spectrum = &mut self.block[channel][..n / 2];
+            if used[channel] {
+                let floor = &setup.floors[mapping.submap_floor[submap(channel)] as usize];
+                apply_floor(floor, &y[channel], spectrum);
+                dct_iv(spectrum);
+            } else {
+                spectrum.fill(0);
+            }
+        }
+
+        // the slopes are short next to a short block
+        let short = blocksizes[0];
+        let (left_start, left_len) = match mode.long && !previous_long {
+            true => ((n - short) / 4, short / 2),
+            false => (0, n / 2),
+        };
+        let (right_start, right_end) = match mode.long && !next_long {
+            true => ((3 * n - short) / 4, (3 * n + short) / 4),
+            false => (n / 2, n),
+        };
+        self.left_start = left_start;
+        self.right = Some((right_start, right_end));
+        // the first block, or one after a lost packet, only starts the overlap
+        if self.previous_len != left_len {
+            self.previous_len = 0;
+            return Some(0);
+        }
+        Some(right_start - left_start)
+    }
+
+    // Sample `i` of the block as 16 bits, overlapped with the end of the block before
+    fn sample(&self, channel: usize, i: usize) -> i16 {
+        let mut value = unfold(&self.block[channel][..self.size / 2], i);
+        let overlap = i - self.left_start;
+        let len = self.previous_len;
+        if overlap < len {
+            let previous = self.previous[channel][overlap];
+            value = mul_q30(value, window(overlap, len))
+                .wrapping_add(mul_q30(previous, window(len - 1 - overlap, len)));
+        }
+        // Q24 to 16 bits, rounded
+        ((value >> 8).saturating_add(1) >> 1).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }
+}
+
+pub struct VorbisDecoder<'a> {
+    ogg: OggReader<'a>,
+    setup: Setup,
+    synthesis: Synthesis,
+    blocksizes: [usize; 2],
+    // the samples of the last block still to be handed out
+    block_pos: usize,
+    block_end: usize,
+    finished: bool,
+    // samples per channel handed out so far
+    position: u64,
+    total_samples: Option<u64>,
+    // file offset of the first audio page
+    first_page: u32,
+    pub replay_gain: ReplayGain,
+    pub sample_rate: u32,
+    pub num_channels: u16,
+}
+
+impl<'a> VorbisDecoder<'a> {
+    // Hands the file back with the error when it cannot be played, so it can be closed
+    pub async fn new(file: SdFile<'a>) -> Result<Self, (Error, SdFile<'a>)> {
+        let mut decoder = Self {
+            ogg: OggReader::new(file),
+            setup: Setup::new(),
+            synthesis: Synthesis::new(),
+            blocksizes: [0; 2],
+            block_pos: 0,
+            block_end: 0,
+            finished: false,
+            position: 0,
+            total_samples: None,
+            first_page: 0,
+            replay_gain: ReplayGain::default(),
+            sample_rate: 0,
+            num_channels: 0,
+        };
+        match decoder.read_headers().await {
+            Ok(()) => Ok(decoder),
+            Err(e) => Err((e, decoder.destroy())),
+        }
+    }
+
+    // Reads the three header packets, which leaves the reader at the first audio packet
+    async fn read_headers(&mut self) -> Result<(), Error> {
+        let ident = self.ogg.next_packet().await?.ok_or(Error::BadHeader)?;
+        if ident.len() < 30 || &ident[..7] != b"\x01vorbis" || ident[7..11] != [0; 4] {
+            return Err(Error::BadHeader);
+        }
+        let channels = ident[11];
+        let sample_rate = u32::from_le_bytes(ident[12..16].try_into().unwrap());
+        let blocksizes = [1 << (ident[28] & 0xF), 1 << (ident[28] >> 4)];
+        if channels == 0
+            || sample_rate == 0
+            || !(64..=8192).contains(&blocksizes[0])
+            || !(blocksizes[0]..=8192).contains(&blocksizes[1])
+        {
+            return Err(Error::BadHeader);
+        }
+        if channels as usize > MAX_CHANNELS || blocksizes[1] > MAX_BLOCKSIZE {
+            return Err(Error::Unsupported {
+                sample_rate,
+                channels,
+                blocksize: blocksizes[1] as u16,
+            });
+        }
+
+        // the comment header is dropped when cover art makes it too large for the packet
+        // buffer, the setup header comes next either way
+        let mut replay_gain = ReplayGain::default();
+        let setup = loop {
+            let packet = self.ogg.next_packet().await?.ok_or(Error::BadHeader)?;
+            match packet.get(..7) {
+                Some(b"\x03vorbis") => parse_vorbis_comments(&packet[7..], &mut replay_gain),
+                Some(b"\x05vorbis") => break packet,
+                _ => return Err(Error::BadHeader),
+            }
+        };
+        self.setup.parse(setup, channels as usize)?;
+
+        self.first_page = self.ogg.position();
+        self.total_samples = self.ogg.last_granule_position().await?;
+        info!(
+            "[VORBIS] {}hz, {} channels, blocks of {} and {}, {} samples",
+            sample_rate, channels, blocksizes[0], blocksizes[1], self.total_samples
+        );
+        self.blocksizes = blocksizes;
+        self.replay_gain = replay_gain;
+        self.sample_rate = sample_rate;
+        self.num_channels = channels as u16;
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    // Frames left to play, when the last page tells the length of the stream
+    pub fn remaining_frames(&self) -> Option<u64> {
+        self.total_samples
+            .map(|total| total.saturating_sub(self.position))
+    }
+
+    // Ends the track early, when its file cannot be read
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+
+    pub fn destroy(self) -> SdFile<'a> {
+        self.ogg.destroy()
+    }
+
+    // Moves playback close to `sample`. The file offset is interpolated over the stream, the
+    // position of the first page read after it corrects the estimate
+    pub fn seek(&mut self, sample: u64) -> Result<(), Error> {
+        // without the length of the stream there is nothing to interpolate over
+        let Some(total) = self.total_samples.filter(|total| *total > 0) else {
+            return Ok(());
+        };
+        if sample >= total {
+            self.position = total;
+            self.finished = true;
+            return Ok(());
+        }
+
+        let stream_len = self.ogg.file_len().saturating_sub(self.first_page) as u64;
+        let offset = stream_len * sample / total;
+        self.ogg.seek(self.first_page + offset as u32)?;
+        self.synthesis.reset();
+        self.block_pos = 0;
+        self.block_end = 0;
+        self.finished = false;
+        self.position = sample;
+        Ok(())
+    }
+
+    // Fills `buf` with 16bit little endian pcm, as if it was read from a wav file.
+    // Pads with silence once the stream ends and returns the length before the padding
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let frame_len = 2 * self.num_channels as usize;
+        let mut written = 0;
+        while written + frame_len <= buf.len() {
+            if self.block_pos == self.block_end && (self.finished || !self.decode_block().await?) {
+                self.finished = true;
+                buf[written..].fill(0);
+                return Ok(written);
+            }
+
+            let frame = &mut buf[written..written + frame_len];
+            for (channel, sample) in frame.chunks_exact_mut(2).enumerate() {
+                let value = self.synthesis.sample(channel, self.block_pos);
+                sample.copy_from_slice(&value.to_le_bytes());
+            }
+            self.block_pos += 1;
+            self.position += 1;
+            written += frame_len;
+        }
+        buf[written..].fill(0);
+        Ok(written)
+    }
+
+    // Decodes packets until one completes samples. Returns false at the end of the stream
+    async fn decode_block(&mut self) -> Result<bool, Error> {
+        loop {
+            let Some(packet) = self.ogg.next_packet().await? else {
+                return Ok(false);
+            };
+            let channels = self.num_channels as usize;
+            let Some(len) = self
+                .synthesis
+                .decode(&self.setup, self.blocksizes, channels, packet)
+            else {
+                continue;
+            };
+
+            // a page tells the position at the end of its last packet, on the last page that
+            // cuts the final block short
+            let mut len = len as u64;
+            if let Some(end) = self.ogg.granule_position() {
+                if self.ogg.is_last_page() {
+                    len = len.min(end.saturating_sub(self.position));
+                    self.finished = true;
+                } else {
+                    self.position = end.saturating_sub(len);
+                }
+            }
+            self.block_pos = self.synthesis.left_start;
+            self.block_end = self.block_pos + len as usize;
+            if len > 0 {
+                return Ok(true);
+            }
+            if self.finished {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+// The floor 1 amplitudes from -140dB up to 0dB, in Q31
+#[rustfmt::skip]
+static FLOOR1_INVERSE_DB: [u32; 256] = [
+    229, 244, 259, 276, 294, 313, 334, 355,
+    378, 403, 429, 457, 487, 518, 552, 588,
+    626, 667, 710, 756, 806, 858, 914, 973,
+    1036, 1104, 1175, 1252, 1333, 1420, 1512, 1610,
+    1715, 1826, 1945, 2072, 2206, 2350, 2502, 2665,
+    2838, 3023, 3219, 3428, 3651, 3888, 4141, 4410,
+    4696, 5002, 5327, 5673, 6042, 6434, 6852, 7298,
+    7772, 8277, 8815, 9388, 9998, 10647, 11339, 12076,
+    12861, 13697, 14587, 15535, 16544, 17619, 18764, 19984,
+    21283, 22666, 24139, 25707, 27378, 29157, 31052, 33070,
+    35219, 37507, 39945, 42541, 45305, 48249, 51385, 54724,
+    58281, 62068, 66101, 70397, 74972, 79844, 85033, 90559,
+    96444, 102711, 109386, 116494, 124065, 132127, 140714, 149858,
+    159597, 169968, 181014, 192777, 205305, 218646, 232855, 247988,
+    264103, 281266, 299544, 319011, 339742, 361820, 385333, 410374,
+    437043, 465444, 495691, 527904, 562210, 598746, 637656, 679094,
+    723226, 770225, 820278, 873585, 930355, 990815, 1055204, 1123777,
+    1196806, 1274581, 1357411, 1445623, 1539568, 1639617, 1746169, 1859645,
+    1980495, 2109199, 2246266, 2392241, 2547703, 2713267, 2889590, 3077372,
+    3277357, 3490338, 3717160, 3958722, 4215982, 4489960, 4781743, 5092488,
+    5423426, 5775871, 6151219, 6550960, 6976679, 7430063, 7912910, 8427135,
+    8974778, 9558009, 10179143, 10840641, 11545127, 12295394, 13094418, 13945367,
+    14851616, 15816757, 16844619, 17939278, 19105073, 20346628, 21668866, 23077031,
+    24576707, 26173840, 27874763, 29686223, 31615400, 33669947, 35858010, 38188266,
+    40669954, 43312918, 46127635, 49125267, 52317705, 55717604, 59338448, 63194594,
+    67301334, 71674955, 76332796, 81293331, 86576231, 92202442, 98194276, 104575492,
+    111371397, 118608939, 126316814, 134525593, 143267823, 152578174, 162493564, 173053310,
+    184299289, 196276095, 209031220, 222615242, 237082044, 252488973, 268897122, 286371562,
+    304981612, 324801039, 345908441, 368387505, 392327395, 417823051, 444975534, 473892561,
+    504688765, 537486295, 572415181, 609613936, 649230091, 691420715, 736353116, 784205494,
+    835167579, 889441492, 947242410, 1008799556, 1074357035, 1144174799, 1218529724, 1297716638,
+    1382049587, 1471862937, 1567512890, 1669378712, 1777864346, 1893399976, 2016443766, 2147483648,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codewords(lengths: &[u32]) -> std::vec::Vec<u32> {
+        let mut nodes = Vec::new();
+        nodes.push([0; 2]).unwrap();
+        let mut tree = Tree::new(0);
+        for (entry, len) in lengths.iter().enumerate() {
+            tree.insert(&mut nodes, entry as u16, *len).unwrap();
+        }
+        tree.finish(&mut nodes);
+        let mut setup = Setup::new();
+        setup.nodes = nodes;
+        let book = Codebook::default();
+
+        // decode every codeword written most significant bit first into the packet
+        (0..lengths.len())
+            .map(|entry| {
+                (0..1u32 << 8)
+                    .find(|codeword| {
+                        let len = lengths[entry];
+                        let mut packet = [0u8; 4];
+                        for bit in 0..len {
+                            if codeword >> (len - 1 - bit) & 1 == 1 {
+                                packet[bit as usize / 8] |= 1 << (bit % 8);
+                            }
+                        }
+                        let bits = &mut Bits::new(&packet);
+                        setup.decode(&book, bits) == Some(entry as u32) && bits.pos == len as usize
+                    })
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn codewords_are_assigned_like_the_spec_example() {
+        assert_eq!(
+            codewords(&[2, 4, 4, 4, 4, 2, 3, 3]),
+            [0b00, 0b0100, 0b0101, 0b0110, 0b0111, 0b10, 0b110, 0b111]
+        );
+    }
+
+    #[test]
+    fn an_overfull_codebook_is_rejected() {
+        let mut nodes = Vec::new();
+        nodes.push([0; 2]).unwrap();
+        let mut tree = Tree::new(0);
+        for entry in 0..2 {
+            tree.insert(&mut nodes, entry, 1).unwrap();
+        }
+        assert!(tree.insert(&mut nodes, 2, 1).is_err());
+    }
+
+    #[test]
+    fn bits_are_read_from_the_least_significant_up() {
+        let bits = &mut Bits::new(&[0b1010_0110, 0xFF, 0x01]);
+        assert_eq!(bits.read(1), Some(0));
+        assert_eq!(bits.read(3), Some(0b011));
+        assert_eq!(bits.read(8), Some(0xFA));
+        assert_eq!(bits.read(12), Some(0x1F));
+        assert_eq!(bits.read(1), None);
+    }
+
+    #[test]
+    fn codebook_floats_unpack_to_q12() {
+        // 1.0 is 2^20 * 2^-20, the exponent field holds -20 + 788
+        let one = (768u32 << 21) | (1 << 20);
+        assert_eq!(float_q12(one), Some(1 << 12));
+        assert_eq!(float_q12(one | 0x8000_0000), Some(-(1 << 12)));
+        // 0.5 and values below the Q12 resolution
+        assert_eq!(float_q12((767 << 21) | (1 << 20)), Some(1 << 11));
+        assert_eq!(float_q12((700 << 21) | 1), Some(0));
+        // too large for Q12 in an i32
+        assert_eq!(float_q12((800 << 21) | (1 << 20)), None);
+    }
+
+    #[test]
+    fn lookup1_values_is_the_integer_root() {
+        assert_eq!(lookup1_values(81, 4), 3);
+        assert_eq!(lookup1_values(80, 4), 2);
+        assert_eq!(lookup1_values(6561, 8), 3);
+        assert_eq!(lookup1_values(289, 2), 17);
+        assert_eq!(lookup1_values(7, 1), 7);
+    }
+
+    #[test]
+    fn dct_iv_matches_the_direct_sum() {
+        for n in [32, 128, 1024] {
+            let input: std::vec::Vec<i32> =
+                (0..n).map(|i| ((i * 7919 % 1031) - 515) << 12).collect();
+            let mut x = input.clone();
+            dct_iv(&mut x);
+            for (k, out) in x.iter().enumerate() {
+                let expected: f64 = input
+                    .iter()
+                    .enumerate()
+                    .map(|(j, v)| {
+                        let angle =
+                            core::f64::consts::PI / n as f64 * (j as f64 + 0.5) * (k as f64 + 0.5);
+                        *v as f64 * angle.cos()
+                    })
+                    .sum();
+                let error = (*out as f64 - expected).abs();
+                assert!(
+                    error < 1e-5 * (515 << 12) as f64 * n as f64,
+                    "{n} {k} {error}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn the_window_rises_from_zero_to_one() {
+        for len in [32, 1024] {
+            assert!(window(0, len) < 1 << 20);
+            assert!(window(len - 1, len) > (1 << 30) - (1 << 20));
+            // power complementary, w(i)^2 + w(len - 1 - i)^2 = 1
+            for i in 0..len {
+                let (a, b) = (window(i, len) as i64, window(len - 1 - i, len) as i64);
+                assert!(((a * a + b * b) >> 30).abs_diff(1 << 30) < 1 << 14);
+            }
+        }
+    }
+
+    #[test]
+    fn render_point_follows_the_spec() {
+        assert_eq!(render_point(0, 28, 128, 67, 12), 31);
+        assert_eq!(render_point(12, 38, 128, 67, 46), 46);
+        assert_eq!(render_point(0, 67, 128, 28, 12), 64);
+    }
+
+    #[test]
+    fn render_line_steps_through_the_inverse_db_table() {
+        let mut spectrum = [1 << Q12; 16];
+        render_line(0, 200, 16, 216, &mut spectrum);
+        for (x, value) in spectrum.iter().enumerate() {
+            let expected = (FLOOR1_INVERSE_DB[200 + x] >> 7) as i32;
+            assert_eq!(*value, expected);
+        }
+
+        // a falling line with a fractional slope
+        let mut spectrum = [1 << Q12; 4];
+        render_line(0, 255, 4, 245, &mut spectrum);
+        let ys: std::vec::Vec<usize> = spectrum
+            .iter()
+            .map(|v| {
+                FLOOR1_INVERSE_DB
+                    .iter()
+                    .position(|db| (db >> 7) as i32 == *v)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(ys, [255, 253, 250, 248]);
+    }
+}
@@ -0,0 +1,439 @@
+use super::adpcm::{AdpcmDecoder, Codec, MS_PREDICTORS};
+use super::g711::{ALAW_SILENCE, MULAW_SILENCE};
+use super::{SampleFormat, read_all};
+use crate::downmix::MAX_CHANNELS;
+use crate::file_reader::{SdError, SdFile};
+use defmt::{Format, info};
+
+// WAVE_FORMAT tags from the fmt chunk
+const FORMAT_PCM: u16 = 0x0001;
+const FORMAT_MS_ADPCM: u16 = 0x0002;
+const FORMAT_IEEE_FLOAT: u16 = 0x0003;
+const FORMAT_ALAW: u16 = 0x0006;
+const FORMAT_MULAW: u16 = 0x0007;
+const FORMAT_IMA_ADPCM: u16 = 0x0011;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+// WAVE_FORMAT_EXTENSIBLE has the longest fmt chunk
+const MAX_FMT_LEN: usize = 40;
+
+#[derive(Debug, Format)]
+pub enum Error {
+    Sd(SdError),
+    // The file does not start with a RIFF/WAVE header
+    NotWav,
+    // There is no fmt chunk before the data chunk
+    MissingFormat,
+    MissingData,
+    // The samples cannot be converted for the dac
+    Unsupported {
+        format_tag: u16,
+        bit_depth: u16,
+        channels: u16,
+    },
+}
+
+impl From<SdError> for Error {
+    fn from(e: SdError) -> Self {
+        Error::Sd(e)
+    }
+}
+
+#[derive(Debug, Format, Clone, Copy)]
+pub struct FormatChunk {
+    // for WAVE_FORMAT_EXTENSIBLE this is the tag of the sub format
+    pub format_tag: u16,
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub block_align: u16,
+    pub bit_depth: u16,
+    // speaker positions of the channels, only WAVE_FORMAT_EXTENSIBLE has one
+    pub channel_mask: Option<u32>,
+    // size of the predictor coefficient table of ms adpcm
+    pub ms_predictors: Option<u16>,
+}
+
+impl FormatChunk {
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        if b.len() < 16 {
+            return None;
+        }
+        let mut fmt = Self {
+            format_tag: u16::from_le_bytes([b[0], b[1]]),
+            channels: u16::from_le_bytes([b[2], b[3]]),
+            sample_rate: u32::from_le_bytes([b[4], b[5], b[6], b[7]]),
+            block_align: u16::from_le_bytes([b[12], b[13]]),
+            bit_depth: u16::from_le_bytes([b[14], b[15]]),
+            channel_mask: None,
+            ms_predictors: None,
+        };
+        if fmt.format_tag == FORMAT_EXTENSIBLE {
+            let b = b.get(..MAX_FMT_LEN)?;
+            fmt.channel_mask = Some(u32::from_le_bytes([b[20], b[21], b[22], b[23]]));
+            // the first two bytes of the sub format guid are the actual format tag
+            fmt.format_tag = u16::from_le_bytes([b[24], b[25]]);
+        } else if fmt.format_tag == FORMAT_MS_ADPCM {
+            // after the extension size and the samples per block
+            fmt.ms_predictors = b.get(20..22).map(|n| u16::from_le_bytes([n[0], n[1]]));
+        }
+        Some(fmt)
+    }
+
+    fn sample_format(&self) -> Option<SampleFormat> {
+        match (self.format_tag, self.bit_depth) {
+            (FORMAT_PCM, 8 | 16 | 24 | 32) => Some(SampleFormat::Int),
+            (FORMAT_IEEE_FLOAT, 32) => Some(SampleFormat::Float),
+            (FORMAT_ALAW, 8) => Some(SampleFormat::ALaw),
+            (FORMAT_MULAW, 8) => Some(SampleFormat::MuLaw),
+            _ => None,
+        }
+    }
+
+    fn adpcm_codec(&self) -> Option<Codec> {
+        match (self.format_tag, self.bit_depth) {
+            (FORMAT_IMA_ADPCM, 4) => Some(Codec::Ima),
+            // only the coefficients every file starts its table with are known
+            (FORMAT_MS_ADPCM, 4)
+                if self
+                    .ms_predictors
+                    .is_none_or(|predictors| predictors as usize <= MS_PREDICTORS) =>
+            {
+                Some(Codec::Ms)
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct WavFile<'a> {
+    file: SdFile<'a>,
+    pub format: SampleFormat,
+    pub sample_rate: u32,
+    // adpcm files are decoded to 16bit
+    pub bit_depth: u16,
+    pub num_channels: u16,
+    pub block_align: u16,
+    pub channel_mask: Option<u32>,
+    // file offsets of the next unread sample and the end of the data chunk
+    pub read: u32,
+    pub end: u32,
+    // file offset of the start of the data chunk
+    start: u32,
+    // byte the end of the stream is padded with
+    silence: u8,
+    adpcm: Option<AdpcmDecoder>,
+    // samples of all channels left to play according to the fact chunk,
+    // the last adpcm block is usually padded past the end of the audio
+    samples_left: Option<u32>,
+    total_samples: Option<u32>,
+}
+
+impl<'a> WavFile<'a> {
+    // Hands the file back with the error when it cannot be played, so it can be closed
+    pub async fn new(file: SdFile<'a>) -> Result<Self, (Error, SdFile<'a>)> {
+        let mut wav = Self {
+            file,
+            format: SampleFormat::Int,
+            sample_rate: 0,
+            bit_depth: 0,
+            num_channels: 0,
+            block_align: 0,
+            channel_mask: None,
+            read: 0,
+            end: 0,
+            start: 0,
+            silence: 0,
+            adpcm: None,
+            samples_left: None,
+            total_samples: None,
+        };
+        match wav.read_header().await {
+            Ok(()) => Ok(wav),
+            Err(e) => Err((e, wav.destroy())),
+        }
+    }
+
+    // Reads the chunks up to the sample data, the file is left at the first sample
+    async fn read_header(&mut self) -> Result<(), Error> {
+        let file = &mut self.file;
+        let mut riff = [0u8; 12];
+        if read_all(file, &mut riff).await? < riff.len()
+            || &riff[..4] != b"RIFF"
+            || &riff[8..] != b"WAVE"
+        {
+            return Err(Error::NotWav);
+        }
+
+        let mut fmt = None;
+        let mut fact = None;
+        let data_len = loop {
+            let mut header = [0u8; 8];
+            if read_all(file, &mut header).await? < header.len() {
+                return Err(Error::MissingData);
+            }
+            let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            if &header[..4] == b"data" {
+                break len;
+            }
+
+            // chunks are padded to an even length
+            let mut skip = len + (len & 1);
+            if &header[..4] == b"fmt " {
+                let mut bytes = [0u8; MAX_FMT_LEN];
+                let bytes = &mut bytes[..(len as usize).min(MAX_FMT_LEN)];
+                read_all(file, bytes).await?;
+                fmt = Some(FormatChunk::parse(bytes).ok_or(Error::MissingFormat)?);
+                skip -= bytes.len() as u32;
+            } else if &header[..4] == b"fact" && len >= 4 {
+                // number of frames in the file, compressed formats need it to find the end
+                let mut frames = [0u8; 4];
+                read_all(file, &mut frames).await?;
+                fact = Some(u32::from_le_bytes(frames));
+                skip -= 4;
+            }
+            file.seek_from_current(skip as i32)?;
+        };
+        let fmt = fmt.ok_or(Error::MissingFormat)?;
+
+        let unsupported = Error::Unsupported {
+            format_tag: fmt.format_tag,
+            bit_depth: fmt.bit_depth,
+            channels: fmt.channels,
+        };
+        if fmt.sample_rate == 0 {
+            return Err(unsupported);
+        }
+        let (format, bit_depth, adpcm) = if let Some(codec) = fmt.adpcm_codec() {
+            // blocks are decoded to 16bit pcm, `AdpcmDecoder` checks the block layout
+            let Some(adpcm) = AdpcmDecoder::new(codec, fmt.channels, fmt.block_align) else {
+                return Err(unsupported);
+            };
+            (SampleFormat::Int, 16, Some(adpcm))
+        } else {
+            let Some(format) = fmt.sample_format() else {
+                return Err(unsupported);
+            };
+            if fmt.channels == 0
+                || fmt.channels as usize > MAX_CHANNELS
+                || fmt.block_align != fmt.channels * fmt.bit_depth / 8
+            {
+                return Err(unsupported);
+            }
+            (format, fmt.bit_depth, None)
+        };
+
+        // streamed files leave the data length at 0 or u32::MAX, play those up to the end of the file
+        let read = file.offset();
+        let available = file.length() - read;
+        let data_len = if data_len == 0 {
+            available
+        } else {
+            data_len.min(available)
+        };
+        // the last adpcm block may be short, whole pcm frames are always the same size
+        let end = if adpcm.is_some() {
+            read + data_len
+        } else {
+            read + data_len - data_len % fmt.block_align as u32
+        };
+
+        let silence = match (format, bit_depth) {
+            // 8bit pcm is unsigned
+            (SampleFormat::Int, 8) => 0x80,
+            (SampleFormat::MuLaw, _) => MULAW_SILENCE,
+            (SampleFormat::ALaw, _) => ALAW_SILENCE,
+            _ => 0,
+        };
+
+        info!("[WAV] {}", fmt);
+        if let Some(adpcm) = &adpcm {
+            info!(
+                "[WAV] adpcm, {} samples per block",
+                adpcm.samples_per_block()
+            );
+        }
+        let total_samples = fact
+            .filter(|_| adpcm.is_some())
+            .map(|frames| frames.saturating_mul(fmt.channels as u32));
+        self.format = format;
+        self.sample_rate = fmt.sample_rate;
+        self.bit_depth = bit_depth;
+        self.num_channels = fmt.channels;
+        self.block_align = fmt.block_align;
+        self.channel_mask = fmt.channel_mask;
+        self.read = read;
+        self.end = end;
+        self.start = read;
+        self.silence = silence;
+        self.samples_left = total_samples;
+        self.total_samples = total_samples;
+        self.adpcm = adpcm;
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        let buffered = self
+            .adpcm
+            .as_ref()
+            .is_some_and(|adpcm| !adpcm.pcm().is_empty());
+        (self.read >= self.end && !buffered) || self.samples_left == Some(0)
+    }
+
+    // Frames left to play
+    pub fn remaining_frames(&self) -> u64 {
+        let channels = self.num_channels as u64;
+        let blocks = ((self.end - self.read) / self.block_align as u32) as u64;
+        let Some(adpcm) = &self.adpcm else {
+            return blocks;
+        };
+        match self.samples_left {
+            // the fact chunk is exact, the last block is usually padded
+            Some(left) => left as u64 / channels,
+            None => blocks * adpcm.samples_per_block() as u64 + adpcm.pcm().len() as u64 / channels,
+        }
+    }
+
+    // Ends the track early, when its file cannot be read
+    pub fn finish(&mut self) {
+        self.read = self.end;
+        self.samples_left = Some(0);
+    }
+
+    pub fn destroy(self) -> SdFile<'a> {
+        self.file
+    }
+
+    // Moves playback to `frame`, counted from the start of the data chunk
+    pub async fn seek(&mut self, frame: u32) -> Result<(), Error> {
+        // adpcm blocks only decode from their start, so seek to the block holding the frame
+        let (block, skip) = match &self.adpcm {
+            Some(adpcm) => {
+                let samples_per_block = adpcm.samples_per_block() as u32;
+                (frame / samples_per_block, frame % samples_per_block)
+            }
+            None => (frame, 0),
+        };
+        self.read = self
+            .start
+            .saturating_add(block.saturating_mul(self.block_align as u32))
+            .min(self.end);
+        self.file.seek_from_start(self.read)?;
+
+        let Some(adpcm) = &mut self.adpcm else {
+            return Ok(());
+        };
+        adpcm.consume(adpcm.pcm().len());
+        let channels = self.num_channels as u32;
+        let skipped = (frame - skip).saturating_mul(channels);
+        self.samples_left = self
+            .total_samples
+            .map(|total| total.saturating_sub(skipped));
+
+        // decode the start of the block and throw it away
+        let mut scratch = [0u8; 256];
+        let mut left = (skip * channels * 2) as usize;
+        while left > 0 && !self.is_finished() {
+            let len = left.min(scratch.len());
+            self.read_adpcm(&mut scratch[..len]).await?;
+            left -= len;
+        }
+        Ok(())
+    }
+
+    // Fills `buf` with samples straight from the data chunk, pads with silence once it ends.
+    // Adpcm is handed out as 16bit little endian pcm. Returns the length before the padding
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if self.adpcm.is_some() {
+            return self.read_adpcm(buf).await;
+        }
+
+        let len = (buf.len() as u32).min(self.end - self.read) as usize;
+        let read = read_all(&mut self.file, &mut buf[..len]).await?;
+        self.read += read as u32;
+        if read < len {
+            // the file is shorter than its header says
+            self.end = self.read;
+        }
+        buf[read..].fill(self.silence);
+        Ok(read)
+    }
+
+    async fn read_adpcm(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let Some(adpcm) = &mut self.adpcm else {
+            return Ok(0);
+        };
+
+        let mut written = 0;
+        while written + 1 < buf.len() && self.samples_left != Some(0) {
+            if adpcm.pcm().is_empty() {
+                let len = adpcm.block_align().min((self.end - self.read) as usize);
+                if len == 0 {
+                    break;
+                }
+                let read = read_all(&mut self.file, adpcm.block_mut(len)).await?;
+                self.read += read as u32;
+                if read < len {
+                    // the file is shorter than its header says
+                    self.end = self.read;
+                }
+                adpcm.decode(read);
+                continue;
+            }
+
+            let mut count = adpcm.pcm().len().min((buf.len() - written) / 2);
+            if let Some(left) = &mut self.samples_left {
+                count = count.min(*left as usize);
+                *left -= count as u32;
+            }
+            for (out, sample) in buf[written..]
+                .chunks_exact_mut(2)
+                .zip(&adpcm.pcm()[..count])
+            {
+                out.copy_from_slice(&sample.to_le_bytes());
+            }
+            adpcm.consume(count);
+            written += count * 2;
+        }
+        buf[written..].fill(0);
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_extensible_fmt_chunk_has_the_sub_format_and_channel_mask() {
+        let mut b = [0u8; MAX_FMT_LEN];
+        b[..2].copy_from_slice(&FORMAT_EXTENSIBLE.to_le_bytes());
+        b[2..4].copy_from_slice(&6u16.to_le_bytes());
+        b[4..8].copy_from_slice(&48_000u32.to_le_bytes());
+        b[12..14].copy_from_slice(&18u16.to_le_bytes());
+        b[14..16].copy_from_slice(&24u16.to_le_bytes());
+        b[20..24].copy_from_slice(&0x3Fu32.to_le_bytes());
+        b[24..26].copy_from_slice(&FORMAT_PCM.to_le_bytes());
+
+        let fmt = FormatChunk::parse(&b).unwrap();
+        assert_eq!(fmt.format_tag, FORMAT_PCM);
+        assert_eq!(fmt.channels, 6);
+        assert_eq!(fmt.sample_rate, 48_000);
+        assert_eq!(fmt.bit_depth, 24);
+        assert_eq!(fmt.channel_mask, Some(0x3F));
+        // the extension is cut off
+        assert!(FormatChunk::parse(&b[..24]).is_none());
+    }
+
+    #[test]
+    fn ms_adpcm_with_its_own_coefficients_is_not_supported() {
+        let mut b = [0u8; 22];
+        b[..2].copy_from_slice(&FORMAT_MS_ADPCM.to_le_bytes());
+        b[14..16].copy_from_slice(&4u16.to_le_bytes());
+        b[20..22].copy_from_slice(&7u16.to_le_bytes());
+        assert_eq!(
+            FormatChunk::parse(&b).unwrap().adpcm_codec(),
+            Some(Codec::Ms)
+        );
+        b[20..22].copy_from_slice(&8u16.to_le_bytes());
+        assert_eq!(FormatChunk::parse(&b).unwrap().adpcm_codec(), None);
+    }
+}
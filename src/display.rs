@@ -1,4 +1,7 @@
+use crate::audio_playback::PAUSED;
+use crate::volume::VOLUME;
 use core::str::FromStr;
+use core::sync::atomic::Ordering;
 use defmt::*;
 use embassy_rp::{
     gpio::Output,
@@ -27,8 +30,10 @@ use mipidsi::{
 pub const SONG_NAME_LEN: usize = 25;
 pub struct MediaUi<'a> {
     display: Display<'a>,
+    // last state drawn, the actual state is shared with playback through `PAUSED`
     pub paused: bool,
     pub song: String<SONG_NAME_LEN>,
+    // last volume drawn, the actual volume is shared with playback through `VOLUME`
     pub volume: u8,
 }
 
@@ -36,6 +41,7 @@ impl<'a> MediaUi<'a> {
     const STATUS_BAR: i32 = 40;
     const SPEAKER: Point = Point::new(40, Self::STATUS_BAR);
     const VOLUME: Point = Point::new(60, Self::STATUS_BAR + 10);
+    const PLAY_STATE: Point = Point::new(Display::W / 2 - 7, Self::STATUS_BAR);
     const BATTERY: Point = Point::new(Display::W - 65, Self::STATUS_BAR);
     const SONG_ROW: i32 = 100;
     const PLAYED_ROW: i32 = 125;
@@ -44,7 +50,7 @@ impl<'a> MediaUi<'a> {
         Self {
             paused: true,
             song: String::from_str("Not Playing").unwrap(),
-            volume: 100,
+            volume: VOLUME.level(),
             display,
         }
     }
@@ -69,7 +75,8 @@ impl<'a> MediaUi<'a> {
 
     pub fn init(&mut self) {
         self.draw_speaker();
-        self.draw_volume(99);
+        self.draw_volume(VOLUME.level());
+        self.draw_play_state(PAUSED.load(Ordering::Relaxed));
         self.draw_battery(100);
         self.draw_song("Truth Hurts - Sawyer Bristol");
         self.draw_played(0);
@@ -94,9 +101,27 @@ impl<'a> MediaUi<'a> {
         .unwrap();
     }
 
+    // Redraws the volume if it was changed since it was last drawn
+    pub fn update_volume(&mut self) {
+        let volume = VOLUME.level();
+        if volume != self.volume {
+            self.draw_volume(volume);
+        }
+    }
+
     fn draw_volume(&mut self, volume: u8) {
         let style = MonoTextStyle::new(&FONT_10X20, Rgb565::BLACK);
         let mut buf = [0u8; 3];
+        self.volume = volume;
+
+        // clear the previous value
+        Rectangle::new(
+            Point::new(Self::VOLUME.x + 12, Self::VOLUME.y - 15),
+            Size::new(30, 20),
+        )
+        .into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE))
+        .draw(&mut self.display.display)
+        .unwrap();
 
         Text::new("%", Self::VOLUME, style)
             .draw(&mut self.display.display)
@@ -110,6 +135,46 @@ impl<'a> MediaUi<'a> {
         .unwrap();
     }
 
+    // Redraws the play state if playback was paused or resumed since it was last drawn
+    pub fn update_paused(&mut self) {
+        let paused = PAUSED.load(Ordering::Relaxed);
+        if paused != self.paused {
+            self.draw_play_state(paused);
+        }
+    }
+
+    fn draw_play_state(&mut self, paused: bool) {
+        let style = PrimitiveStyle::with_fill(Rgb565::BLACK);
+        self.paused = paused;
+
+        // clear the previous icon
+        Rectangle::new(Self::PLAY_STATE, Size::new(15, 15))
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE))
+            .draw(&mut self.display.display)
+            .unwrap();
+
+        if paused {
+            for x in [0, 9] {
+                Rectangle::new(
+                    Point::new(Self::PLAY_STATE.x + x, Self::PLAY_STATE.y),
+                    Size::new(5, 15),
+                )
+                .into_styled(style)
+                .draw(&mut self.display.display)
+                .unwrap();
+            }
+        } else {
+            Triangle::new(
+                Self::PLAY_STATE,
+                Point::new(Self::PLAY_STATE.x, Self::PLAY_STATE.y + 14),
+                Point::new(Self::PLAY_STATE.x + 14, Self::PLAY_STATE.y + 7),
+            )
+            .into_styled(style)
+            .draw(&mut self.display.display)
+            .unwrap();
+        }
+    }
+
     fn draw_battery(&mut self, battery: u8) {
         let color = match battery {
             0..=20 => Rgb565::RED,
@@ -0,0 +1,259 @@
+// Downmix of surround files to stereo following ITU-R BS.775.
+// Every channel is added to the left and right output with a fixed coefficient, then all of them
+// are scaled down together so a full scale signal on every channel cannot clip
+
+// 7.1 is the largest layout that is played
+pub const MAX_CHANNELS: usize = 8;
+
+const ONE_Q15: i32 = 1 << 15;
+// -3dB
+const HALF_POWER_Q15: i32 = 23_170;
+// -6dB
+const HALF_Q15: i32 = 16_384;
+
+// WAVE_FORMAT_EXTENSIBLE speaker positions, each one is a bit of the channel mask
+const FRONT_LEFT: u32 = 0x1;
+const FRONT_RIGHT: u32 = 0x2;
+const FRONT_CENTER: u32 = 0x4;
+const LOW_FREQUENCY: u32 = 0x8;
+const BACK_LEFT: u32 = 0x10;
+const BACK_RIGHT: u32 = 0x20;
+const BACK_CENTER: u32 = 0x100;
+const SIDE_LEFT: u32 = 0x200;
+const SIDE_RIGHT: u32 = 0x400;
+
+// Left and right coefficient of every speaker position, in channel mask bit order
+const SPEAKERS: [[i32; 2]; 18] = [
+    // front left, front right, front center
+    [ONE_Q15, 0],
+    [0, ONE_Q15],
+    [HALF_POWER_Q15, HALF_POWER_Q15],
+    // the lfe channel is left out, small speakers cannot play it anyway
+    [0, 0],
+    // back left, back right
+    [HALF_POWER_Q15, 0],
+    [0, HALF_POWER_Q15],
+    // front left of center, front right of center
+    [ONE_Q15, 0],
+    [0, ONE_Q15],
+    // back center
+    [HALF_Q15, HALF_Q15],
+    // side left, side right
+    [HALF_POWER_Q15, 0],
+    [0, HALF_POWER_Q15],
+    // top center, top front left, top front center, top front right
+    [HALF_Q15, HALF_Q15],
+    [HALF_POWER_Q15, 0],
+    [HALF_Q15, HALF_Q15],
+    [0, HALF_POWER_Q15],
+    // top back left, top back center, top back right
+    [HALF_POWER_Q15, 0],
+    [HALF_Q15, HALF_Q15],
+    [0, HALF_POWER_Q15],
+];
+
+pub struct Downmix {
+    channels: usize,
+    // left and right coefficient of every channel in Q15
+    coefficients: [[i32; 2]; MAX_CHANNELS],
+}
+
+impl Downmix {
+    // Uses the speaker positions from the channel mask when the file has one,
+    // otherwise the default layout for the channel count
+    pub fn new(channels: u16, channel_mask: Option<u32>) -> Self {
+        let channels = (channels as usize).min(MAX_CHANNELS);
+        let mask = match channel_mask {
+            Some(mask) if mask != 0 => mask,
+            _ => default_mask(channels),
+        };
+
+        // channels are stored in the order of the mask bits,
+        // channels past the last set bit have no position and are left out
+        let mut coefficients = [[0i32; 2]; MAX_CHANNELS];
+        let speakers = (0..SPEAKERS.len()).filter(|bit| mask & 1 << bit != 0);
+        for (coefficient, speaker) in coefficients[..channels].iter_mut().zip(speakers) {
+            *coefficient = SPEAKERS[speaker];
+        }
+
+        // scale every coefficient down until neither output can exceed full scale
+        let total = (0..2)
+            .map(|side| coefficients.iter().map(|c| c[side]).sum::<i32>())
+            .max()
+            .unwrap_or(0);
+        if total > ONE_Q15 {
+            for coefficient in coefficients.iter_mut().flatten() {
+                *coefficient = *coefficient * ONE_Q15 / total;
+            }
+        }
+
+        Self {
+            channels,
+            coefficients,
+        }
+    }
+
+    pub fn coefficients(&self) -> &[[i32; 2]] {
+        &self.coefficients[..self.channels]
+    }
+
+    // Mixes the samples of one frame, at full i32 scale, to a left and right sample at the same
+    // scale. 24 and 32bit sources keep their precision until the mix is dithered down to 16 bits
+    pub fn mix(&self, frame: impl Iterator<Item = i32>) -> (i32, i32) {
+        let (left, right) =
+            frame
+                .zip(self.coefficients())
+                .fold((0i64, 0i64), |(left, right), (sample, [l, r])| {
+                    (
+                        left + sample as i64 * *l as i64,
+                        right + sample as i64 * *r as i64,
+                    )
+                });
+        let scale = |mixed: i64| (mixed >> 15).clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+        (scale(left), scale(right))
+    }
+}
+
+// Default speaker layouts used by wav files without a channel mask
+fn default_mask(channels: usize) -> u32 {
+    let front = FRONT_LEFT | FRONT_RIGHT;
+    match channels {
+        1 => FRONT_CENTER,
+        2 => front,
+        3 => front | FRONT_CENTER,
+        4 => front | BACK_LEFT | BACK_RIGHT,
+        5 => front | FRONT_CENTER | BACK_LEFT | BACK_RIGHT,
+        6 => front | FRONT_CENTER | LOW_FREQUENCY | BACK_LEFT | BACK_RIGHT,
+        7 => front | FRONT_CENTER | LOW_FREQUENCY | BACK_CENTER | SIDE_LEFT | SIDE_RIGHT,
+        _ => front | FRONT_CENTER | LOW_FREQUENCY | BACK_LEFT | BACK_RIGHT | SIDE_LEFT | SIDE_RIGHT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stereo_is_left_as_it_is() {
+        let downmix = Downmix::new(2, None);
+        assert_eq!(downmix.coefficients(), &[[ONE_Q15, 0], [0, ONE_Q15]]);
+        assert_eq!(
+            downmix.mix([1000 << 16, -2000 << 16].into_iter()),
+            (1000 << 16, -2000 << 16)
+        );
+    }
+
+    #[test]
+    fn coefficients_add_up_to_full_scale_at_most() {
+        for channels in 1..=MAX_CHANNELS as u16 {
+            let downmix = Downmix::new(channels, None);
+            let sums =
+                [0, 1].map(|side| downmix.coefficients().iter().map(|c| c[side]).sum::<i32>());
+            assert!(
+                sums.iter().all(|sum| *sum <= ONE_Q15),
+                "{} channels: {:?}",
+                channels,
+                sums
+            );
+            // layouts that had to be scaled down lose no more than the rounding
+            if channels > 2 {
+                let loudest = *sums.iter().max().unwrap();
+                assert!(
+                    loudest >= ONE_Q15 - channels as i32,
+                    "{} channels: {:?}",
+                    channels,
+                    sums
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn full_scale_surround_does_not_clip() {
+        let downmix = Downmix::new(6, None);
+        for sample in [i32::MAX, i32::MIN] {
+            let (left, right) = downmix.mix([sample; 6].into_iter());
+            for (side, mixed) in [left, right].into_iter().enumerate() {
+                let exact: i64 = downmix
+                    .coefficients()
+                    .iter()
+                    .map(|c| sample as i64 * c[side] as i64)
+                    .sum::<i64>()
+                    >> 15;
+                // nothing was saturated and the mix stays within 1% of full scale
+                assert_eq!(mixed as i64, exact);
+                let level = exact as f64 / sample as f64;
+                assert!((0.99..=1.0).contains(&level), "level {}", level);
+            }
+        }
+    }
+
+    // Feeds a full scale sample on one channel at a time, so each output shows the coefficient
+    // of that channel
+    fn single_channel_levels(downmix: &Downmix, channels: usize) -> Vec<(f64, f64)> {
+        (0..channels)
+            .map(|channel| {
+                let frame = (0..channels).map(|i| if i == channel { 1 << 30 } else { 0 });
+                let (left, right) = downmix.mix(frame);
+                (
+                    left as f64 / (1 << 30) as f64,
+                    right as f64 / (1 << 30) as f64,
+                )
+            })
+            .collect()
+    }
+
+    fn assert_levels(levels: &[(f64, f64)], expected: &[(f64, f64)]) {
+        // the layout is scaled down as a whole, so compare the levels relative to the front left
+        let scale = levels[0].0;
+        for (channel, (level, expected)) in levels.iter().zip(expected).enumerate() {
+            for (got, want) in [(level.0 / scale, expected.0), (level.1 / scale, expected.1)] {
+                assert!(
+                    (got - want).abs() < 1e-3,
+                    "channel {}: {} != {}",
+                    channel,
+                    got,
+                    want
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn each_channel_of_5_1_gets_its_itu_coefficient() {
+        let downmix = Downmix::new(6, None);
+        let minus_3db = core::f64::consts::FRAC_1_SQRT_2;
+        assert_levels(
+            &single_channel_levels(&downmix, 6),
+            &[
+                (1.0, 0.0),
+                (0.0, 1.0),
+                (minus_3db, minus_3db),
+                (0.0, 0.0),
+                (minus_3db, 0.0),
+                (0.0, minus_3db),
+            ],
+        );
+        // scaled down so the five full range channels together reach full scale
+        let front_left = single_channel_levels(&downmix, 6)[0].0;
+        assert!((front_left - 1.0 / (1.0 + 2.0 * minus_3db)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn the_channel_mask_places_the_channels() {
+        // five channels stored in the order of their mask bits, back center before the sides
+        let mask = FRONT_LEFT | FRONT_RIGHT | BACK_CENTER | SIDE_LEFT | SIDE_RIGHT;
+        let downmix = Downmix::new(5, Some(mask));
+        let minus_3db = core::f64::consts::FRAC_1_SQRT_2;
+        assert_levels(
+            &single_channel_levels(&downmix, 5),
+            &[
+                (1.0, 0.0),
+                (0.0, 1.0),
+                (0.5, 0.5),
+                (minus_3db, 0.0),
+                (0.0, minus_3db),
+            ],
+        );
+    }
+}
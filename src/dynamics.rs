@@ -0,0 +1,464 @@
+// Compressor and look-ahead peak limiter, the last stage before the dac.
+// They see the samples of the volume stage before those are cut to 16bit, so boosted audio is
+// turned down smoothly instead of clipping. The compressor evens out the loudness for small
+// speakers and the limiter keeps every peak under the ceiling
+
+use crate::frame::StereoFrame;
+use crate::settings::parse_fixed;
+use crate::volume::{Gain, centibels_to_q15, clip};
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicI16, AtomicU8, AtomicU16, Ordering};
+
+// Frames the limiter looks ahead, about 1.5ms at 44.1khz.
+// The gain is turned down over this many frames and the audio is delayed by as much
+const LOOKAHEAD_BITS: u32 = 6;
+const LOOKAHEAD: usize = 1 << LOOKAHEAD_BITS;
+// Frames between updates of the compressor gain
+const BLOCK_BITS: u32 = 4;
+const BLOCK: u32 = 1 << BLOCK_BITS;
+
+// Gains are in Q14 so a boosted sample times a gain stays within an i32
+const ONE_Q14: i32 = 1 << 14;
+const ONE_Q16: i64 = 1 << 16;
+const ONE_Q20: i64 = 1 << 20;
+const ONE_Q30: i32 = 1 << 30;
+// The volume stage boosts samples to at most twice full scale, the makeup gain doubles that
+const MAX_SAMPLE: i32 = (1 << 17) - 1;
+// Centibels in a doubling, 200 / log2(10), times 1000
+const CB_PER_OCTAVE_X1000: i64 = 60_206;
+
+// Limits of the settings, the makeup gain stays below +6dB to keep the samples in range
+const MAX_MAKEUP_CB: i32 = 59;
+const MIN_LEVEL_CB: i32 = -600;
+const MAX_RATIO_X10: i32 = 200;
+const MAX_TIME_MS: i32 = 5000;
+
+// Compressor and limiter settings, read from the settings file
+pub static DYNAMICS: Settings = Settings::new();
+
+pub struct Settings {
+    limiter: AtomicBool,
+    // level the limiter keeps the peaks under, in centibels below full scale
+    ceiling_cb: AtomicI16,
+    limiter_release_ms: AtomicU16,
+    threshold_cb: AtomicI16,
+    // compression ratio in tenths, 10 leaves the compressor off
+    ratio_x10: AtomicU8,
+    attack_ms: AtomicU16,
+    release_ms: AtomicU16,
+    makeup_cb: AtomicI16,
+}
+
+impl Settings {
+    const fn new() -> Self {
+        Self {
+            limiter: AtomicBool::new(true),
+            ceiling_cb: AtomicI16::new(-3),
+            limiter_release_ms: AtomicU16::new(100),
+            threshold_cb: AtomicI16::new(-180),
+            ratio_x10: AtomicU8::new(10),
+            attack_ms: AtomicU16::new(5),
+            release_ms: AtomicU16::new(200),
+            makeup_cb: AtomicI16::new(0),
+        }
+    }
+
+    // Takes a setting from the settings file, returns false for names that are not one of these.
+    // Levels are in dB and times in ms, values that do not parse are ignored
+    pub fn set(&self, key: &[u8], value: &[u8]) -> bool {
+        let tenths = |min: i32, max: i32| parse_fixed(value, 1).map(|x| x.clamp(min, max));
+        let ms = || parse_fixed(value, 0).map(|ms| ms.clamp(1, MAX_TIME_MS) as u16);
+        let store_level = |setting: &AtomicI16, level: Option<i32>| {
+            if let Some(level) = level {
+                setting.store(level as i16, Ordering::Relaxed);
+            }
+        };
+        let store_ms = |setting: &AtomicU16| {
+            if let Some(ms) = ms() {
+                setting.store(ms, Ordering::Relaxed);
+            }
+        };
+
+        match key {
+            b"limiter" => self
+                .limiter
+                .store(value.eq_ignore_ascii_case(b"on"), Ordering::Relaxed),
+            b"limiter_ceiling" => store_level(&self.ceiling_cb, tenths(MIN_LEVEL_CB, 0)),
+            b"limiter_release" => store_ms(&self.limiter_release_ms),
+            b"compressor_threshold" => store_level(&self.threshold_cb, tenths(MIN_LEVEL_CB, 0)),
+            b"compressor_ratio" => {
+                if let Some(ratio) = tenths(10, MAX_RATIO_X10) {
+                    self.ratio_x10.store(ratio as u8, Ordering::Relaxed);
+                }
+            }
+            b"compressor_attack" => store_ms(&self.attack_ms),
+            b"compressor_release" => store_ms(&self.release_ms),
+            b"compressor_makeup" => store_level(&self.makeup_cb, tenths(0, MAX_MAKEUP_CB)),
+            _ => return false,
+        }
+        true
+    }
+
+    fn config(&self) -> Config {
+        Config {
+            limiter: self.limiter.load(Ordering::Relaxed),
+            ceiling_cb: self.ceiling_cb.load(Ordering::Relaxed),
+            limiter_release_ms: self.limiter_release_ms.load(Ordering::Relaxed),
+            threshold_cb: self.threshold_cb.load(Ordering::Relaxed),
+            ratio_x10: self.ratio_x10.load(Ordering::Relaxed),
+            attack_ms: self.attack_ms.load(Ordering::Relaxed),
+            release_ms: self.release_ms.load(Ordering::Relaxed),
+            makeup_cb: self.makeup_cb.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// The settings as they were when the stages were last set up
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Config {
+    limiter: bool,
+    ceiling_cb: i16,
+    limiter_release_ms: u16,
+    threshold_cb: i16,
+    ratio_x10: u8,
+    attack_ms: u16,
+    release_ms: u16,
+    makeup_cb: i16,
+}
+
+impl Config {
+    fn compressor(&self) -> bool {
+        self.ratio_x10 > 10
+    }
+}
+
+// The compressor and limiter for one stream of dac frames
+pub struct Dynamics {
+    rate: u32,
+    config: Config,
+    compressor: Compressor,
+    limiter: Limiter,
+}
+
+impl Dynamics {
+    pub fn new(rate: u32) -> Self {
+        let config = DYNAMICS.config();
+        let mut dynamics = Self {
+            rate,
+            config,
+            compressor: Compressor::new(),
+            limiter: Limiter::new(),
+        };
+        dynamics.configure(config);
+        dynamics
+    }
+
+    fn configure(&mut self, config: Config) {
+        let rate = self.rate;
+        let compressor = &mut self.compressor;
+        compressor.threshold = centibels_to_q15(config.threshold_cb as i32);
+        compressor.slope_q16 = (ONE_Q16 - ONE_Q16 * 10 / config.ratio_x10.max(10) as i64) as i32;
+        compressor.makeup_cb = config.makeup_cb as i32;
+        compressor.attack_q16 = coefficient(ONE_Q16, BLOCK, config.attack_ms, rate);
+        compressor.release_q16 = coefficient(ONE_Q16, BLOCK, config.release_ms, rate);
+
+        // the delay line holds old audio when the limiter is turned back on
+        if config.limiter && !self.config.limiter {
+            self.limiter = Limiter::new();
+        }
+        self.limiter.ceiling = centibels_to_q15(config.ceiling_cb as i32).min(i16::MAX as i32);
+        self.limiter.release_q20 = coefficient(ONE_Q20, 1, config.limiter_release_ms, rate);
+        self.config = config;
+    }
+
+    // Applies the volume of `gain` followed by the compressor and limiter, if they are enabled
+    pub fn process(&mut self, gain: &mut Gain, frames: &mut [StereoFrame]) {
+        let config = DYNAMICS.config();
+        if config != self.config {
+            self.configure(config);
+        }
+        let (compress, limit) = (config.compressor(), config.limiter);
+        if !compress && !limit {
+            gain.process(frames);
+            return;
+        }
+
+        gain.process_with(frames, |mut left, mut right| {
+            if compress {
+                (left, right) = self.compressor.process(left, right);
+            }
+            if limit {
+                (left, right) = self.limiter.process(left, right);
+            }
+            StereoFrame::new(clip(left), clip(right))
+        });
+    }
+}
+
+// Feed forward compressor. The level is followed once per block of frames and the gain
+// ramps to the new value over the next block
+struct Compressor {
+    // sample level the compression starts at
+    threshold: i32,
+    // how much of the level above the threshold is taken away, 1 - 1 / ratio in Q16
+    slope_q16: i32,
+    makeup_cb: i32,
+    // per block envelope coefficients in Q16
+    attack_q16: i32,
+    release_q16: i32,
+    envelope: i32,
+    // loudest sample of the block so far
+    peak: i32,
+    frames: u32,
+    // gain in Q14 and its change per frame
+    gain: i32,
+    step: i32,
+}
+
+impl Compressor {
+    fn new() -> Self {
+        Self {
+            threshold: 0,
+            slope_q16: 0,
+            makeup_cb: 0,
+            attack_q16: 0,
+            release_q16: 0,
+            envelope: 0,
+            peak: 0,
+            frames: 0,
+            gain: ONE_Q14,
+            step: 0,
+        }
+    }
+
+    fn process(&mut self, left: i32, right: i32) -> (i32, i32) {
+        self.peak = self.peak.max(left.abs()).max(right.abs());
+        self.frames += 1;
+        if self.frames == BLOCK {
+            self.update();
+        }
+
+        self.gain = (self.gain + self.step).max(0);
+        let compress = |sample| apply(sample, self.gain).clamp(-MAX_SAMPLE, MAX_SAMPLE);
+        (compress(left), compress(right))
+    }
+
+    fn update(&mut self) {
+        let peak = mem::take(&mut self.peak);
+        self.frames = 0;
+        let coefficient = if peak > self.envelope {
+            self.attack_q16
+        } else {
+            self.release_q16
+        };
+        self.envelope += (((peak - self.envelope) as i64 * coefficient as i64) >> 16) as i32;
+
+        let mut gain_cb = self.makeup_cb;
+        if self.envelope > self.threshold {
+            let over_q16 = log2_q16(self.envelope as u32) - log2_q16(self.threshold as u32);
+            let reduction_q16 = (over_q16 as i64 * self.slope_q16 as i64) >> 16;
+            gain_cb -= ((reduction_q16 * CB_PER_OCTAVE_X1000 / 1000) >> 16) as i32;
+        }
+        let target = centibels_to_q15(gain_cb) >> 1;
+        self.step = (target - self.gain) >> BLOCK_BITS;
+    }
+}
+
+// Look-ahead peak limiter.
+// The gain a frame needs to stay under the ceiling is held for the look-ahead and averaged over
+// it, so the gain has come all the way down when the frame leaves the delay line
+struct Limiter {
+    // sample level no output goes above
+    ceiling: i32,
+    // per frame release coefficient in Q20
+    release_q20: i32,
+    // gain recovering from the last peak, in Q30
+    envelope: i32,
+    window: MinWindow,
+    // the gains of the frames in the delay line in Q14, and their sum
+    gains: [u16; LOOKAHEAD],
+    sum: u32,
+    delay: [(i32, i32); LOOKAHEAD],
+    position: usize,
+}
+
+impl Limiter {
+    fn new() -> Self {
+        Self {
+            ceiling: i16::MAX as i32,
+            release_q20: ONE_Q20 as i32,
+            envelope: ONE_Q30,
+            window: MinWindow::new(),
+            gains: [ONE_Q14 as u16; LOOKAHEAD],
+            sum: (ONE_Q14 as u32) << LOOKAHEAD_BITS,
+            delay: [(0, 0); LOOKAHEAD],
+            position: 0,
+        }
+    }
+
+    fn process(&mut self, left: i32, right: i32) -> (i32, i32) {
+        let peak = left.abs().max(right.abs());
+        let required = if peak > self.ceiling {
+            self.ceiling * ONE_Q14 / peak
+        } else {
+            ONE_Q14
+        };
+        let held = self.window.push(required as u16) as i32;
+
+        // recover after a peak, but never above what the frames in the look-ahead need
+        let rest = ONE_Q30 - self.envelope;
+        let released = if rest < 1 << 20 {
+            ONE_Q30
+        } else {
+            self.envelope + (rest >> 20) * self.release_q20
+        };
+        self.envelope = released.min(held << 16);
+
+        let gain = (self.envelope >> 16) as u16;
+        self.sum = self.sum - self.gains[self.position] as u32 + gain as u32;
+        self.gains[self.position] = gain;
+        self.delay[self.position] = (left, right);
+        self.position = (self.position + 1) % LOOKAHEAD;
+
+        let (left, right) = self.delay[self.position];
+        let gain = (self.sum >> LOOKAHEAD_BITS) as i32;
+        (apply(left, gain), apply(right, gain))
+    }
+}
+
+// Smallest of the last `LOOKAHEAD` values. Only the values that can still become the smallest
+// are kept, in the order they came in, so the smallest is always the first one
+struct MinWindow {
+    // when each value came in and the value
+    entries: [(u32, u16); LOOKAHEAD],
+    first: usize,
+    len: usize,
+    time: u32,
+}
+
+impl MinWindow {
+    fn new() -> Self {
+        Self {
+            entries: [(0, 0); LOOKAHEAD],
+            first: 0,
+            len: 0,
+            time: 0,
+        }
+    }
+
+    // Adds a value and returns the smallest in the window
+    fn push(&mut self, value: u16) -> u16 {
+        // the first value leaves the window
+        if self.len > 0 && self.time.wrapping_sub(self.entries[self.first].0) >= LOOKAHEAD as u32 {
+            self.first = (self.first + 1) % LOOKAHEAD;
+            self.len -= 1;
+        }
+        // larger values from before this one can no longer be the smallest
+        while self.len > 0 && self.entries[(self.first + self.len - 1) % LOOKAHEAD].1 >= value {
+            self.len -= 1;
+        }
+        self.entries[(self.first + self.len) % LOOKAHEAD] = (self.time, value);
+        self.len += 1;
+        self.time = self.time.wrapping_add(1);
+        self.entries[self.first].1
+    }
+}
+
+fn apply(sample: i32, gain_q14: i32) -> i32 {
+    (sample * gain_q14) >> 14
+}
+
+// One pole coefficient for a time constant of `ms`, updated every `frames` frames
+fn coefficient(one: i64, frames: u32, ms: u16, rate: u32) -> i32 {
+    let time_frames = ms.max(1) as i64 * rate as i64 / 1000;
+    (one * frames as i64 / time_frames.max(1)).min(one) as i32
+}
+
+// log2 of `x` in Q16, `x` must not be 0
+fn log2_q16(x: u32) -> i32 {
+    let whole = 31 - x.leading_zeros();
+    // the mantissa in Q30, every squaring moves out the next bit of the fraction
+    let mut mantissa = ((x as u64) << 30) >> whole;
+    let mut fraction = 0;
+    for bit in (0..16).rev() {
+        mantissa = (mantissa * mantissa) >> 30;
+        if mantissa >= 2 << 30 {
+            mantissa >>= 1;
+            fraction |= 1 << bit;
+        }
+    }
+    ((whole as i32) << 16) | fraction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shuffle::Rng;
+
+    #[test]
+    fn log2_matches_the_float_log2() {
+        let mut rng = Rng::new(1);
+        let values = (0..32)
+            .map(|bit| 1 << bit)
+            .chain((0..10_000).map(|_| rng.next() >> (rng.next() % 32)));
+        for x in values.filter(|x| *x > 0) {
+            let expected = (x as f64).log2() * ONE_Q16 as f64;
+            let error = log2_q16(x) as f64 - expected;
+            // the fraction is cut off, never rounded up
+            assert!((-2.0..=0.0).contains(&error), "log2({x}): {error} off");
+        }
+    }
+
+    #[test]
+    fn min_window_matches_a_brute_force_minimum() {
+        let mut rng = Rng::new(7);
+        let mut window = MinWindow::new();
+        let mut values = std::vec::Vec::new();
+        for n in 0..5_000 {
+            // runs of rising and falling values as well as noise
+            let value = match n / 500 % 3 {
+                0 => (rng.next() % 1000) as u16,
+                1 => (n % 300) as u16,
+                _ => (300 - n % 300) as u16,
+            };
+            values.push(value);
+            let start = values.len().saturating_sub(LOOKAHEAD);
+            let expected = *values[start..].iter().min().unwrap();
+            assert_eq!(window.push(value), expected, "after {} values", n + 1);
+        }
+    }
+
+    #[test]
+    fn a_boosted_burst_never_goes_past_the_ceiling() {
+        for ceiling_cb in [-3, -60] {
+            let mut limiter = Limiter::new();
+            limiter.ceiling = centibels_to_q15(ceiling_cb);
+            limiter.release_q20 = coefficient(ONE_Q20, 1, 50, 44_100);
+
+            // quiet, then a burst at twice full scale, then quiet again
+            let full = 2 * i16::MAX as i32;
+            let mut loudest = 0;
+            for n in 0..20_000 {
+                let sample = match n {
+                    5_000..10_000 => {
+                        let phase = (n as f64 * 0.3).sin();
+                        (phase * full as f64) as i32
+                    }
+                    _ => ((n as f64 * 0.05).sin() * 1000.0) as i32,
+                };
+                let (left, right) = limiter.process(sample, -sample);
+                loudest = loudest.max(left.abs()).max(right.abs());
+            }
+            assert!(
+                loudest <= limiter.ceiling,
+                "{ceiling_cb} cB: {loudest} > {}",
+                limiter.ceiling
+            );
+            // the burst is turned down to the ceiling, not far below it
+            assert!(
+                loudest > limiter.ceiling * 95 / 100,
+                "{ceiling_cb} cB: {loudest}"
+            );
+        }
+    }
+}
@@ -0,0 +1,724 @@
+// Tone control and parametric equalizer.
+// A cascade of fixed point biquads designed with the formulas of the audio eq cookbook.
+// A stage takes 10 single cycle multiplies per sample, all 5 stages at 44.1khz stereo come to
+// roughly 20M of the 133M cycles a second. Flat bands are left out and cost nothing
+
+use crate::file_reader::{Library, SdError};
+use crate::frame::StereoFrame;
+use crate::resample::sin_pi_q30;
+use crate::settings::{Fixed, parse_fixed};
+use crate::volume::{ONE_Q15, centibels_to_q15};
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use core::str::{FromStr, SplitAsciiWhitespace};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use defmt::{Format, info, warn};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use heapless::{String, Vec};
+
+// Parametric bands on top of the bass and treble shelves
+pub const BANDS: usize = 3;
+pub const MAX_PRESETS: usize = 8;
+pub const MAX_PRESET_NAME: usize = 12;
+// Largest boost or cut of a band
+pub const MAX_GAIN_CB: i16 = 120;
+const BASS_HZ: u16 = 100;
+const TREBLE_HZ: u16 = 10_000;
+// A Q of 1/sqrt(2) is the steepest shelf without a bump
+const SHELF_Q_X100: u16 = 71;
+const MAX_STAGES: usize = BANDS + 2;
+
+// Fractional bits of the filter coefficients. Low bands need far more precision than a 16x16 bit
+// multiply gives, so every coefficient is split into a coarse part and the fine bits below it
+const COARSE_BITS: u32 = 14;
+const FINE_BITS: u32 = 13;
+const COEFF_BITS: u32 = COARSE_BITS + FINE_BITS;
+const ONE_Q28: i64 = 1 << 28;
+
+const PRESETS_FILE: &str = "EQ.TXT";
+const PRESETS_FILE_LEN: usize = 1024;
+// Explains the presets file to anyone editing it
+const PRESETS_HEADER: &str = "\
+# name, bass and treble in dB, then frequency, gain in dB and Q of every band.
+# The preset marked with * is used
+";
+// Written to the card when it has no presets yet
+const DEFAULT_PRESETS: &str = "\
+*flat 0.0 0.0 250 0.0 1.00 1000 0.0 1.00 4000 0.0 1.00
+bass 6.0 0.0 250 0.0 1.00 1000 0.0 1.00 4000 0.0 1.00
+treble 0.0 6.0 250 0.0 1.00 1000 0.0 1.00 4000 0.0 1.00
+loudness 6.0 4.0 250 0.0 1.00 1000 -2.0 0.70 4000 0.0 1.00
+vocal -2.0 0.0 250 -2.0 1.00 1000 2.0 0.80 3000 3.0 1.00
+";
+
+// The presets shared between the ui and playback
+pub static EQ: EqSettings = EqSettings::new();
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct Band {
+    pub freq: u16,
+    // boost or cut in centibels
+    pub gain_cb: i16,
+    // Q times 100, higher is narrower
+    pub q_x100: u16,
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct Tone {
+    pub bass_cb: i16,
+    pub treble_cb: i16,
+    pub bands: [Band; BANDS],
+}
+
+impl Tone {
+    pub const FLAT: Self = Self {
+        bass_cb: 0,
+        treble_cb: 0,
+        bands: [Band {
+            freq: 1000,
+            gain_cb: 0,
+            q_x100: 100,
+        }; BANDS],
+    };
+}
+
+#[derive(Debug, Format)]
+pub struct Preset {
+    pub name: String<MAX_PRESET_NAME>,
+    pub tone: Tone,
+}
+
+pub struct EqSettings {
+    presets: Mutex<CriticalSectionRawMutex, RefCell<Vec<Preset, MAX_PRESETS>>>,
+    active: AtomicU8,
+    // the presets file is out of date
+    changed: AtomicBool,
+    // the presets file could not be read, the defaults in its place are never written over it
+    unread: AtomicBool,
+}
+
+impl EqSettings {
+    const fn new() -> Self {
+        Self {
+            presets: Mutex::new(RefCell::new(Vec::new())),
+            active: AtomicU8::new(0),
+            changed: AtomicBool::new(false),
+            unread: AtomicBool::new(false),
+        }
+    }
+
+    // The settings of the selected preset, flat until the presets are loaded
+    pub fn tone(&self) -> Tone {
+        let active = self.active();
+        self.presets.lock(|presets| {
+            presets
+                .borrow()
+                .get(active)
+                .map_or(Tone::FLAT, |preset| preset.tone)
+        })
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed) as usize
+    }
+
+    pub fn count(&self) -> usize {
+        self.presets.lock(|presets| presets.borrow().len())
+    }
+
+    pub fn select(&self, preset: usize) {
+        if preset < self.count() && preset != self.active() {
+            self.active.store(preset as u8, Ordering::Relaxed);
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Reads the presets from the card, or puts the default ones there when it has none
+    pub async fn load(&self, library: &Library<'_>) {
+        let mut buf = [0u8; PRESETS_FILE_LEN];
+        let text = match library.read_file(PRESETS_FILE, &mut buf).await {
+            Ok(len) => str::from_utf8(&buf[..len]).unwrap_or(""),
+            Err(SdError::NotFound) => {
+                warn!("[EQ] no presets, writing the defaults");
+                self.changed.store(true, Ordering::Relaxed);
+                DEFAULT_PRESETS
+            }
+            // the presets on the card are kept, they are only missing until the next boot
+            Err(e) => {
+                warn!(
+                    "[EQ] cannot read {}, using the defaults: {}",
+                    PRESETS_FILE, e
+                );
+                self.unread.store(true, Ordering::Relaxed);
+                DEFAULT_PRESETS
+            }
+        };
+
+        let (mut presets, mut active) = parse_presets(text);
+        if presets.is_empty() {
+            (presets, active) = parse_presets(DEFAULT_PRESETS);
+        }
+        info!(
+            "[EQ] {} presets, using {}",
+            presets.len(),
+            presets[active].name
+        );
+        self.presets.lock(|cell| *cell.borrow_mut() = presets);
+        self.active.store(active as u8, Ordering::Relaxed);
+
+        self.save(library).await;
+    }
+
+    // Whether the selection changed since the presets were last written, they are never written
+    // over when the card could not be read
+    pub fn is_changed(&self) -> bool {
+        self.changed.load(Ordering::Relaxed) && !self.unread.load(Ordering::Relaxed)
+    }
+
+    // Writes the presets to the card if the selection changed since they were last written
+    pub async fn save(&self, library: &Library<'_>) {
+        if !self.is_changed() {
+            return;
+        }
+        self.changed.store(false, Ordering::Relaxed);
+
+        let mut text: String<PRESETS_FILE_LEN> = String::new();
+        let active = self.active();
+        let written = self.presets.lock(|presets| {
+            text.push_str(PRESETS_HEADER).map_err(|_| fmt::Error)?;
+            presets
+                .borrow()
+                .iter()
+                .enumerate()
+                .try_for_each(|(i, preset)| write_preset(&mut text, preset, i == active))
+        });
+        if written.is_err() {
+            warn!("[EQ] presets do not fit in {}", PRESETS_FILE);
+            return;
+        }
+        if let Err(e) = library.write_file(PRESETS_FILE, text.as_bytes()).await {
+            warn!("[EQ] cannot write {}: {}", PRESETS_FILE, e);
+        }
+    }
+}
+
+// The presets in a presets file and which of them is selected
+fn parse_presets(text: &str) -> (Vec<Preset, MAX_PRESETS>, usize) {
+    let mut presets = Vec::new();
+    let mut active = 0;
+    for line in text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        let (selected, line) = match line.strip_prefix('*') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let Some(preset) = parse_preset(line) else {
+            warn!("[EQ] skipping preset: {}", line);
+            continue;
+        };
+        if selected {
+            active = presets.len();
+        }
+        if presets.push(preset).is_err() {
+            warn!("[EQ] too many presets, increase MAX_PRESETS");
+            break;
+        }
+    }
+    let active = active.min(presets.len().saturating_sub(1));
+    (presets, active)
+}
+
+// `name bass treble` and then `frequency gain q` for every band
+fn parse_preset(line: &str) -> Option<Preset> {
+    fn gain(fields: &mut SplitAsciiWhitespace) -> Option<i16> {
+        let cb = parse_fixed(fields.next()?.as_bytes(), 1)?;
+        Some(cb.clamp(-MAX_GAIN_CB as i32, MAX_GAIN_CB as i32) as i16)
+    }
+
+    let mut fields = line.split_ascii_whitespace();
+    let name = String::from_str(fields.next()?).ok()?;
+    let bass_cb = gain(&mut fields)?;
+    let treble_cb = gain(&mut fields)?;
+    let mut bands = Tone::FLAT.bands;
+    for band in bands.iter_mut() {
+        let freq = parse_fixed(fields.next()?.as_bytes(), 0)?;
+        band.freq = freq.clamp(20, 20_000) as u16;
+        band.gain_cb = gain(&mut fields)?;
+        let q = parse_fixed(fields.next()?.as_bytes(), 2)?;
+        band.q_x100 = q.clamp(10, 1000) as u16;
+    }
+    Some(Preset {
+        name,
+        tone: Tone {
+            bass_cb,
+            treble_cb,
+            bands,
+        },
+    })
+}
+
+fn write_preset(out: &mut impl Write, preset: &Preset, active: bool) -> fmt::Result {
+    let tone = &preset.tone;
+    write!(
+        out,
+        "{}{} {} {}",
+        if active { "*" } else { "" },
+        preset.name,
+        Fixed(tone.bass_cb as i32, 1),
+        Fixed(tone.treble_cb as i32, 1)
+    )?;
+    for band in &tone.bands {
+        write!(
+            out,
+            " {} {} {}",
+            band.freq,
+            Fixed(band.gain_cb as i32, 1),
+            Fixed(band.q_x100 as i32, 2)
+        )?;
+    }
+    writeln!(out)
+}
+
+// The filters of the selected preset for one stream of dac frames
+pub struct Equalizer {
+    rate: u32,
+    tone: Tone,
+    stages: Vec<Biquad, MAX_STAGES>,
+}
+
+impl Equalizer {
+    pub fn new(rate: u32) -> Self {
+        let mut eq = Self {
+            rate,
+            tone: Tone::FLAT,
+            stages: Vec::new(),
+        };
+        eq.set_tone(EQ.tone());
+        eq
+    }
+
+    // Picks up a different preset from the ui
+    pub fn update(&mut self) {
+        let tone = EQ.tone();
+        if tone != self.tone {
+            self.set_tone(tone);
+        }
+    }
+
+    pub fn set_tone(&mut self, tone: Tone) {
+        self.tone = tone;
+        self.stages.clear();
+
+        let shelves = [
+            (
+                Kind::LowShelf,
+                Band {
+                    freq: BASS_HZ,
+                    gain_cb: tone.bass_cb,
+                    q_x100: SHELF_Q_X100,
+                },
+            ),
+            (
+                Kind::HighShelf,
+                Band {
+                    freq: TREBLE_HZ,
+                    gain_cb: tone.treble_cb,
+                    q_x100: SHELF_Q_X100,
+                },
+            ),
+        ];
+        let peaks = tone.bands.map(|band| (Kind::Peak, band));
+        // turn the input down by the largest boost, so a full scale sine comes out of the band
+        // boosting it most at full scale. Where boosted bands overlap the limiter catches the rest.
+        // Folded into the first stage so it costs nothing
+        let boost = shelves
+            .iter()
+            .chain(&peaks)
+            .map(|(_, band)| band.gain_cb.max(0) as i32)
+            .max()
+            .unwrap_or(0);
+        let mut headroom = centibels_to_q15(-boost);
+        for (kind, band) in shelves.into_iter().chain(peaks) {
+            if band.gain_cb == 0 {
+                continue;
+            }
+            let mut coefficients = design(kind, band, self.rate);
+            for b in &mut coefficients[..3] {
+                *b = ((*b as i64 * headroom as i64 + ONE_Q15 as i64 / 2) >> 15) as i32;
+            }
+            headroom = ONE_Q15;
+            // there is room for every band
+            let _ = self.stages.push(Biquad::new(coefficients));
+        }
+    }
+
+    pub fn process(&mut self, frames: &mut [StereoFrame]) {
+        if self.stages.is_empty() {
+            return;
+        }
+        for frame in frames.iter_mut() {
+            let (mut left, mut right) = (frame.left(), frame.right());
+            for stage in self.stages.iter_mut() {
+                left = stage.process(0, left);
+                right = stage.process(1, right);
+            }
+            *frame = StereoFrame::new(left, right);
+        }
+    }
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    LowShelf,
+    HighShelf,
+    Peak,
+}
+
+// A second order section in direct form 1. The rounding errors of each output are carried into
+// the next one, which keeps the noise of the low bands down
+pub struct Biquad {
+    // b0, b1, b2, -a1 and -a2 normalized by a0, split into the upper bits in Q14
+    // and the `FINE_BITS` below them
+    coarse: [i32; 5],
+    fine: [i32; 5],
+    // the last two inputs and outputs of each channel
+    x: [[i32; 2]; 2],
+    y: [[i32; 2]; 2],
+    error: [[i32; 2]; 2],
+}
+
+impl Biquad {
+    // `coefficients` are b0, b1, b2, a1 and a2 from `design`
+    pub fn new(coefficients: [i32; 5]) -> Self {
+        let [b0, b1, b2, a1, a2] = coefficients;
+        let coefficients = [b0, b1, b2, -a1, -a2];
+        Self {
+            coarse: coefficients.map(|c| c >> FINE_BITS),
+            fine: coefficients.map(|c| c & ((1 << FINE_BITS) - 1)),
+            x: [[0; 2]; 2],
+            y: [[0; 2]; 2],
+            error: [[0; 2]; 2],
+        }
+    }
+
+    pub fn process(&mut self, channel: usize, sample: i16) -> i16 {
+        let [x1, x2] = self.x[channel];
+        let [y1, y2] = self.y[channel];
+        let x0 = sample as i32;
+        let history = [x0, x1, x2, y1, y2];
+
+        // the fine products stay below 2^28, so their sum cannot overflow.
+        // The coarse terms may wrap on their own, the sum is in range as long as the output is
+        let [mut coarse, mut fine] = self.error[channel];
+        for ((c, f), v) in self.coarse.iter().zip(&self.fine).zip(history) {
+            coarse = coarse.wrapping_add(c.wrapping_mul(v));
+            fine += f * v;
+        }
+        coarse = coarse.wrapping_add(fine >> FINE_BITS);
+        let y0 = (coarse >> COARSE_BITS).clamp(i16::MIN as i32, i16::MAX as i32);
+        self.error[channel] = [
+            coarse & ((1 << COARSE_BITS) - 1),
+            fine & ((1 << FINE_BITS) - 1),
+        ];
+
+        self.x[channel] = [x0, x1];
+        self.y[channel] = [y0, y1];
+        y0 as i16
+    }
+}
+
+// Cookbook coefficients b0, b1, b2, a1 and a2 of a band at `rate`, normalized by a0 in Q27
+pub fn design(kind: Kind, band: Band, rate: u32) -> [i32; 5] {
+    let mul = |x: i64, y: i64| x * y / ONE_Q28;
+
+    // keep the band clear of the nyquist frequency
+    let freq = (band.freq as i64).min(rate as i64 * 9 / 20);
+    // w0 / pi in Q30
+    let w = 2 * freq * (1 << 30) / rate as i64;
+    // cos(w0) of a low band is too close to 1 for `cos_pi_q30`, 1 - 2sin(w0/2)^2 keeps its precision
+    let half = sin_pi_q30(w / 2) >> 2;
+    let cos = ONE_Q28 - 2 * mul(half, half);
+    let sin = sin_pi_q30(w) >> 2;
+    // A = 10^(gain / 40), the square root of the gain at the centre of the band
+    let a = amplitude_q28(band.gain_cb);
+    // sin(w0) / 2Q
+    let alpha = sin * 50 / band.q_x100.max(1) as i64;
+
+    let one = ONE_Q28;
+    let [b0, b1, b2, a0, a1, a2] = match kind {
+        Kind::Peak => {
+            let (alpha_a, alpha_over_a) = (mul(alpha, a), alpha * ONE_Q28 / a);
+            [
+                one + alpha_a,
+                -2 * cos,
+                one - alpha_a,
+                one + alpha_over_a,
+                -2 * cos,
+                one - alpha_over_a,
+            ]
+        }
+        Kind::LowShelf | Kind::HighShelf => {
+            // the high shelf is the low shelf mirrored around half the sample rate
+            let sign = if kind == Kind::LowShelf { 1 } else { -1 };
+            let cos = sign * cos;
+            let s = 2 * mul((a * ONE_Q28).isqrt(), alpha);
+            let (ap1, am1) = (a + one, a - one);
+            [
+                mul(a, ap1 - mul(am1, cos) + s),
+                sign * 2 * mul(a, am1 - mul(ap1, cos)),
+                mul(a, ap1 - mul(am1, cos) - s),
+                ap1 + mul(am1, cos) + s,
+                -sign * 2 * (am1 + mul(ap1, cos)),
+                ap1 + mul(am1, cos) - s,
+            ]
+        }
+    };
+
+    [b0, b1, b2, a1, a2].map(|c| {
+        let c = c << COEFF_BITS;
+        ((c + c.signum() * a0 / 2) / a0) as i32
+    })
+}
+
+// 10^(cb / 400) in Q28, the A of the cookbook. `centibels_to_q15` is too coarse for it,
+// the shelves reach A squared at the ends of the band
+fn amplitude_q28(gain_cb: i16) -> i64 {
+    const LOG2_10_Q28: i64 = 891_723_283;
+    const LN_2_Q28: i64 = 186_065_280;
+    // 2^x, the whole powers are shifts
+    let x = gain_cb as i64 * LOG2_10_Q28 / 400;
+    let (whole, fraction) = (x.div_euclid(ONE_Q28), x.rem_euclid(ONE_Q28));
+    // taylor series of e^(fraction * ln 2)
+    let y = fraction * LN_2_Q28 / ONE_Q28;
+    let (mut term, mut sum) = (ONE_Q28, ONE_Q28);
+    for divisor in 1..=10 {
+        term = term * y / ONE_Q28 / divisor;
+        sum += term;
+    }
+    if whole >= 0 {
+        sum << whole
+    } else {
+        sum >> -whole
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 44_100;
+
+    // Gain in dB of a stage for a sine at `freq`, leaving out the filter settling at the start
+    fn gain_db(stage: &mut Biquad, freq: f64) -> f64 {
+        let amplitude = 8_000.0;
+        let (mut power_in, mut power_out) = (0.0, 0.0);
+        for n in 0..RATE {
+            let t = n as f64 / RATE as f64;
+            let x = (amplitude * (2.0 * core::f64::consts::PI * freq * t).sin()) as i16;
+            let y = stage.process(0, x);
+            if n >= RATE / 4 {
+                power_in += (x as f64).powi(2);
+                power_out += (y as f64).powi(2);
+            }
+        }
+        10.0 * (power_out / power_in).log10()
+    }
+
+    #[test]
+    fn peak_has_its_gain_at_the_centre_frequency() {
+        for freq in [100, 1000, 8000] {
+            for gain_cb in [-120, -30, 60, 120] {
+                let band = Band {
+                    freq,
+                    gain_cb,
+                    q_x100: 100,
+                };
+                let mut stage = Biquad::new(design(Kind::Peak, band, RATE));
+                let gain = gain_db(&mut stage, freq as f64);
+                assert!(
+                    (gain - gain_cb as f64 / 10.0).abs() < 0.1,
+                    "{freq} Hz {gain_cb} cB: {gain} dB"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn peak_leaves_frequencies_far_from_it_alone() {
+        let band = Band {
+            freq: 1000,
+            gain_cb: 120,
+            q_x100: 200,
+        };
+        let mut stage = Biquad::new(design(Kind::Peak, band, RATE));
+        assert!(gain_db(&mut stage, 15_000.0).abs() < 0.1);
+    }
+
+    // The cookbook formulas in floating point, b0, b1, b2, a1 and a2 normalized by a0
+    fn reference(kind: Kind, band: Band, rate: u32) -> [f64; 5] {
+        let w0 = 2.0 * core::f64::consts::PI * band.freq as f64 / rate as f64;
+        let (sin, cos) = w0.sin_cos();
+        let a = 10f64.powf(band.gain_cb as f64 / 400.0);
+        let alpha = sin / (2.0 * band.q_x100 as f64 / 100.0);
+        let [b0, b1, b2, a0, a1, a2] = match kind {
+            Kind::Peak => [
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ],
+            Kind::LowShelf => {
+                let s = 2.0 * a.sqrt() * alpha;
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos + s),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - s),
+                    (a + 1.0) + (a - 1.0) * cos + s,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - s,
+                ]
+            }
+            Kind::HighShelf => {
+                let s = 2.0 * a.sqrt() * alpha;
+                [
+                    a * ((a + 1.0) + (a - 1.0) * cos + s),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - s),
+                    (a + 1.0) - (a - 1.0) * cos + s,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - s,
+                ]
+            }
+        };
+        [b0, b1, b2, a1, a2].map(|c| c / a0)
+    }
+
+    fn to_f64(coefficients: [i32; 5]) -> [f64; 5] {
+        coefficients.map(|c| c as f64 / (1 << COEFF_BITS) as f64)
+    }
+
+    #[test]
+    fn design_matches_the_cookbook() {
+        for kind in [Kind::LowShelf, Kind::HighShelf, Kind::Peak] {
+            for freq in [40, 100, 1000, 10_000, 18_000] {
+                for gain_cb in [-120, -35, 20, 120] {
+                    for q_x100 in [50, 71, 200] {
+                        let band = Band {
+                            freq,
+                            gain_cb,
+                            q_x100,
+                        };
+                        let expected = reference(kind, band, RATE);
+                        let actual = to_f64(design(kind, band, RATE));
+                        for (i, (actual, expected)) in actual.iter().zip(expected).enumerate() {
+                            assert!(
+                                (actual - expected).abs() < 2e-5,
+                                "{kind:?} {band:?} coefficient {i}: {actual} != {expected}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Gain in dB at DC and at the nyquist frequency, from the transfer function at z = 1 and -1
+    fn dc_and_nyquist_db(coefficients: [i32; 5]) -> (f64, f64) {
+        let [b0, b1, b2, a1, a2] = to_f64(coefficients);
+        let db = |x: f64| 20.0 * x.abs().log10();
+        (
+            db((b0 + b1 + b2) / (1.0 + a1 + a2)),
+            db((b0 - b1 + b2) / (1.0 - a1 + a2)),
+        )
+    }
+
+    #[test]
+    fn shelves_have_their_gain_at_one_end_and_none_at_the_other() {
+        for gain_cb in [-120, -60, 30, 120] {
+            let gain = gain_cb as f64 / 10.0;
+            let bass = Band {
+                freq: BASS_HZ,
+                gain_cb,
+                q_x100: SHELF_Q_X100,
+            };
+            let (dc, nyquist) = dc_and_nyquist_db(design(Kind::LowShelf, bass, RATE));
+            assert!((dc - gain).abs() < 0.01, "bass {gain_cb} cB: {dc} dB at dc");
+            assert!(
+                nyquist.abs() < 0.01,
+                "bass {gain_cb} cB: {nyquist} dB at nyquist"
+            );
+
+            let treble = Band {
+                freq: TREBLE_HZ,
+                ..bass
+            };
+            let (dc, nyquist) = dc_and_nyquist_db(design(Kind::HighShelf, treble, RATE));
+            assert!(dc.abs() < 0.01, "treble {gain_cb} cB: {dc} dB at dc");
+            assert!(
+                (nyquist - gain).abs() < 0.01,
+                "treble {gain_cb} cB: {nyquist} dB at nyquist"
+            );
+        }
+    }
+
+    #[test]
+    fn the_input_is_turned_down_by_the_largest_boost() {
+        let mut tone = Tone::FLAT;
+        tone.bass_cb = 60;
+        tone.bands[1].gain_cb = 30;
+        tone.bands[2].gain_cb = -60;
+        let mut eq = Equalizer {
+            rate: RATE,
+            tone: Tone::FLAT,
+            stages: Vec::new(),
+        };
+        eq.set_tone(tone);
+        assert_eq!(eq.stages.len(), 3);
+
+        // the bass shelf comes first and carries the headroom, -6dB that its +6dB at dc undoes
+        let stage = &eq.stages[0];
+        let [b0, b1, b2, a1, a2] = core::array::from_fn(|i| {
+            let c = (stage.coarse[i] << FINE_BITS) + stage.fine[i];
+            // `Biquad` keeps -a1 and -a2
+            if i < 3 { c } else { -c }
+        });
+        let (dc, _) = dc_and_nyquist_db([b0, b1, b2, a1, a2]);
+        assert!(dc.abs() < 0.05, "{dc} dB at dc");
+    }
+
+    #[test]
+    fn flat_presets_are_an_identity() {
+        let (presets, active) = parse_presets(DEFAULT_PRESETS);
+        let flat = &presets[active];
+        assert_eq!(flat.name.as_str(), "flat");
+
+        let mut eq = Equalizer {
+            rate: RATE,
+            tone: Tone::FLAT,
+            stages: Vec::new(),
+        };
+        eq.set_tone(flat.tone);
+        let input: [StereoFrame; 256] = core::array::from_fn(|n| {
+            let sample = (n as i32 * 257 - 32_768) as i16;
+            StereoFrame::new(sample, !sample)
+        });
+        let mut frames = input;
+        eq.process(&mut frames);
+        assert_eq!(frames, input);
+    }
+
+    #[test]
+    fn unity_biquad_is_an_identity() {
+        let mut stage = Biquad::new([1 << COEFF_BITS, 0, 0, 0, 0]);
+        for n in 0..=u16::MAX {
+            let sample = n as i16;
+            assert_eq!(stage.process(n as usize & 1, sample), sample);
+        }
+    }
+}
@@ -1,4 +1,9 @@
-use core::str::FromStr;
+use crate::decoder::read_all;
+use crate::index::{
+    Entry, INDEX_FILES, Index, IndexFiles, IndexWriter, Level, NAME_FILES, ShortName,
+};
+use core::fmt::Write;
+use core::ops::Range;
 use defmt::{Format, info, warn};
 use embassy_rp::{
     gpio::Output,
@@ -7,27 +12,24 @@ use embassy_rp::{
 };
 use embedded_hal_bus::spi::ExclusiveDevice;
 use embedded_sdmmc::asynchronous::{
-    DirEntry, Directory, LfnBuffer, SdCard, ShortFileName, TimeSource, Timestamp, Volume,
+    DirEntry, Directory, Error, File, LfnBuffer, Mode, SdCard, SdCardError, ShortFileName,
+    TimeSource, Timestamp, Volume,
 };
 use heapless::{String, Vec};
 
+// Dirs and files open at a time
 pub const MAX_DIRS: usize = 4;
 pub const MAX_FILES: usize = 5;
 pub const MAX_VOLUMES: usize = 1;
-// Max file or dir name string len
-pub const MAX_NAME_LEN: usize = 25;
-
-#[derive(Debug, Format)]
-pub struct Album {
-    pub name: String<MAX_NAME_LEN>,
-    pub songs: Vec<String<MAX_NAME_LEN>, MAX_FILES>,
-}
-
-#[derive(Debug, Format)]
-pub struct Artist {
-    pub name: String<MAX_NAME_LEN>,
-    pub albums: Vec<Album, MAX_DIRS>,
-}
+// Longest file or dir name in utf-8, 255 utf-16 units of up to 3 bytes each
+pub const MAX_NAME_LEN: usize = 255 * 3;
+// Entries of a dir gathered before the dirs among them are opened, and room for their names.
+// A batch ends early when the next name does not fit, at least one always does. Every batch
+// reads the dir again, so they hold the albums of an artist or the songs of an album in one go
+// and only a large root dir takes a few. The scan is done before anything plays, the memory of
+// the batches is used by the decoders after it
+const BATCH_LEN: usize = 128;
+const BATCH_NAMES_LEN: usize = 4096;
 
 pub struct DummyTimeSource {}
 impl TimeSource for DummyTimeSource {
@@ -38,136 +40,544 @@ impl TimeSource for DummyTimeSource {
 
 type Device = ExclusiveDevice<Spi<'static, SPI0, spi::Async>, Output<'static>, embassy_time::Delay>;
 pub type SD = SdCard<Device, embassy_time::Delay>;
+pub type SdError = Error<SdCardError>;
 type Dir<'a> = Directory<'a, SD, DummyTimeSource, MAX_DIRS, MAX_FILES, MAX_VOLUMES>;
+pub type SdFile<'a> = File<'a, SD, DummyTimeSource, MAX_DIRS, MAX_FILES, MAX_VOLUMES>;
 
 pub struct Library<'a> {
     volume: Volume<'a, SD, DummyTimeSource, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
-    artists: Option<Vec<Artist, MAX_DIRS>>,
+    index: Index,
 }
 
 impl<'a> Library<'a> {
     pub fn new(volume: Volume<'a, SD, DummyTimeSource, MAX_DIRS, MAX_FILES, MAX_VOLUMES>) -> Self {
         Self {
-            artists: None,
             volume,
+            index: Index::default(),
         }
     }
 
-    pub fn get_root_dir(&self) -> Dir {
-        self.volume.open_root_dir().unwrap()
+    // Indexes the artist dirs in the root, the album dirs in them and the songs in those.
+    // Albums whose dir did not change are taken from the last index instead of being scanned.
+    // The last index stays in use when the next one cannot be written
+    pub async fn discover_music(&mut self) {
+        let old = Index::load(self).await;
+        self.index = match self.write_index(old.as_ref()).await {
+            Ok(index) => index,
+            Err(e) => {
+                warn!("[LIBRARY] cannot write the index: {}", e);
+                old.unwrap_or_default()
+            }
+        };
     }
 
-    pub async fn discover_music(&mut self) {
-        let root_dir = self.volume.open_root_dir().unwrap();
+    async fn write_index(&self, old: Option<&Index>) -> Result<Index, SdError> {
+        let slot = IndexWriter::slot(old);
+        let root_dir = self.volume.open_root_dir()?;
+        let mut old_files = match old {
+            Some(old) => match open_index_files(&root_dir, old).await {
+                Ok(files) => Some(files),
+                Err(e) => {
+                    warn!(
+                        "[LIBRARY] cannot open the last index, scanning everything: {}",
+                        e
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+        let written = match root_dir
+            .open_file_in_dir(INDEX_FILES[slot], Mode::ReadWriteCreateOrTruncate)
+            .await
+        {
+            Ok(index) => match root_dir
+                .open_file_in_dir(NAME_FILES[slot], Mode::ReadWriteCreateOrTruncate)
+                .await
+            {
+                Ok(names) => {
+                    let mut scan = Scan {
+                        old: old.zip(old_files.as_mut()),
+                        index: IndexWriter::new(index, names, old),
+                        next_old_artist: 0,
+                    };
+                    let written = scan.write_sections(&root_dir).await;
+                    scan.index.finish(written).await
+                }
+                Err(e) => {
+                    index.close().await?;
+                    Err(e)
+                }
+            },
+            Err(e) => Err(e),
+        };
+        if let Some(files) = old_files {
+            files.close().await?;
+        }
+        root_dir.close()?;
+        written
+    }
 
-        let mut artist_names: Vec<String<MAX_NAME_LEN>, MAX_FILES> = Vec::new();
+    // Number of artists, albums or songs
+    pub fn len(&self, level: Level) -> u32 {
+        self.index.len(level)
+    }
 
-        let mut buf = [0u8; MAX_NAME_LEN];
-        let mut lfn_buffer = LfnBuffer::new(&mut buf);
-        root_dir
-            .iterate_dir_lfn(&mut lfn_buffer, |entry, lfn| {
-                if !artist_names.is_full() {
-                    if entry.attributes.is_directory() && !ignore_name(&entry.name) {
-                        artist_names.push(get_name(entry, lfn)).unwrap()
+    // An artist, album or song by its position in the index
+    pub async fn entry(&self, level: Level, record: u32) -> Option<Entry> {
+        self.index.entry(self, level, record).await
+    }
+
+    // The name of an entry, cut short on a character boundary when it does not fit `buf`
+    pub async fn name<'b>(&self, entry: &Entry, buf: &'b mut [u8]) -> Option<&'b str> {
+        self.index.name(self, entry, buf).await
+    }
+
+    // Opens a song for reading by the 8.3 names of its dirs and file, so any long name works.
+    // The file stays open on its own, so the dirs leading to it are closed again straight away
+    pub async fn open_song(
+        &self,
+        artist: &ShortName,
+        album: &ShortName,
+        song: &ShortName,
+    ) -> Result<SdFile, SdError> {
+        let root_dir = self.volume.open_root_dir()?;
+        let file = match root_dir.open_dir(artist.to_str().as_str()).await {
+            Ok(artist_dir) => {
+                let file = match artist_dir.open_dir(album.to_str().as_str()).await {
+                    Ok(album_dir) => {
+                        let file = album_dir
+                            .open_file_in_dir(song.to_str().as_str(), Mode::ReadOnly)
+                            .await;
+                        album_dir.close()?;
+                        file
                     }
-                } else {
-                    warn!("Too many songs in album. increase MAX_DIRS");
-                }
-            })
+                    Err(e) => Err(e),
+                };
+                artist_dir.close()?;
+                file
+            }
+            Err(e) => Err(e),
+        };
+        root_dir.close()?;
+        file
+    }
+
+    // Reads a file in the root dir, like a settings file, into `buf`.
+    // Returns how much of `buf` was filled
+    pub async fn read_file(&self, name: &str, buf: &mut [u8]) -> Result<usize, SdError> {
+        self.read_file_at(name, 0, buf).await
+    }
+
+    // Reads a file in the root dir from `offset` on
+    pub async fn read_file_at(
+        &self,
+        name: &str,
+        offset: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, SdError> {
+        let root_dir = self.volume.open_root_dir()?;
+        let read = match root_dir.open_file_in_dir(name, Mode::ReadOnly).await {
+            Ok(mut file) => {
+                let read = read_at(&mut file, offset, buf).await;
+                file.close().await?;
+                read
+            }
+            Err(e) => Err(e),
+        };
+        root_dir.close()?;
+        read
+    }
+
+    // Replaces the contents of a file in the root dir, creating it if needed
+    pub async fn write_file(&self, name: &str, data: &[u8]) -> Result<(), SdError> {
+        let root_dir = self.volume.open_root_dir()?;
+        let written = match root_dir
+            .open_file_in_dir(name, Mode::ReadWriteCreateOrTruncate)
             .await
-            .unwrap();
+        {
+            Ok(mut file) => {
+                let written = file.write(data).await;
+                file.close().await?;
+                written
+            }
+            Err(e) => Err(e),
+        };
+        root_dir.close()?;
+        written
+    }
+}
+
+// Reads an open file from `offset` on, returns how much of `buf` was filled
+pub async fn read_at(file: &mut SdFile<'_>, offset: u32, buf: &mut [u8]) -> Result<usize, SdError> {
+    file.seek_from_start(offset)?;
+    read_all(file, buf).await
+}
+
+// The files of the last index, open for the whole scan so unchanged albums are copied quickly
+async fn open_index_files<'a>(root_dir: &Dir<'a>, old: &Index) -> Result<IndexFiles<'a>, SdError> {
+    let [index, names] = old.files();
+    let index = root_dir.open_file_in_dir(index, Mode::ReadOnly).await?;
+    match root_dir.open_file_in_dir(names, Mode::ReadOnly).await {
+        Ok(names) => Ok(IndexFiles::new(index, names)),
+        Err(e) => {
+            index.close().await?;
+            Err(e)
+        }
+    }
+}
 
-        let mut artists = Vec::new();
+// Writes the artists, then the albums and then the songs. Each section walks the dirs again,
+// so the number of records and where the children of every record start are known up front
+struct Scan<'s, 'a> {
+    // the last index and its open files
+    old: Option<(&'s Index, &'s mut IndexFiles<'a>)>,
+    index: IndexWriter<'a>,
+    // where the next artist is looked for in the old index
+    next_old_artist: u32,
+}
 
-        for artist in artist_names {
-            let artist_dir = root_dir.open_dir(artist.as_str()).await.unwrap();
-            artists
-                .push(Artist {
-                    albums: get_artist(&artist_dir).await,
-                    name: artist,
-                })
-                .unwrap()
+impl Scan<'_, '_> {
+    async fn write_sections(&mut self, root_dir: &Dir<'_>) -> Result<(), SdError> {
+        self.index
+            .section(Level::Artists, count_entries(root_dir, Kind::Dir).await?)
+            .await?;
+        let mut albums = 0;
+        let mut artists = Batches::new(Kind::Dir);
+        while let Some(batch) = artists.next(root_dir).await? {
+            for artist in &batch.found {
+                let artist_dir = root_dir.open_dir(&artist.short_name).await?;
+                let count = count_entries(&artist_dir, Kind::Dir).await;
+                artist_dir.close()?;
+                let count = count?;
+                self.index
+                    .entry(&artist.entry(0, albums, count), batch.name(artist))
+                    .await?;
+                albums += count;
+            }
         }
 
-        root_dir.close().unwrap();
+        self.index.section(Level::Albums, albums).await?;
+        let mut songs = 0;
+        let mut artist_index = 0;
+        let mut artists = Batches::new(Kind::Dir);
+        self.next_old_artist = 0;
+        while let Some(batch) = artists.next(root_dir).await? {
+            for artist in &batch.found {
+                let old_artist = self.old_artist(artist).await;
+                let artist_dir = root_dir.open_dir(&artist.short_name).await?;
+                let written = self
+                    .write_albums(&artist_dir, old_artist.as_ref(), artist_index, &mut songs)
+                    .await;
+                artist_dir.close()?;
+                written?;
+                artist_index += 1;
+            }
+        }
 
-        self.artists = Some(artists)
+        self.index.section(Level::Songs, songs).await?;
+        let mut album_index = 0;
+        let mut artists = Batches::new(Kind::Dir);
+        self.next_old_artist = 0;
+        while let Some(batch) = artists.next(root_dir).await? {
+            for artist in &batch.found {
+                let old_artist = self.old_artist(artist).await;
+                let artist_dir = root_dir.open_dir(&artist.short_name).await?;
+                let written = self
+                    .write_songs(&artist_dir, old_artist.as_ref(), &mut album_index)
+                    .await;
+                artist_dir.close()?;
+                written?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn artists(&self) -> Option<&Vec<Artist, MAX_DIRS>> {
-        self.artists.as_ref()
+    // The album records of an artist, `songs` counts the songs of the albums before them.
+    // Only the albums that changed are opened to count their songs
+    async fn write_albums(
+        &mut self,
+        artist_dir: &Dir<'_>,
+        old_artist: Option<&Entry>,
+        artist: u32,
+        songs: &mut u32,
+    ) -> Result<(), SdError> {
+        let mut albums = Batches::new(Kind::Dir);
+        while let Some(batch) = albums.next(artist_dir).await? {
+            for album in &batch.found {
+                let count = match self.unchanged_album(old_artist, album).await {
+                    Some(old_album) => old_album.count,
+                    None => {
+                        let album_dir = artist_dir.open_dir(&album.short_name).await?;
+                        let count = count_entries(&album_dir, Kind::File).await;
+                        album_dir.close()?;
+                        count?
+                    }
+                };
+                self.index
+                    .entry(&album.entry(artist, *songs, count), batch.name(album))
+                    .await?;
+                *songs += count;
+            }
+        }
+        Ok(())
+    }
+
+    // The song records of the albums of an artist, `album` counts the albums before them
+    async fn write_songs(
+        &mut self,
+        artist_dir: &Dir<'_>,
+        old_artist: Option<&Entry>,
+        album: &mut u32,
+    ) -> Result<(), SdError> {
+        let mut albums = Batches::new(Kind::Dir);
+        while let Some(batch) = albums.next(artist_dir).await? {
+            for found in &batch.found {
+                match self.unchanged_album(old_artist, found).await {
+                    Some(old_album) => self.copy_songs(&old_album, *album).await?,
+                    None => {
+                        info!("[LIBRARY] scanning {}", batch.name(found));
+                        let album_dir = artist_dir.open_dir(&found.short_name).await?;
+                        let written = self.write_album_songs(&album_dir, *album).await;
+                        album_dir.close()?;
+                        written?;
+                    }
+                }
+                *album += 1;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_album_songs(&mut self, album_dir: &Dir<'_>, album: u32) -> Result<(), SdError> {
+        let mut songs = Batches::new(Kind::File);
+        while let Some(batch) = songs.next(album_dir).await? {
+            for song in &batch.found {
+                self.index
+                    .entry(&song.entry(album, 0, 0), batch.name(song))
+                    .await?;
+            }
+        }
+        Ok(())
     }
-}
 
-async fn get_artist<'a>(dir: &Dir<'a>) -> Vec<Album, MAX_DIRS> {
-    let mut album_names: Vec<String<MAX_NAME_LEN>, MAX_FILES> = Vec::new();
+    // The songs of an album that did not change, taken from the old index
+    async fn copy_songs(&mut self, old_album: &Entry, album: u32) -> Result<(), SdError> {
+        // only albums of the old index are unchanged
+        let (old, files) = self.old.as_mut().unwrap();
+        let mut buf = [0; MAX_NAME_LEN];
+        for record in old_album.children() {
+            let Some(mut song) = old.entry_in(files, Level::Songs, record).await else {
+                return Err(Error::FormatError(
+                    "cannot read the songs of the last index",
+                ));
+            };
+            let Some(name) = old.name_in(files, &song, &mut buf).await else {
+                return Err(Error::FormatError(
+                    "cannot read the names of the last index",
+                ));
+            };
+            song.parent = album;
+            self.index.entry(&song, name).await?;
+        }
+        Ok(())
+    }
 
-    let mut buf = [0u8; MAX_NAME_LEN];
-    let mut lfn_buffer = LfnBuffer::new(&mut buf);
-    dir.iterate_dir_lfn(&mut lfn_buffer, |entry, lfn| {
-        if !album_names.is_full() {
-            if entry.attributes.is_directory() && !ignore_name(&entry.name) {
-                album_names.push(get_name(entry, lfn)).unwrap()
+    // The artist of the old index with the same 8.3 name, they are usually in the same order as then
+    async fn old_artist(&mut self, artist: &Found) -> Option<Entry> {
+        let (old, files) = self.old.as_mut()?;
+        let artists = old.len(Level::Artists);
+        for i in 0..artists {
+            let record = (self.next_old_artist + i) % artists;
+            let old_artist = old.entry_in(files, Level::Artists, record).await?;
+            if old_artist.short_name == artist.short_name() {
+                self.next_old_artist = record + 1;
+                return Some(old_artist);
             }
-        } else {
-            warn!("Too many songs in album. increase MAX_DIRS");
         }
-    })
-    .await
-    .unwrap();
-
-    let mut albums = Vec::new();
-
-    for album in album_names {
-        info!("Looking for dir: {}", album);
-        let album_dir = dir.open_dir(album.as_str()).await.unwrap();
-        if albums
-            .push(Album {
-                songs: get_album(&album_dir).await,
-                name: album,
-            })
-            .is_err()
-        {
-            album_dir.close().unwrap();
-            break;
+        None
+    }
+
+    // The album of the old index with the same 8.3 name, if the dir still has the same
+    // modification time. Its songs and their number are then taken from the old index
+    async fn unchanged_album(
+        &mut self,
+        old_artist: Option<&Entry>,
+        album: &Found,
+    ) -> Option<Entry> {
+        let (old, files) = self.old.as_mut()?;
+        for record in old_artist?.children() {
+            let old_album = old.entry_in(files, Level::Albums, record).await?;
+            if old_album.short_name == album.short_name() {
+                return (old_album.modified == album.modified).then_some(old_album);
+            }
         }
-        album_dir.close().unwrap();
+        None
     }
+}
 
-    albums
+// Artists and albums are dirs, songs are files
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Dir,
+    File,
 }
 
-async fn get_album<'a, const MAX_FILES: usize>(
-    dir: &Dir<'a>,
-) -> Vec<String<MAX_NAME_LEN>, MAX_FILES> {
-    let mut songs: Vec<String<MAX_NAME_LEN>, MAX_FILES> = Vec::new();
+impl Kind {
+    fn matches(self, entry: &DirEntry) -> bool {
+        entry.attributes.is_directory() == (self == Kind::Dir) && !ignore_name(&entry.name)
+    }
+}
 
-    let mut buf = [0u8; MAX_NAME_LEN];
-    let mut lfn_buffer = LfnBuffer::new(&mut buf);
-    dir.iterate_dir_lfn(&mut lfn_buffer, |entry, lfn| {
-        if !songs.is_full() {
-            if !entry.attributes.is_directory() && !ignore_name(&entry.name) {
-                songs.push(get_name(entry, lfn)).unwrap()
-            }
-        } else {
-            warn!("Too many songs in album. increase MAX_FILES");
+async fn count_entries(dir: &Dir<'_>, kind: Kind) -> Result<u32, SdError> {
+    let mut count = 0;
+    dir.iterate_dir(|entry| {
+        if kind.matches(entry) {
+            count += 1;
         }
     })
-    .await
-    .unwrap();
+    .await?;
+    Ok(count)
+}
 
-    songs
+// An entry found in a dir, with the name to open it by and where the name to show is in its batch
+struct Found {
+    short_name: ShortFileName,
+    name: Range<usize>,
+    modified: u32,
 }
 
-fn get_name<'a>(entry: &DirEntry, lfn: Option<&'a str>) -> String<MAX_NAME_LEN> {
-    if let Some(lfn) = lfn {
-        String::from_str(lfn).unwrap()
-    } else {
-        String::from_utf8(Vec::from_slice(entry.name.base_name()).unwrap()).unwrap()
+impl Found {
+    fn short_name(&self) -> ShortName {
+        ShortName::new(self.short_name.base_name(), self.short_name.extension())
     }
+
+    // The name is left to `IndexWriter::entry`
+    fn entry(&self, parent: u32, first: u32, count: u32) -> Entry {
+        Entry {
+            name: Default::default(),
+            short_name: self.short_name(),
+            parent,
+            first,
+            count,
+            modified: self.modified,
+        }
+    }
+}
+
+// Entries found in a dir, with their names one after the other
+struct Batch {
+    found: Vec<Found, BATCH_LEN>,
+    names: String<BATCH_NAMES_LEN>,
+}
+
+impl Batch {
+    fn name(&self, found: &Found) -> &str {
+        &self.names[found.name.clone()]
+    }
+}
+
+// Goes through the entries of a dir a batch at a time. The callback of `iterate_dir_lfn` cannot
+// open the dirs it finds and a whole dir may not fit in memory, so every batch reads the dir again
+struct Batches {
+    kind: Kind,
+    // entries handed out so far
+    read: usize,
+    done: bool,
+}
+
+impl Batches {
+    fn new(kind: Kind) -> Self {
+        Self {
+            kind,
+            read: 0,
+            done: false,
+        }
+    }
+
+    async fn next(&mut self, dir: &Dir<'_>) -> Result<Option<Batch>, SdError> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let (kind, skip) = (self.kind, self.read);
+        let mut batch = Batch {
+            found: Vec::new(),
+            names: String::new(),
+        };
+        let mut full = false;
+        let mut matching = 0;
+        let mut buf = [0u8; MAX_NAME_LEN];
+        let mut lfn_buffer = LfnBuffer::new(&mut buf);
+        dir.iterate_dir_lfn(&mut lfn_buffer, |entry, lfn| {
+            if !kind.matches(entry) {
+                return;
+            }
+            if matching >= skip && !full {
+                let name = match batch.found.is_full() {
+                    true => None,
+                    false => push_name(&mut batch.names, entry, lfn),
+                };
+                match name {
+                    Some(name) => {
+                        let found = Found {
+                            short_name: entry.name.clone(),
+                            name,
+                            modified: pack_timestamp(&entry.mtime),
+                        };
+                        let _ = batch.found.push(found);
+                    }
+                    // the entries after it wait for the next batch
+                    None => full = true,
+                }
+            }
+            matching += 1;
+        })
+        .await?;
+
+        self.read += batch.found.len();
+        self.done = self.read >= matching;
+        Ok((!batch.found.is_empty()).then_some(batch))
+    }
+}
+
+// Adds the long name of an entry to `names`, or the short one when it has none.
+// Returns where it is, `None` when it does not fit
+fn push_name(
+    names: &mut String<BATCH_NAMES_LEN>,
+    entry: &DirEntry,
+    lfn: Option<&str>,
+) -> Option<Range<usize>> {
+    let start = names.len();
+    let pushed = match lfn {
+        Some(lfn) if !lfn.is_empty() => names.push_str(lfn),
+        _ => write!(names, "{}", entry.name).map_err(|_| ()),
+    };
+    if pushed.is_err() {
+        names.truncate(start);
+        return None;
+    }
+    Some(start..names.len())
+}
+
+// A timestamp in a u32 the way fat packs its date and time, to tell when a dir was changed
+fn pack_timestamp(time: &Timestamp) -> u32 {
+    ((time.year_since_1970 as u32) << 25)
+        | ((time.zero_indexed_month as u32 + 1) << 21)
+        | ((time.zero_indexed_day as u32 + 1) << 16)
+        | ((time.hours as u32) << 11)
+        | ((time.minutes as u32) << 5)
+        | (time.seconds as u32 / 2)
+}
+
+// Case insensitive check of a file names extension
+pub fn has_extension(name: &str, extension: &str) -> bool {
+    name.rsplit_once('.')
+        .is_some_and(|(_, ext)| ext.eq_ignore_ascii_case(extension))
 }
 
+// The base name is in the code page of the card, so it is compared as bytes
 fn ignore_name(name: &ShortFileName) -> bool {
-    let name = str::from_utf8(name.base_name()).unwrap();
-    name == "." || name == ".." || name.contains("TRASH")
+    let base = name.base_name();
+    base == b"." || base == b".." || base.windows(5).any(|w| w == b"TRASH")
 }
@@ -0,0 +1,59 @@
+use defmt::Format;
+
+// A left and right sample as one 32bit word for the pio i2s program.
+// The state machine shifts words out msb first, so the left sample is the upper half word
+// and goes out while the word clock is low, the right sample is the lower half word
+#[derive(Debug, Format, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct StereoFrame(u32);
+
+impl StereoFrame {
+    pub const SILENCE: Self = Self(0);
+
+    pub const fn new(left: i16, right: i16) -> Self {
+        // go through u16 so a negative right sample is not sign extended over the left one
+        Self((left as u16 as u32) << 16 | right as u16 as u32)
+    }
+
+    pub const fn mono(sample: i16) -> Self {
+        Self::new(sample, sample)
+    }
+
+    pub const fn left(self) -> i16 {
+        (self.0 >> 16) as i16
+    }
+
+    pub const fn right(self) -> i16 {
+        self.0 as i16
+    }
+
+    // The words the dma pushes into the pio tx fifo
+    pub fn as_words(frames: &[Self]) -> &[u32] {
+        // SAFETY: `StereoFrame` is a transparent wrapper around u32
+        unsafe { core::slice::from_raw_parts(frames.as_ptr().cast(), frames.len()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packs_every_sign_combination() {
+        let samples = [0, 1, -1, 12_345, -12_345, i16::MAX, i16::MIN];
+        for left in samples {
+            for right in samples {
+                let frame = StereoFrame::new(left, right);
+                assert_eq!((frame.left(), frame.right()), (left, right));
+            }
+        }
+    }
+
+    #[test]
+    fn left_is_the_upper_half_word() {
+        let frame = StereoFrame::new(-2, 1);
+        assert_eq!(StereoFrame::as_words(&[frame]), &[0xFFFE_0001]);
+        assert_eq!(StereoFrame::new(1, -2).0, 0x0001_FFFE);
+        assert_eq!(StereoFrame::mono(-1).0, u32::MAX);
+    }
+}
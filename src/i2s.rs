@@ -0,0 +1,174 @@
+use crate::frame::StereoFrame;
+use defmt::{Format, info};
+use embassy_rp::dma::{AnyChannel, Channel, Transfer};
+use embassy_rp::pio::{
+    Common, Config, Direction, FifoJoin, Instance, PioPin, ShiftConfig, ShiftDirection,
+    StateMachine,
+};
+use embassy_rp::{Peripheral, PeripheralRef, clocks::clk_sys_freq, into_ref};
+use fixed::FixedU32;
+use fixed::types::extra::U8;
+
+// The pio program below always clocks out 16bit stereo frames
+pub const BIT_DEPTH: u32 = 16;
+pub const CHANNELS: u32 = 2;
+// Rate the dac runs at when a track has to be resampled, the pio can generate it exactly
+pub const DEVICE_RATE: u32 = 44_100;
+
+// The largest difference between the requested and generated sample rate
+// that is still treated as an exact match
+const MAX_RATE_ERROR_PPM: u32 = 100;
+// The pio executes two instructions per bit clock
+const CYCLES_PER_BIT: u32 = 2;
+// Valid range of the 16.8 fixed point pio clock divider
+const MIN_DIVIDER: u32 = 1 << 8;
+const MAX_DIVIDER: u32 = (u16::MAX as u32) << 8 | 0xFF;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum RateError {
+    // The divider needed for this rate does not fit the pio clock divider
+    OutOfRange,
+    // The closest rate the pio can generate is too far from the requested rate
+    Inexact { closest: u32 },
+}
+
+pub struct I2sOut<'d, P: Instance, const S: usize> {
+    dma: PeripheralRef<'d, AnyChannel>,
+    sm: StateMachine<'d, P, S>,
+    cfg: Config<'d, P>,
+    sample_rate: u32,
+}
+
+impl<'d, P: Instance, const S: usize> I2sOut<'d, P, S> {
+    pub fn new(
+        common: &mut Common<'d, P>,
+        mut sm: StateMachine<'d, P, S>,
+        dma: impl Peripheral<P = impl Channel> + 'd,
+        data_pin: impl PioPin,
+        bit_clock_pin: impl PioPin,
+        left_right_clock_pin: impl PioPin,
+    ) -> Self {
+        into_ref!(dma);
+
+        let prg = pio_proc::pio_asm!(
+            ".side_set 2",
+            "    set x, 14          side 0b01", // side 0bWB - W = Word Clock, B = Bit Clock
+            "left_data:",
+            "    out pins, 1        side 0b00",
+            "    jmp x-- left_data  side 0b01",
+            "    out pins 1         side 0b10",
+            "    set x, 14          side 0b11",
+            "right_data:",
+            "    out pins 1         side 0b10",
+            "    jmp x-- right_data side 0b11",
+            "    out pins 1         side 0b00",
+        );
+        let program = common.load_program(&prg.program);
+
+        let data_pin = common.make_pio_pin(data_pin);
+        let bit_clock_pin = common.make_pio_pin(bit_clock_pin);
+        let left_right_clock_pin = common.make_pio_pin(left_right_clock_pin);
+
+        let mut cfg = Config::default();
+        cfg.use_program(&program, &[&bit_clock_pin, &left_right_clock_pin]);
+        cfg.set_out_pins(&[&data_pin]);
+        cfg.clock_divider = divider_for(DEVICE_RATE).unwrap();
+        cfg.shift_out = ShiftConfig {
+            threshold: 32,
+            direction: ShiftDirection::Left,
+            auto_fill: true,
+        };
+        // join fifos to have twice the time to start the next dma transfer
+        cfg.fifo_join = FifoJoin::TxOnly;
+
+        sm.set_config(&cfg);
+        sm.set_pin_dirs(
+            Direction::Out,
+            &[&data_pin, &left_right_clock_pin, &bit_clock_pin],
+        );
+        sm.set_enable(true);
+
+        Self {
+            dma: dma.map_into(),
+            sm,
+            cfg,
+            sample_rate: DEVICE_RATE,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Reprograms the bit clock for a new sample rate.
+    // Should only be called between tracks, while no dma transfer is running
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), RateError> {
+        if sample_rate == self.sample_rate {
+            return Ok(());
+        }
+
+        let divider = divider_for(sample_rate)?;
+        let closest = rate_for(divider);
+        if rate_error_ppm(sample_rate, closest) > MAX_RATE_ERROR_PPM {
+            return Err(RateError::Inexact { closest });
+        }
+
+        self.apply_divider(divider);
+        self.sample_rate = sample_rate;
+        info!("[I2S] sample rate set to {}hz", sample_rate);
+        Ok(())
+    }
+
+    // Runs the bit clock at the closest rate the pio can generate, even if it is not exact.
+    // Returns the rate the dac will actually run at
+    pub fn set_closest_sample_rate(&mut self, sample_rate: u32) -> Result<u32, RateError> {
+        let divider = divider_for(sample_rate)?;
+        let closest = rate_for(divider);
+
+        self.apply_divider(divider);
+        self.sample_rate = closest;
+        info!(
+            "[I2S] sample rate set to {}hz (requested {}hz)",
+            closest, sample_rate
+        );
+        Ok(closest)
+    }
+
+    fn apply_divider(&mut self, divider: FixedU32<U8>) {
+        self.sm.set_enable(false);
+        self.sm.clear_fifos();
+        self.cfg.clock_divider = divider;
+        self.sm.set_config(&self.cfg);
+        self.sm.set_enable(true);
+    }
+
+    pub fn write<'b>(&'b mut self, buff: &'b [StereoFrame]) -> Transfer<'b, AnyChannel> {
+        self.sm
+            .tx()
+            .dma_push(self.dma.reborrow(), StereoFrame::as_words(buff))
+    }
+}
+
+// 16.8 fixed point pio clock divider for a sample rate, rounded to the nearest step
+fn divider_for(sample_rate: u32) -> Result<FixedU32<U8>, RateError> {
+    let pio_clock = sample_rate as u64 * (BIT_DEPTH * CHANNELS * CYCLES_PER_BIT) as u64;
+    if pio_clock == 0 {
+        return Err(RateError::OutOfRange);
+    }
+
+    let bits = ((clk_sys_freq() as u64) << 8).saturating_add(pio_clock / 2) / pio_clock;
+    if bits < MIN_DIVIDER as u64 || bits > MAX_DIVIDER as u64 {
+        return Err(RateError::OutOfRange);
+    }
+    Ok(FixedU32::from_bits(bits as u32))
+}
+
+// Sample rate the pio generates with a given divider
+fn rate_for(divider: FixedU32<U8>) -> u32 {
+    let per_sample = divider.to_bits() as u64 * (BIT_DEPTH * CHANNELS * CYCLES_PER_BIT) as u64;
+    (((clk_sys_freq() as u64) << 8) / per_sample) as u32
+}
+
+fn rate_error_ppm(requested: u32, actual: u32) -> u32 {
+    (requested.abs_diff(actual) as u64 * 1_000_000 / requested as u64) as u32
+}
@@ -0,0 +1,590 @@
+// The library index on the sd card.
+// Artists, albums and songs are each kept in a section of fixed size records, with the albums of
+// an artist and the songs of an album next to each other. Only a page of records per section is
+// held in memory, so the library can hold far more songs than fit in ram.
+//
+// The names are of any length, so they are kept apart in a names file and the records only say
+// where theirs is. Dirs and files are opened by the 8.3 name in the record, which always works
+// whatever characters the long name has.
+//
+// The index is kept between boots. It is written to the other of two pairs of files, so the last
+// complete index stays on the card while the next one is written and the dirs that did not change
+// can be copied from it. `tools/libdump.py` reads the format on a computer.
+//
+// Index file, all numbers little endian:
+//   header   magic "PPLI", version u16, record length u16, generation u32
+//   3 times  number of records u32, then the records of the artists, albums and songs
+//   trailer  length of the names u32, magic "PPLE", missing when writing the index was cut short
+// Record:
+//   parent u32, first u32, count u32, modified u32, name offset u32, name length u16,
+//   8.3 name as in the dir entry, 11 bytes padded with spaces
+// Names file:
+//   header   magic "PPLN", generation u32 of its index
+//   names    utf-8, one after the other
+
+use crate::file_reader::{Library, SdError, SdFile, read_at};
+use core::cell::RefCell;
+use core::ops::Range;
+use defmt::{Format, warn};
+use embedded_sdmmc::asynchronous::Error;
+use heapless::{String, Vec};
+
+// The newer complete index of the two is used
+pub const INDEX_FILES: [&str; 2] = ["LIBRARY0.IDX", "LIBRARY1.IDX"];
+pub const NAME_FILES: [&str; 2] = ["LIBRARY0.NAM", "LIBRARY1.NAM"];
+// Changes whenever the layout does, older indexes are then scanned again
+const VERSION: u16 = 4;
+const MAGIC: [u8; 4] = *b"PPLI";
+const END_MAGIC: [u8; 4] = *b"PPLE";
+const NAMES_MAGIC: [u8; 4] = *b"PPLN";
+const HEADER_LEN: usize = 12;
+const NAMES_HEADER_LEN: usize = 8;
+pub const RECORD_LEN: usize = 4 * 5 + 2 + SHORT_NAME_LEN;
+const SHORT_NAME_LEN: usize = 11;
+// "NAME.EXT" with every character of the 8.3 name taking up to 2 bytes in utf-8
+pub const SHORT_NAME_STR_LEN: usize = 2 * (SHORT_NAME_LEN + 1);
+// Records read from the card at a time
+const PAGE_LEN: usize = 8;
+const BLOCK_LEN: usize = 512;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Artists,
+    Albums,
+    Songs,
+}
+
+const LEVELS: [Level; 3] = [Level::Artists, Level::Albums, Level::Songs];
+
+// Where the name of an entry is in the names file, read it with `Index::name`
+#[derive(Debug, Format, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Name {
+    offset: u32,
+    len: u16,
+}
+
+// The 8.3 name of the dir or file of an entry, as the base name and extension padded with spaces
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct ShortName([u8; SHORT_NAME_LEN]);
+
+impl ShortName {
+    pub fn new(base_name: &[u8], extension: &[u8]) -> Self {
+        let mut name = [b' '; SHORT_NAME_LEN];
+        let base_name = &base_name[..base_name.len().min(8)];
+        let extension = &extension[..extension.len().min(3)];
+        name[..base_name.len()].copy_from_slice(base_name);
+        name[8..8 + extension.len()].copy_from_slice(extension);
+        Self(name)
+    }
+
+    // As "NAME.EXT" to open the dir or file by. The bytes above ascii are in the code page of
+    // the card, they are taken as latin-1 the way `ShortFileName::create_from_str` reads them back
+    pub fn to_str(self) -> String<SHORT_NAME_STR_LEN> {
+        let trim = |part: &[u8]| part.len() - part.iter().rev().take_while(|b| **b == b' ').count();
+        let (base_name, extension) = self.0.split_at(8);
+        let mut name = String::new();
+        for byte in &base_name[..trim(base_name)] {
+            name.push(*byte as char).unwrap();
+        }
+        if trim(extension) > 0 {
+            name.push('.').unwrap();
+        }
+        for byte in &extension[..trim(extension)] {
+            name.push(*byte as char).unwrap();
+        }
+        name
+    }
+}
+
+impl Default for ShortName {
+    fn default() -> Self {
+        Self([b' '; SHORT_NAME_LEN])
+    }
+}
+
+// An artist, album or song of the library
+#[derive(Debug, Format, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Entry {
+    pub name: Name,
+    pub short_name: ShortName,
+    // the artist of an album or the album of a song
+    pub parent: u32,
+    // the albums of an artist or the songs of an album, in the next section
+    pub first: u32,
+    pub count: u32,
+    // modification time of the dir or file, a dir with the same 8.3 name that still has it
+    // is not scanned again
+    pub modified: u32,
+}
+
+impl Entry {
+    pub fn children(&self) -> Range<u32> {
+        self.first..self.first + self.count
+    }
+
+    fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut record = [0; RECORD_LEN];
+        let fields = [
+            self.parent,
+            self.first,
+            self.count,
+            self.modified,
+            self.name.offset,
+        ];
+        for (at, field) in fields.iter().enumerate() {
+            record[at * 4..at * 4 + 4].copy_from_slice(&field.to_le_bytes());
+        }
+        record[20..22].copy_from_slice(&self.name.len.to_le_bytes());
+        record[22..].copy_from_slice(&self.short_name.0);
+        record
+    }
+
+    fn decode(record: &[u8]) -> Self {
+        let read_u32 = |at: usize| u32::from_le_bytes(record[at..at + 4].try_into().unwrap());
+        Self {
+            name: Name {
+                offset: read_u32(16),
+                len: u16::from_le_bytes([record[20], record[21]]),
+            },
+            parent: read_u32(0),
+            first: read_u32(4),
+            count: read_u32(8),
+            modified: read_u32(12),
+            short_name: ShortName(record[22..].try_into().unwrap()),
+        }
+    }
+}
+
+// Where the sections are in the index file
+#[derive(Debug, Format, Clone, Copy, Default)]
+pub struct Layout {
+    // offset of the first record and the number of records
+    sections: [(u32, u32); 3],
+    // bytes in the names file after its header
+    names_len: u32,
+}
+
+impl Layout {
+    fn len(&self, level: Level) -> u32 {
+        self.sections[level as usize].1
+    }
+
+    // File offset of a record
+    fn offset(&self, level: Level, record: u32) -> u32 {
+        self.sections[level as usize].0 + record * RECORD_LEN as u32
+    }
+}
+
+// A page of records of one section
+struct Page {
+    start: u32,
+    entries: Vec<Entry, PAGE_LEN>,
+}
+
+impl Page {
+    fn get(&self, record: u32) -> Option<Entry> {
+        let at = record.checked_sub(self.start)?;
+        self.entries.get(at as usize).copied()
+    }
+}
+
+// A complete index on the card
+#[derive(Default)]
+pub struct Index {
+    // which of `INDEX_FILES` it is in
+    slot: usize,
+    // counts up with every index written
+    generation: u32,
+    layout: Layout,
+    // the last page read from each section
+    pages: RefCell<[Option<Page>; 3]>,
+}
+
+impl Index {
+    fn new(slot: usize, generation: u32, layout: Layout) -> Self {
+        Self {
+            slot,
+            generation,
+            layout,
+            pages: RefCell::new([None, None, None]),
+        }
+    }
+
+    // Reads the newer of the two index files, `None` when neither is complete and of this version
+    pub async fn load(library: &Library<'_>) -> Option<Self> {
+        let mut newest: Option<Self> = None;
+        for slot in 0..INDEX_FILES.len() {
+            if let Some(index) = Self::open(library, slot).await {
+                if newest
+                    .as_ref()
+                    .is_none_or(|newest| index.generation > newest.generation)
+                {
+                    newest = Some(index);
+                }
+            }
+        }
+        newest
+    }
+
+    async fn open(library: &Library<'_>, slot: usize) -> Option<Self> {
+        let name = INDEX_FILES[slot];
+        let read_u32 = async |offset| {
+            let mut bytes = [0; 4];
+            match library.read_file_at(name, offset, &mut bytes).await {
+                Ok(4) => Some(bytes),
+                _ => None,
+            }
+        };
+
+        let mut header = [0; HEADER_LEN];
+        if library.read_file_at(name, 0, &mut header).await.ok()? != HEADER_LEN {
+            return None;
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        let record_len = u16::from_le_bytes([header[6], header[7]]);
+        if header[..4] != MAGIC || version != VERSION || record_len != RECORD_LEN as u16 {
+            warn!("[LIBRARY] {} is of another version", name);
+            return None;
+        }
+        let generation = u32::from_le_bytes(header[8..12].try_into().unwrap());
+
+        // a broken count could point past the end of any file
+        let mut layout = Layout::default();
+        let mut position = HEADER_LEN as u32;
+        for level in LEVELS {
+            let len = u32::from_le_bytes(read_u32(position).await?);
+            let records = len.checked_mul(RECORD_LEN as u32)?;
+            layout.sections[level as usize] = (position + 4, len);
+            position = position.checked_add(4)?.checked_add(records)?;
+        }
+        layout.names_len = u32::from_le_bytes(read_u32(position).await?);
+        if read_u32(position.checked_add(4)?).await? != END_MAGIC {
+            warn!("[LIBRARY] {} is incomplete", name);
+            return None;
+        }
+
+        // the names have to be the ones written with this index, and all of them
+        let names = NAME_FILES[slot];
+        let mut header = [0; NAMES_HEADER_LEN];
+        let read = library.read_file_at(names, 0, &mut header).await.ok()?;
+        if read != NAMES_HEADER_LEN
+            || header[..4] != NAMES_MAGIC
+            || header[4..8] != generation.to_le_bytes()
+        {
+            warn!("[LIBRARY] {} does not belong to {}", names, name);
+            return None;
+        }
+        if layout.names_len > 0 {
+            let last = (NAMES_HEADER_LEN as u32 - 1).checked_add(layout.names_len)?;
+            if library.read_file_at(names, last, &mut [0]).await.ok()? != 1 {
+                warn!("[LIBRARY] {} is incomplete", names);
+                return None;
+            }
+        }
+        Some(Self::new(slot, generation, layout))
+    }
+
+    // The index and names file it is in
+    pub fn files(&self) -> [&'static str; 2] {
+        [INDEX_FILES[self.slot], NAME_FILES[self.slot]]
+    }
+
+    // Number of artists, albums or songs
+    pub fn len(&self, level: Level) -> u32 {
+        self.layout.len(level)
+    }
+
+    // An artist, album or song by its position in the index.
+    // Reads the page it is on from the card unless that is the last page read
+    pub async fn entry(&self, library: &Library<'_>, level: Level, record: u32) -> Option<Entry> {
+        let file = INDEX_FILES[self.slot];
+        self.read_entry(level, record, async |offset, buf| {
+            library.read_file_at(file, offset, buf).await
+        })
+        .await
+    }
+
+    // `entry` from the files opened with `IndexFiles`
+    pub async fn entry_in(
+        &self,
+        files: &mut IndexFiles<'_>,
+        level: Level,
+        record: u32,
+    ) -> Option<Entry> {
+        self.read_entry(level, record, async |offset, buf| {
+            read_at(&mut files.index, offset, buf).await
+        })
+        .await
+    }
+
+    async fn read_entry(
+        &self,
+        level: Level,
+        record: u32,
+        mut read_at: impl AsyncFnMut(u32, &mut [u8]) -> Result<usize, SdError>,
+    ) -> Option<Entry> {
+        if record >= self.layout.len(level) {
+            return None;
+        }
+        let cached = self.pages.borrow()[level as usize]
+            .as_ref()
+            .and_then(|page| page.get(record));
+        if cached.is_some() {
+            return cached;
+        }
+
+        let name = INDEX_FILES[self.slot];
+        let start = record - record % PAGE_LEN as u32;
+        let len = (self.layout.len(level) - start).min(PAGE_LEN as u32) as usize;
+        let mut buf = [0u8; PAGE_LEN * RECORD_LEN];
+        let records = &mut buf[..len * RECORD_LEN];
+        let offset = self.layout.offset(level, start);
+        match read_at(offset, records).await {
+            Ok(read) if read == records.len() => (),
+            Ok(_) => {
+                warn!("[LIBRARY] {} is cut short", name);
+                return None;
+            }
+            Err(e) => {
+                warn!("[LIBRARY] cannot read {}: {}", name, e);
+                return None;
+            }
+        }
+
+        let page = Page {
+            start,
+            entries: records
+                .chunks_exact(RECORD_LEN)
+                .map(Entry::decode)
+                .collect(),
+        };
+        let entry = page.get(record);
+        self.pages.borrow_mut()[level as usize] = Some(page);
+        entry
+    }
+
+    // The name of an entry, cut short on a character boundary when it does not fit `buf`
+    pub async fn name<'b>(
+        &self,
+        library: &Library<'_>,
+        entry: &Entry,
+        buf: &'b mut [u8],
+    ) -> Option<&'b str> {
+        let file = NAME_FILES[self.slot];
+        self.read_name(entry, buf, async |offset, buf| {
+            library.read_file_at(file, offset, buf).await
+        })
+        .await
+    }
+
+    // `name` from the files opened with `IndexFiles`
+    pub async fn name_in<'b>(
+        &self,
+        files: &mut IndexFiles<'_>,
+        entry: &Entry,
+        buf: &'b mut [u8],
+    ) -> Option<&'b str> {
+        self.read_name(entry, buf, async |offset, buf| {
+            read_at(&mut files.names, offset, buf).await
+        })
+        .await
+    }
+
+    async fn read_name<'b>(
+        &self,
+        entry: &Entry,
+        buf: &'b mut [u8],
+        mut read_at: impl AsyncFnMut(u32, &mut [u8]) -> Result<usize, SdError>,
+    ) -> Option<&'b str> {
+        let file = NAME_FILES[self.slot];
+        let len = (entry.name.len as usize).min(buf.len());
+        let offset = NAMES_HEADER_LEN as u32 + entry.name.offset;
+        match read_at(offset, &mut buf[..len]).await {
+            Ok(read) if read == len => (),
+            Ok(_) => {
+                warn!("[LIBRARY] {} is cut short", file);
+                return None;
+            }
+            Err(e) => {
+                warn!("[LIBRARY] cannot read {}: {}", file, e);
+                return None;
+            }
+        }
+
+        let name = &buf[..len];
+        match str::from_utf8(name) {
+            Ok(name) => Some(name),
+            // only the last character can have been cut
+            Err(e) => str::from_utf8(&name[..e.valid_up_to()]).ok(),
+        }
+    }
+}
+
+// The files of an index kept open while many of its records are read, opening them for every
+// record would take most of the time of a scan
+pub struct IndexFiles<'a> {
+    index: SdFile<'a>,
+    names: SdFile<'a>,
+}
+
+impl<'a> IndexFiles<'a> {
+    // Takes the files named by `Index::files`
+    pub fn new(index: SdFile<'a>, names: SdFile<'a>) -> Self {
+        Self { index, names }
+    }
+
+    pub async fn close(self) -> Result<(), SdError> {
+        let closed = self.index.close().await;
+        self.names.close().await?;
+        closed
+    }
+}
+
+// Gathers what is written to a file into whole blocks
+struct BlockWriter<'a> {
+    file: SdFile<'a>,
+    buf: Vec<u8, BLOCK_LEN>,
+    // bytes written so far
+    position: u32,
+}
+
+impl<'a> BlockWriter<'a> {
+    fn new(file: SdFile<'a>) -> Self {
+        Self {
+            file,
+            buf: Vec::new(),
+            position: 0,
+        }
+    }
+
+    async fn write(&mut self, mut data: &[u8]) -> Result<(), SdError> {
+        self.position += data.len() as u32;
+        while !data.is_empty() {
+            let len = data.len().min(BLOCK_LEN - self.buf.len());
+            self.buf.extend_from_slice(&data[..len]).unwrap();
+            data = &data[len..];
+            if self.buf.is_full() {
+                self.file.write(&self.buf).await?;
+                self.buf.clear();
+            }
+        }
+        Ok(())
+    }
+
+    // Writes out the last block unless `written` failed, the file is closed either way
+    async fn close(mut self, written: Result<(), SdError>) -> Result<(), SdError> {
+        let written = match written {
+            Ok(()) => self.file.write(&self.buf).await,
+            Err(e) => Err(e),
+        };
+        self.file.close().await?;
+        written
+    }
+}
+
+// Writes the next index a section at a time, with the names next to it
+pub struct IndexWriter<'a> {
+    index: BlockWriter<'a>,
+    names: BlockWriter<'a>,
+    slot: usize,
+    generation: u32,
+    layout: Layout,
+    // the section being written and its records so far
+    level: Option<Level>,
+    written: u32,
+}
+
+impl<'a> IndexWriter<'a> {
+    // The files of the index after `old`, in the slot `old` is not in
+    pub fn slot(old: Option<&Index>) -> usize {
+        old.map_or(0, |old| 1 - old.slot)
+    }
+
+    // Takes the files of `slot(old)`, the headers are written out with the first blocks
+    pub fn new(index: SdFile<'a>, names: SdFile<'a>, old: Option<&Index>) -> Self {
+        let generation = old.map_or(0, |old| old.generation.wrapping_add(1));
+        let mut index = BlockWriter::new(index);
+        index.buf.extend_from_slice(&MAGIC).unwrap();
+        index.buf.extend_from_slice(&VERSION.to_le_bytes()).unwrap();
+        index
+            .buf
+            .extend_from_slice(&(RECORD_LEN as u16).to_le_bytes())
+            .unwrap();
+        index
+            .buf
+            .extend_from_slice(&generation.to_le_bytes())
+            .unwrap();
+        index.position = HEADER_LEN as u32;
+
+        let mut names = BlockWriter::new(names);
+        names.buf.extend_from_slice(&NAMES_MAGIC).unwrap();
+        names
+            .buf
+            .extend_from_slice(&generation.to_le_bytes())
+            .unwrap();
+        names.position = NAMES_HEADER_LEN as u32;
+
+        Self {
+            index,
+            names,
+            slot: Self::slot(old),
+            generation,
+            layout: Layout::default(),
+            level: None,
+            written: 0,
+        }
+    }
+
+    // Starts the next section, `len` records have to follow
+    pub async fn section(&mut self, level: Level, len: u32) -> Result<(), SdError> {
+        self.check_section()?;
+        self.index.write(&len.to_le_bytes()).await?;
+        self.layout.sections[level as usize] = (self.index.position, len);
+        self.level = Some(level);
+        self.written = 0;
+        Ok(())
+    }
+
+    // Writes the record of `entry` with `name` in place of the name it has
+    pub async fn entry(&mut self, entry: &Entry, name: &str) -> Result<(), SdError> {
+        let entry = Entry {
+            name: Name {
+                offset: self.names.position - NAMES_HEADER_LEN as u32,
+                len: name.len() as u16,
+            },
+            ..*entry
+        };
+        self.names.write(name.as_bytes()).await?;
+        self.written += 1;
+        self.index.write(&entry.encode()).await
+    }
+
+    // A section with more or fewer records than it was started with would shift the ones after it
+    fn check_section(&self) -> Result<(), SdError> {
+        match self.level {
+            Some(level) if self.written != self.layout.len(level) => Err(Error::FormatError(
+                "library index section has the wrong number of records",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Marks the index as complete and closes the files. An index whose records could not all be
+    // written, `written` is the error then, is closed without the trailer so it is never loaded.
+    // The names are on the card before the index is, so a complete index always has them
+    pub async fn finish(mut self, written: Result<(), SdError>) -> Result<Index, SdError> {
+        self.layout.names_len = self.names.position - NAMES_HEADER_LEN as u32;
+        let written = written.and_then(|()| self.check_section());
+        let written = self.names.close(written).await;
+        let written = match written {
+            Ok(()) => self.index.write(&self.layout.names_len.to_le_bytes()).await,
+            Err(e) => Err(e),
+        };
+        let written = match written {
+            Ok(()) => self.index.write(&END_MAGIC).await,
+            Err(e) => Err(e),
+        };
+        self.index.close(written).await?;
+        Ok(Index::new(self.slot, self.generation, self.layout))
+    }
+}
@@ -1,30 +1,51 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(inherent_str_constructors)]
 #![feature(impl_trait_in_assoc_type)]
 
-use audio_parser::AudioFile;
 use core::default::Default;
+use core::sync::atomic::Ordering;
 use defmt::{info, unwrap, warn};
 use display::{Display, MediaUi};
 use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_futures::select::{Either, select};
 use embassy_rp::bind_interrupts;
 use embassy_rp::gpio::{Level, Output};
 use embassy_rp::peripherals::{DMA_CH2, I2C0, I2C1, PIN_2, PIN_3, PIN_4, PIN_5, PIO0, PIO1, SPI0};
 use embassy_rp::pio::{self, Pio};
-use embassy_rp::pio_programs::i2s::{PioI2sOut, PioI2sOutProgram};
 use embassy_rp::spi::{self, Spi};
 use embassy_time::Timer;
 use embedded_hal_bus::spi::ExclusiveDevice;
-use embedded_sdmmc::asynchronous::{File, SdCard, ShortFileName, VolumeIdx, VolumeManager};
+use embedded_sdmmc::asynchronous::{File, SdCard, VolumeIdx, VolumeManager};
 use {defmt_rtt as _, panic_probe as _};
 
 // mod ble;
 mod audio_playback;
-use audio_playback::play_file;
+use audio_playback::{Ended, PAUSED, TRANSPORT, play_file};
+mod buttons;
+use buttons::{Button, Buttons};
+mod crossfade;
+mod decoder;
 mod display;
+mod downmix;
+mod dynamics;
+mod eq;
+use eq::EQ;
 mod file_reader;
+mod frame;
 use file_reader::{DummyTimeSource, Library, MAX_DIRS, MAX_FILES, MAX_VOLUMES, SD};
+mod i2s;
+mod index;
+use i2s::I2sOut;
+use index::Level as LibraryLevel;
+mod queue;
+use queue::{COMMANDS, Command, IDLE, ModeChanges, Queue, QueueTracks, SHUFFLE, open_track};
+mod replaygain;
+mod resample;
+mod settings;
+mod shuffle;
+mod volume;
 
 bind_interrupts!(struct Irqs {
     // i2s
@@ -74,11 +95,8 @@ async fn main(spawner: Spawner) {
     };
 
     // i2s DAC
+    // The sample rate is set per track in `play_file`
     let i2s = {
-        const SAMPLE_RATE: u32 = 8_000;
-        const BIT_DEPTH: u32 = 16; // this is the highest bit depth for stereo?
-        const CHANNELS: u32 = 2;
-
         // Setup pio state machine for i2s output
         let Pio {
             mut common, sm0, ..
@@ -90,25 +108,42 @@ async fn main(spawner: Spawner) {
 
         let _deem = Output::new(p.PIN_8, Level::High);
 
-        let program = PioI2sOutProgram::new(&mut common);
-        PioI2sOut::new(
+        I2sOut::new(
             &mut common,
             sm0,
             p.DMA_CH0,
             data_pin,
             bit_clock_pin,
             left_right_clock_pin,
-            SAMPLE_RATE,
-            BIT_DEPTH,
-            CHANNELS,
-            &program,
         )
     };
-    unwrap!(spawner.spawn(reader(sdcard, i2s)))
+    unwrap!(spawner.spawn(player(sdcard, i2s)));
+
+    // Start with the whole library until the buttons pick something else
+    COMMANDS.send(Command::PlayAll).await;
+
+    let buttons = Buttons {
+        volume_up: Button::new(p.PIN_6),
+        volume_down: Button::new(p.PIN_7),
+        play: Button::new(p.PIN_9),
+        next: Button::new(p.PIN_12),
+        previous: Button::new(p.PIN_16),
+        mode: Button::new(p.PIN_17),
+        browse: Button::new(p.PIN_21),
+    };
+    join(buttons.run(), async {
+        // redraw what the buttons and the player changed
+        loop {
+            media_ui.update_volume();
+            media_ui.update_paused();
+            Timer::after_millis(50).await;
+        }
+    })
+    .await;
 }
 
 #[embassy_executor::task]
-async fn reader(sdcard: SD, mut i2s: PioI2sOut<'static, PIO0, 0>) {
+async fn player(sdcard: SD, mut i2s: I2sOut<'static, PIO0, 0>) {
     let volume_mgr = VolumeManager::<_, _, MAX_DIRS, MAX_FILES, MAX_VOLUMES>::new_with_limits(
         sdcard,
         DummyTimeSource {},
@@ -130,32 +165,91 @@ async fn reader(sdcard: SD, mut i2s: PioI2sOut<'static, PIO0, 0>) {
     let mut library: Library = Library::new(volume);
     info!("indexing music");
     library.discover_music().await;
-    info!("music: discovererd {:?}", library.artists());
+    info!(
+        "music: indexed {} artists, {} albums and {} songs",
+        library.len(LibraryLevel::Artists),
+        library.len(LibraryLevel::Albums),
+        library.len(LibraryLevel::Songs)
+    );
+    settings::load(&library).await;
+    EQ.load(&library).await;
 
-    let root = library.get_root_dir();
+    let mut queue = Queue::new();
+    // repeat and shuffle changes while playing, the queue picks them up when it moves on
+    let changes = ModeChanges::default();
+    // the track after the playing one, opened early so it can follow without a gap
+    let mut upcoming = None;
 
     loop {
-        let artist = &library.artists().as_ref().unwrap()[0];
-        let artist_dir = root.open_dir(artist.name.as_str()).await.unwrap();
-        let album = &artist.albums[0];
-        let album_dir = artist_dir.open_dir(album.name.as_str()).await.unwrap();
-        let song_name = &album.songs[3];
-        let file = album_dir
-            .open_file_in_dir(
-                ShortFileName::create_from_str(song_name.as_str()).unwrap(),
-                embedded_sdmmc::asynchronous::Mode::ReadOnly,
-            )
-            .await
-            .unwrap();
-
-        let mut audio_file: AudioFile<SD, DummyTimeSource, MAX_DIRS, MAX_FILES, MAX_VOLUMES> =
-            AudioFile::new_wav(file).await.unwrap();
-
-        info!("playing {}", song_name);
-        play_file(&mut i2s, &mut audio_file).await;
-
-        audio_file.destroy().close().await.unwrap();
-        album_dir.close().unwrap();
-        artist_dir.close().unwrap();
+        // Idle until something is queued
+        let Some(track) = queue.current() else {
+            PAUSED.store(true, Ordering::Relaxed);
+            IDLE.store(true, Ordering::Relaxed);
+            // a pause or seek sent as the last track ended is not for the next one
+            while TRANSPORT.try_receive().is_ok() {}
+            queue.handle(COMMANDS.receive().await, &library).await;
+            continue;
+        };
+        IDLE.store(false, Ordering::Relaxed);
+
+        let mut audio_file = match upcoming.take() {
+            Some(audio_file) => audio_file,
+            None => match open_track(&library, track).await {
+                Some(audio_file) => audio_file,
+                None => {
+                    queue.next();
+                    continue;
+                }
+            },
+        };
+
+        // A command cuts the song short, the files are still closed before it is handled
+        let mut tracks = QueueTracks {
+            queue: &mut queue,
+            library: &library,
+            changes: &changes,
+        };
+        let command = match select(
+            play_file(
+                &mut i2s,
+                &mut audio_file,
+                &mut upcoming,
+                &mut tracks,
+                TRANSPORT.receiver(),
+            ),
+            async {
+                loop {
+                    if let Some(command) = changes.keep(COMMANDS.receive().await) {
+                        break command;
+                    }
+                }
+            },
+        )
+        .await
+        {
+            Either::First(Ended::Finished) => None,
+            Either::First(Ended::Stopped) => Some(Command::Stop),
+            Either::Second(command) => Some(command),
+        };
+
+        audio_file.close().await;
+        // the card is only written between tracks, and only when something changed
+        if EQ.is_changed() {
+            EQ.save(&library).await;
+        }
+        if SHUFFLE.is_changed() {
+            settings::save(&library).await;
+        }
+        match command {
+            Some(command) => {
+                // the queue changes, so the track opened after this one is not needed
+                if let Some(audio_file) = upcoming.take() {
+                    audio_file.close().await;
+                }
+                queue.handle(command, &library).await
+            }
+            None => queue.finished(),
+        }
+        queue.apply(&changes);
     }
 }
@@ -0,0 +1,545 @@
+use crate::audio_playback::TrackSource;
+use crate::decoder::{self, Decoder};
+use crate::file_reader::{Library, MAX_NAME_LEN};
+use crate::index::{Entry, Level};
+use crate::shuffle::{self, Rng};
+use core::cell::Cell;
+use core::fmt::Write;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
+use defmt::{Format, info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Instant;
+use heapless::{String, Vec};
+
+// Most songs queued at once, the library itself holds any number of songs
+pub const MAX_QUEUE: usize = 4096;
+
+// Commands for the player task, anything can send them
+pub static COMMANDS: Channel<CriticalSectionRawMutex, Command, 4> = Channel::new();
+// Whether nothing is queued, shared with the buttons
+pub static IDLE: AtomicBool = AtomicBool::new(true);
+// Shuffle mode and seed as kept in the settings file, so a shuffled queue plays in the same order
+// after a reboot
+pub static SHUFFLE: ShuffleSettings = ShuffleSettings::new();
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    // Replace the queue with every song in the library
+    PlayAll,
+    // Replace the queue with every album of an artist, past the last artist it starts over
+    PlayArtist(u32),
+    // An album by its position among the albums of the artist, past the last one it starts over
+    PlayAlbum { artist: u32, album: u32 },
+    Next,
+    Previous,
+    // Stop playing and clear the queue
+    Stop,
+    SetRepeat(Repeat),
+    SetShuffle(Shuffle),
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Repeat {
+    Off,
+    // Plays the current track again when it ends
+    One,
+    // Starts over once the last track ends
+    All,
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Shuffle {
+    Off,
+    Tracks,
+    // Plays albums in a random order, but every album from start to end
+    Albums,
+}
+
+impl Shuffle {
+    const ALL: [Self; 3] = [Self::Off, Self::Tracks, Self::Albums];
+
+    // As written in the settings file
+    fn name(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Tracks => "tracks",
+            Self::Albums => "albums",
+        }
+    }
+}
+
+pub struct ShuffleSettings {
+    shuffle: AtomicU8,
+    seed: AtomicU32,
+    // not written to the card yet
+    changed: AtomicBool,
+}
+
+impl ShuffleSettings {
+    const fn new() -> Self {
+        Self {
+            shuffle: AtomicU8::new(Shuffle::Off as u8),
+            seed: AtomicU32::new(0),
+            changed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn get(&self) -> (Shuffle, u32) {
+        (
+            Shuffle::ALL[self.shuffle.load(Ordering::Relaxed) as usize],
+            self.seed.load(Ordering::Relaxed),
+        )
+    }
+
+    fn store(&self, shuffle: Shuffle, seed: u32) {
+        if self.get() != (shuffle, seed) {
+            self.shuffle.store(shuffle as u8, Ordering::Relaxed);
+            self.seed.store(seed, Ordering::Relaxed);
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Applies a line of the settings file, false when the key is not a shuffle setting
+    pub fn set(&self, key: &[u8], value: &[u8]) -> bool {
+        match key {
+            b"shuffle" => {
+                if let Some(shuffle) = Shuffle::ALL
+                    .iter()
+                    .find(|shuffle| value.eq_ignore_ascii_case(shuffle.name().as_bytes()))
+                {
+                    self.shuffle.store(*shuffle as u8, Ordering::Relaxed);
+                }
+            }
+            b"shuffle_seed" => {
+                if let Some(seed) = str::from_utf8(value).ok().and_then(|v| v.parse().ok()) {
+                    self.seed.store(seed, Ordering::Relaxed);
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    // Whether there is a change that was not written to the card yet
+    pub fn is_changed(&self) -> bool {
+        self.changed.load(Ordering::Relaxed)
+    }
+
+    // The settings file lines, once after every change
+    pub fn take_changed(&self) -> Option<String<48>> {
+        if !self.changed.swap(false, Ordering::Relaxed) {
+            return None;
+        }
+        let (shuffle, seed) = self.get();
+        let mut lines = String::new();
+        // the longest lines fit
+        write!(
+            lines,
+            "shuffle = {}\nshuffle_seed = {}\n",
+            shuffle.name(),
+            seed
+        )
+        .unwrap();
+        Some(lines)
+    }
+}
+
+// Repeat and shuffle changes sent while a track plays. They wait until the queue moves on,
+// so the playing track is not cut short and the already opened next track stays next
+#[derive(Default)]
+pub struct ModeChanges {
+    repeat: Cell<Option<Repeat>>,
+    shuffle: Cell<Option<Shuffle>>,
+}
+
+impl ModeChanges {
+    // Keeps a repeat or shuffle change, every other command is handed back
+    pub fn keep(&self, command: Command) -> Option<Command> {
+        match command {
+            Command::SetRepeat(repeat) => self.repeat.set(Some(repeat)),
+            Command::SetShuffle(shuffle) => self.shuffle.set(Some(shuffle)),
+            command => return Some(command),
+        }
+        info!("[QUEUE] {}", command);
+        None
+    }
+}
+
+// A song by its position in the library
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct Track {
+    pub song: u32,
+}
+
+pub struct Queue {
+    // the queued songs follow each other in the library, `len` of them from `first`
+    first: u32,
+    len: u16,
+    // where each queued album starts, counted from `first`
+    albums: Vec<u16, MAX_QUEUE>,
+    // the order the songs are played in, counted from `first`
+    order: Vec<u16, MAX_QUEUE>,
+    // index into `order`, `None` once the end of the queue is reached or nothing was queued yet
+    position: Option<usize>,
+    repeat: Repeat,
+    shuffle: Shuffle,
+    seed: u32,
+}
+
+impl Queue {
+    // Starts out with the shuffle settings from the card
+    pub fn new() -> Self {
+        let (shuffle, seed) = SHUFFLE.get();
+        Self {
+            first: 0,
+            len: 0,
+            albums: Vec::new(),
+            order: Vec::new(),
+            position: None,
+            repeat: Repeat::Off,
+            shuffle,
+            seed,
+        }
+    }
+
+    pub fn current(&self) -> Option<Track> {
+        self.track_at(self.position?)
+    }
+
+    // The track `finished` moves on to
+    pub fn upcoming(&self) -> Option<Track> {
+        match self.repeat {
+            Repeat::One => self.current(),
+            repeat => self.track_at(self.following(self.position?, repeat == Repeat::All)?),
+        }
+    }
+
+    fn track_at(&self, position: usize) -> Option<Track> {
+        let song = *self.order.get(position)?;
+        Some(Track {
+            song: self.first + song as u32,
+        })
+    }
+
+    pub async fn handle(&mut self, command: Command, library: &Library<'_>) {
+        info!("[QUEUE] {}", command);
+        match command {
+            Command::PlayAll => self.load(library, 0..library.len(Level::Albums)).await,
+            Command::PlayArtist(artist) => {
+                let albums = match Self::artist(library, artist).await {
+                    Some(artist) => artist.children(),
+                    None => 0..0,
+                };
+                self.load(library, albums).await
+            }
+            Command::PlayAlbum { artist, album } => {
+                let albums = match Self::artist(library, artist).await {
+                    Some(artist) if artist.count > 0 => {
+                        let album = artist.first + album % artist.count;
+                        album..album + 1
+                    }
+                    _ => 0..0,
+                };
+                self.load(library, albums).await
+            }
+            Command::Next => self.next(),
+            Command::Previous => self.previous(),
+            Command::Stop => {
+                self.len = 0;
+                self.albums.clear();
+                self.order.clear();
+                self.position = None;
+            }
+            Command::SetRepeat(repeat) => self.repeat = repeat,
+            Command::SetShuffle(shuffle) => self.set_shuffle(shuffle),
+        }
+    }
+
+    // An artist by its position, counted around the library as often as it takes
+    async fn artist(library: &Library<'_>, artist: u32) -> Option<Entry> {
+        let artist = artist.checked_rem(library.len(Level::Artists))?;
+        library.entry(Level::Artists, artist).await
+    }
+
+    // Applies the repeat and shuffle changes kept while a track played
+    pub fn apply(&mut self, changes: &ModeChanges) {
+        if let Some(repeat) = changes.repeat.take() {
+            self.repeat = repeat;
+        }
+        if let Some(shuffle) = changes.shuffle.take() {
+            self.set_shuffle(shuffle);
+        }
+    }
+
+    fn set_shuffle(&mut self, shuffle: Shuffle) {
+        // a new order every time shuffle is turned on, the time it happens at is as good as random
+        if self.shuffle == Shuffle::Off && shuffle != Shuffle::Off {
+            self.seed = Instant::now().as_ticks() as u32;
+        }
+        self.shuffle = shuffle;
+        SHUFFLE.store(shuffle, self.seed);
+        // keep playing the current track, the new order carries on from it
+        let current = self.position.map(|position| self.order[position]);
+        self.reorder();
+        self.position = match current {
+            Some(current) => self.order.iter().position(|track| *track == current),
+            None => None,
+        };
+    }
+
+    // Called once the current track played to its end
+    pub fn finished(&mut self) {
+        if self.repeat != Repeat::One {
+            self.advance(self.repeat == Repeat::All);
+        }
+    }
+
+    // Skips to the next track, with repeat on the queue wraps around
+    pub fn next(&mut self) {
+        self.advance(self.repeat != Repeat::Off);
+    }
+
+    // Goes back a track, the first track is started over unless repeat wraps to the last one
+    pub fn previous(&mut self) {
+        if let Some(position) = &mut self.position {
+            *position = match *position {
+                0 if self.repeat != Repeat::Off => self.order.len() - 1,
+                position => position.saturating_sub(1),
+            };
+        }
+    }
+
+    fn advance(&mut self, wrap: bool) {
+        self.position = self
+            .position
+            .and_then(|position| self.following(position, wrap));
+    }
+
+    fn following(&self, position: usize, wrap: bool) -> Option<usize> {
+        match position + 1 {
+            next if next < self.order.len() => Some(next),
+            _ if wrap => Some(0),
+            _ => None,
+        }
+    }
+
+    // Sets the play order for the queued tracks
+    fn reorder(&mut self) {
+        self.order.clear();
+        // the queue never holds more than u16::MAX tracks
+        self.order.extend(0..self.len);
+
+        let mut rng = Rng::new(self.seed);
+        match self.shuffle {
+            Shuffle::Off => (),
+            Shuffle::Tracks => shuffle::shuffle(&mut self.order, &mut rng),
+            Shuffle::Albums => {
+                let albums = &self.albums;
+                shuffle::shuffle_albums(
+                    &mut self.order,
+                    |song| albums.partition_point(|start| *start <= song),
+                    &mut rng,
+                )
+            }
+        }
+    }
+
+    // Queues every song of `albums`, in library order
+    async fn load(&mut self, library: &Library<'_>, albums: Range<u32>) {
+        self.len = 0;
+        self.albums.clear();
+        for album in albums {
+            let Some(album) = library.entry(Level::Albums, album).await else {
+                break;
+            };
+            if !self.push_album(album.first, album.count) {
+                warn!("[QUEUE] too many songs, increase MAX_QUEUE");
+                break;
+            }
+        }
+        self.start();
+    }
+
+    // Queues the `count` songs from `first` after the queued ones, false when they do not fit.
+    // The songs of an album follow the ones of the album before it in the library
+    fn push_album(&mut self, first: u32, count: u32) -> bool {
+        if count == 0 {
+            return true;
+        }
+        let len = self.len as u32 + count;
+        if len > MAX_QUEUE as u32 {
+            return false;
+        }
+        if self.albums.is_empty() {
+            self.first = first;
+        }
+        // there are fewer albums than songs
+        self.albums.push(self.len).unwrap();
+        self.len = len as u16;
+        true
+    }
+
+    // Plays the queued songs from the first one in their order
+    fn start(&mut self) {
+        self.reorder();
+        self.position = (!self.order.is_empty()).then_some(0);
+    }
+}
+
+// Opens a track for playback, logs why when it cannot be played
+pub async fn open_track<'a>(library: &'a Library<'_>, track: Track) -> Option<Decoder<'a>> {
+    let song = library.entry(Level::Songs, track.song).await?;
+    let album = library.entry(Level::Albums, song.parent).await?;
+    let artist = library.entry(Level::Artists, album.parent).await?;
+    let mut buf = [0; MAX_NAME_LEN];
+    let song_name = library.name(&song, &mut buf).await?;
+
+    let file = match library
+        .open_song(&artist.short_name, &album.short_name, &song.short_name)
+        .await
+    {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("cannot open {}: {}", song_name, e);
+            return None;
+        }
+    };
+    match decoder::open(file, song_name).await {
+        Ok(audio_file) => {
+            info!("opened {}", song_name);
+            Some(audio_file)
+        }
+        Err(e) => {
+            warn!("skipping {}: {}", song_name, e);
+            None
+        }
+    }
+}
+
+// Hands the queued tracks to `play_file`, so they follow each other without a gap
+pub struct QueueTracks<'q, 'a> {
+    pub queue: &'q mut Queue,
+    pub library: &'a Library<'a>,
+    pub changes: &'q ModeChanges,
+}
+
+impl<'a> TrackSource<'a> for QueueTracks<'_, 'a> {
+    async fn open_next(&mut self) -> Option<Decoder<'a>> {
+        self.queue.apply(self.changes);
+        open_track(self.library, self.queue.upcoming()?).await
+    }
+
+    fn advance(&mut self) {
+        // changes since the next track was opened only count from it on
+        self.queue.finished();
+        self.queue.apply(self.changes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A queue of `albums` songs each, played in library order
+    fn queued(albums: &[u32], repeat: Repeat) -> Queue {
+        let mut queue = Queue::new();
+        queue.shuffle = Shuffle::Off;
+        queue.repeat = repeat;
+        let mut first = 100;
+        for &count in albums {
+            assert!(queue.push_album(first, count));
+            first += count;
+        }
+        queue.start();
+        queue
+    }
+
+    fn song(queue: &Queue) -> Option<u32> {
+        queue.current().map(|track| track.song)
+    }
+
+    #[test]
+    fn next_stops_after_the_last_track_unless_repeat_wraps() {
+        let mut queue = queued(&[2, 1], Repeat::Off);
+        assert_eq!(song(&queue), Some(100));
+        queue.next();
+        queue.next();
+        assert_eq!(song(&queue), Some(102));
+        queue.next();
+        assert_eq!(song(&queue), None);
+        // nothing is left to move on from
+        queue.next();
+        assert_eq!(song(&queue), None);
+
+        for repeat in [Repeat::One, Repeat::All] {
+            let mut queue = queued(&[2, 1], repeat);
+            queue.next();
+            queue.next();
+            queue.next();
+            assert_eq!(song(&queue), Some(100), "{:?}", repeat);
+        }
+    }
+
+    #[test]
+    fn previous_starts_the_first_track_over_unless_repeat_wraps() {
+        let mut queue = queued(&[3], Repeat::Off);
+        queue.next();
+        queue.previous();
+        assert_eq!(song(&queue), Some(100));
+        queue.previous();
+        assert_eq!(song(&queue), Some(100));
+
+        for repeat in [Repeat::One, Repeat::All] {
+            let mut queue = queued(&[3], repeat);
+            queue.previous();
+            assert_eq!(song(&queue), Some(102), "{:?}", repeat);
+        }
+    }
+
+    #[test]
+    fn finished_follows_the_repeat_mode() {
+        let mut queue = queued(&[2], Repeat::Off);
+        queue.finished();
+        assert_eq!(song(&queue), Some(101));
+        assert_eq!(queue.upcoming(), None);
+        queue.finished();
+        assert_eq!(song(&queue), None);
+
+        let mut queue = queued(&[2], Repeat::One);
+        queue.finished();
+        queue.finished();
+        assert_eq!(song(&queue), Some(100));
+        assert_eq!(queue.upcoming(), queue.current());
+
+        let mut queue = queued(&[2], Repeat::All);
+        queue.finished();
+        assert_eq!(queue.upcoming(), Some(Track { song: 100 }));
+        queue.finished();
+        assert_eq!(song(&queue), Some(100));
+    }
+
+    #[test]
+    fn an_album_past_max_queue_is_left_out() {
+        let mut queue = queued(&[], Repeat::Off);
+        assert!(queue.push_album(0, MAX_QUEUE as u32 - 1));
+        // empty albums are skipped
+        assert!(queue.push_album(MAX_QUEUE as u32 - 1, 0));
+        assert!(!queue.push_album(MAX_QUEUE as u32 - 1, 2));
+        assert!(queue.push_album(MAX_QUEUE as u32 - 1, 1));
+        assert!(!queue.push_album(MAX_QUEUE as u32, 1));
+        queue.start();
+        assert_eq!(queue.len as usize, MAX_QUEUE);
+        assert_eq!(queue.albums, [0, MAX_QUEUE as u16 - 1]);
+        assert_eq!(queue.order.len(), MAX_QUEUE);
+        assert_eq!(song(&queue), Some(0));
+    }
+
+    #[test]
+    fn nothing_queued_plays_nothing() {
+        let queue = queued(&[0], Repeat::All);
+        assert_eq!(song(&queue), None);
+        assert_eq!(queue.upcoming(), None);
+    }
+}
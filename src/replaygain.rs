@@ -0,0 +1,374 @@
+// ReplayGain loudness normalization.
+// The gains are read from the tags of a track and applied by the volume stage, so old and newly
+// mastered albums play at about the same loudness
+
+use crate::settings::parse_fixed;
+use crate::volume::{MAX_GAIN_Q15, ONE_Q15, centibels_to_q15};
+use core::sync::atomic::{AtomicI16, AtomicU8, Ordering};
+use defmt::Format;
+use heapless::Vec;
+
+// Most amplification a tag can ask for, the volume stage cannot go above +6dB
+const MAX_GAIN_CB: i32 = 60;
+// Gain for tracks without tags, about what newer masters get from their tags
+const DEFAULT_FALLBACK_CB: i16 = -60;
+// Longest TXXX frame that is looked at, ReplayGain frames are far shorter
+const MAX_TXXX_LEN: usize = 96;
+
+// ReplayGain settings shared with the ui
+pub static REPLAY_GAIN: Settings = Settings::new(Mode::Album, DEFAULT_FALLBACK_CB);
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Off,
+    Track,
+    // Keeps the loudness differences between the tracks of an album
+    Album,
+}
+
+impl Mode {
+    const ALL: [Mode; 3] = [Mode::Off, Mode::Track, Mode::Album];
+
+    // As written in the settings file
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Off => "off",
+            Mode::Track => "track",
+            Mode::Album => "album",
+        }
+    }
+}
+
+pub struct Settings {
+    mode: AtomicU8,
+    // gain in centibels for tracks without ReplayGain tags
+    fallback_cb: AtomicI16,
+}
+
+impl Settings {
+    pub const fn new(mode: Mode, fallback_cb: i16) -> Self {
+        Self {
+            mode: AtomicU8::new(mode as u8),
+            fallback_cb: AtomicI16::new(fallback_cb),
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        Mode::ALL[self.mode.load(Ordering::Relaxed) as usize]
+    }
+
+    pub fn set_mode(&self, mode: Mode) {
+        self.mode.store(mode as u8, Ordering::Relaxed);
+    }
+
+    pub fn fallback_cb(&self) -> i16 {
+        self.fallback_cb.load(Ordering::Relaxed)
+    }
+
+    pub fn set_fallback_cb(&self, cb: i16) {
+        self.fallback_cb
+            .store(cb.min(MAX_GAIN_CB as i16), Ordering::Relaxed);
+    }
+
+    // Applies a line of the settings file, false when the key is not a ReplayGain setting
+    pub fn set(&self, key: &[u8], value: &[u8]) -> bool {
+        match key {
+            b"replaygain_mode" => {
+                if let Some(mode) = Mode::ALL
+                    .iter()
+                    .find(|mode| value.eq_ignore_ascii_case(mode.name().as_bytes()))
+                {
+                    self.set_mode(*mode);
+                }
+            }
+            b"replaygain_preamp" => {
+                if let Some(cb) = parse_fixed(value, 1) {
+                    self.set_fallback_cb(cb.clamp(i16::MIN as i32, i16::MAX as i32) as i16);
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    // Gain for a track in Q15
+    pub fn gain_q15(&self, tags: &ReplayGain) -> i32 {
+        tags.gain_q15(self.mode(), self.fallback_cb())
+    }
+}
+
+// The ReplayGain tags of a track
+#[derive(Debug, Format, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayGain {
+    // gains in centibels
+    pub track_gain: Option<i16>,
+    pub album_gain: Option<i16>,
+    // sample peaks in Q15, full scale is 1 << 15
+    pub track_peak: Option<u32>,
+    pub album_peak: Option<u32>,
+}
+
+impl ReplayGain {
+    // Picks up a REPLAYGAIN_* tag, other tags are ignored
+    pub fn set_tag(&mut self, key: &[u8], value: &[u8]) {
+        let key = key.trim_ascii();
+        let is = |name: &str| key.eq_ignore_ascii_case(name.as_bytes());
+        let gain =
+            || parse_fixed(value, 1).map(|cb| cb.clamp(i16::MIN as i32, i16::MAX as i32) as i16);
+        // peaks are written with 6 decimals
+        let peak = || {
+            parse_fixed(value, 6)
+                .map(|peak| (peak.max(0) as i64 * ONE_Q15 as i64 / 1_000_000) as u32)
+        };
+
+        if is("REPLAYGAIN_TRACK_GAIN") {
+            self.track_gain = gain();
+        } else if is("REPLAYGAIN_ALBUM_GAIN") {
+            self.album_gain = gain();
+        } else if is("REPLAYGAIN_TRACK_PEAK") {
+            self.track_peak = peak();
+        } else if is("REPLAYGAIN_ALBUM_PEAK") {
+            self.album_peak = peak();
+        }
+    }
+
+    // Gain in Q15, lowered where needed so the peak does not clip.
+    // Each mode falls back to the other gain when its own is missing
+    pub fn gain_q15(&self, mode: Mode, fallback_cb: i16) -> i32 {
+        let track = (self.track_gain, self.track_peak);
+        let album = (self.album_gain, self.album_peak);
+        let (gain, peak) = match mode {
+            Mode::Off => return ONE_Q15,
+            Mode::Track if track.0.is_some() => track,
+            Mode::Track => album,
+            Mode::Album if album.0.is_some() => album,
+            Mode::Album => track,
+        };
+        let Some(gain) = gain else {
+            return centibels_to_q15(fallback_cb as i32);
+        };
+
+        let gain = centibels_to_q15((gain as i32).min(MAX_GAIN_CB));
+        match peak {
+            Some(peak) if peak > 0 => {
+                let unclipped = ONE_Q15 as i64 * ONE_Q15 as i64 / peak as i64;
+                gain.min(unclipped as i32)
+            }
+            _ => gain,
+        }
+        .min(MAX_GAIN_Q15)
+    }
+}
+
+// Reads the tags of a vorbis comment block, as used by FLAC and Ogg Vorbis files
+pub fn parse_vorbis_comments(block: &[u8], tags: &mut ReplayGain) {
+    let read_u32 = |at: usize| -> Option<usize> {
+        Some(u32::from_le_bytes(block.get(at..at.checked_add(4)?)?.try_into().ok()?) as usize)
+    };
+    let Some(vendor_len) = read_u32(0) else {
+        return;
+    };
+    // the lengths come from the file, so they may point anywhere
+    let Some(mut at) = vendor_len.checked_add(4) else {
+        return;
+    };
+    let Some(count) = read_u32(at) else {
+        return;
+    };
+    at += 4;
+
+    for _ in 0..count {
+        let Some(comment) = read_u32(at)
+            .and_then(|len| (at + 4).checked_add(len))
+            .and_then(|end| block.get(at + 4..end))
+        else {
+            return;
+        };
+        at += 4 + comment.len();
+        if let Some(split) = comment.iter().position(|b| *b == b'=') {
+            tags.set_tag(&comment[..split], &comment[split + 1..]);
+        }
+    }
+}
+
+// Reads an ID3v2 TXXX frame, the body after the frame header.
+// Only ascii text is kept, which is all ReplayGain frames hold
+pub fn parse_id3_txxx(frame: &[u8], tags: &mut ReplayGain) {
+    let Some((&encoding, text)) = frame.split_first() else {
+        return;
+    };
+    let mut ascii: Vec<u8, MAX_TXXX_LEN> = Vec::new();
+    match encoding {
+        // utf-16 with a byte order mark and utf-16be
+        1 | 2 => {
+            let little = text.starts_with(&[0xFF, 0xFE]);
+            for unit in text.chunks_exact(2) {
+                let (low, high) = if little {
+                    (unit[0], unit[1])
+                } else {
+                    (unit[1], unit[0])
+                };
+                // drops the byte order marks and anything outside of ascii
+                if high == 0 && low < 0x80 && ascii.push(low).is_err() {
+                    return;
+                }
+            }
+        }
+        // latin-1 and utf-8
+        _ => {
+            if ascii.extend_from_slice(text).is_err() {
+                return;
+            }
+        }
+    }
+
+    // the description and the value are each terminated by a 0
+    let mut fields = ascii.split(|b| *b == 0);
+    if let (Some(description), Some(value)) = (fields.next(), fields.next()) {
+        tags.set_tag(description, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vorbis_comments(comments: &[&[u8]]) -> std::vec::Vec<u8> {
+        let mut block = std::vec::Vec::new();
+        block.extend_from_slice(&6u32.to_le_bytes());
+        block.extend_from_slice(b"vendor");
+        block.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            block.extend_from_slice(comment);
+        }
+        block
+    }
+
+    #[test]
+    fn reads_replaygain_from_vorbis_comments() {
+        let block = vorbis_comments(&[
+            b"TITLE=Song",
+            b"replaygain_track_gain=-7.43 dB",
+            b"REPLAYGAIN_ALBUM_GAIN=+1.20 dB",
+            b"REPLAYGAIN_TRACK_PEAK=0.988525",
+            b"REPLAYGAIN_ALBUM_PEAK=1.5",
+        ]);
+        let mut tags = ReplayGain::default();
+        parse_vorbis_comments(&block, &mut tags);
+        assert_eq!(
+            tags,
+            ReplayGain {
+                track_gain: Some(-74),
+                album_gain: Some(12),
+                track_peak: Some(32_391),
+                album_peak: Some(49_152),
+            }
+        );
+    }
+
+    #[test]
+    fn broken_vorbis_comment_lengths_stop_the_parse() {
+        let mut block =
+            vorbis_comments(&[b"REPLAYGAIN_TRACK_GAIN=-1.0", b"REPLAYGAIN_ALBUM_GAIN=-2.0"]);
+        // the second comment claims to run far past the end of the block
+        let second = 4 + 6 + 4 + 4 + 26;
+        block[second..second + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut tags = ReplayGain::default();
+        parse_vorbis_comments(&block, &mut tags);
+        assert_eq!(tags.track_gain, Some(-10));
+        assert_eq!(tags.album_gain, None);
+
+        let mut block = vorbis_comments(&[b"REPLAYGAIN_TRACK_GAIN=-1.0"]);
+        block[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut tags = ReplayGain::default();
+        parse_vorbis_comments(&block, &mut tags);
+        assert_eq!(tags, ReplayGain::default());
+    }
+
+    fn utf16(text: &str, little: bool) -> std::vec::Vec<u8> {
+        let mut bytes = if little {
+            std::vec![0xFF, 0xFE]
+        } else {
+            std::vec![0xFE, 0xFF]
+        };
+        for unit in text.encode_utf16() {
+            match little {
+                true => bytes.extend_from_slice(&unit.to_le_bytes()),
+                false => bytes.extend_from_slice(&unit.to_be_bytes()),
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn reads_utf16_txxx_frames_with_either_byte_order() {
+        for little in [true, false] {
+            let mut frame = std::vec![1];
+            frame.extend(utf16("REPLAYGAIN_ALBUM_GAIN\0", little));
+            frame.extend(utf16("-3.10 dB\0", little));
+            let mut tags = ReplayGain::default();
+            parse_id3_txxx(&frame, &mut tags);
+            assert_eq!(tags.album_gain, Some(-31), "little endian {}", little);
+        }
+    }
+
+    #[test]
+    fn reads_latin1_txxx_frames() {
+        let mut tags = ReplayGain::default();
+        parse_id3_txxx(b"\0REPLAYGAIN_TRACK_PEAK\x000.5\0", &mut tags);
+        assert_eq!(tags.track_peak, Some(ONE_Q15 as u32 / 2));
+    }
+
+    #[test]
+    fn the_gain_is_lowered_so_the_peak_does_not_clip() {
+        // +6dB on a track peaking at half scale stays just below full scale
+        let tags = ReplayGain {
+            track_gain: Some(60),
+            track_peak: Some(ONE_Q15 as u32 / 2),
+            ..Default::default()
+        };
+        assert_eq!(tags.gain_q15(Mode::Track, 0), centibels_to_q15(60));
+
+        // with a peak at 0.8, +6dB would clip and is cut to 1 / 0.8
+        let tags = ReplayGain {
+            track_peak: Some(ONE_Q15 as u32 * 4 / 5),
+            ..tags
+        };
+        assert_eq!(tags.gain_q15(Mode::Track, 0), ONE_Q15 * 5 / 4);
+
+        // attenuation is left alone
+        let tags = ReplayGain {
+            track_gain: Some(-60),
+            ..tags
+        };
+        assert_eq!(tags.gain_q15(Mode::Track, 0), centibels_to_q15(-60));
+    }
+
+    #[test]
+    fn modes_fall_back_to_the_other_gain_then_the_setting() {
+        let tags = ReplayGain {
+            album_gain: Some(-20),
+            ..Default::default()
+        };
+        assert_eq!(tags.gain_q15(Mode::Track, -60), centibels_to_q15(-20));
+        assert_eq!(tags.gain_q15(Mode::Off, -60), ONE_Q15);
+        assert_eq!(
+            ReplayGain::default().gain_q15(Mode::Album, -60),
+            centibels_to_q15(-60)
+        );
+    }
+
+    #[test]
+    fn settings_lines_set_the_mode_and_preamp() {
+        let settings = Settings::new(Mode::Album, 0);
+        assert!(settings.set(b"replaygain_mode", b"Track"));
+        assert_eq!(settings.mode(), Mode::Track);
+        assert!(settings.set(b"replaygain_preamp", b"-4.5"));
+        assert_eq!(settings.fallback_cb(), -45);
+        // never more than the volume stage can amplify
+        assert!(settings.set(b"replaygain_preamp", b"+12"));
+        assert_eq!(settings.fallback_cb(), MAX_GAIN_CB as i16);
+        assert!(!settings.set(b"crossfade", b"2"));
+    }
+}
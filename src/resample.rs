@@ -0,0 +1,225 @@
+// Fixed point polyphase resampler.
+// Converts 16bit stereo frames from one sample rate to another
+// using a windowed sinc filter bank, without any floating point math
+
+use crate::frame::StereoFrame;
+
+// Number of input frames each output frame is interpolated from
+const TAPS: usize = 16;
+// Number of fractional positions between two input frames the filter bank is designed for
+const PHASES: usize = 128;
+// Filter cutoff as a fraction of the lower nyquist frequency (0.9 in Q15)
+const ROLLOFF_Q15: i64 = 29_491;
+
+const ONE_Q15: i64 = 1 << 15;
+const ONE_Q30: i64 = 1 << 30;
+const PI_Q30: i64 = 3_373_259_426;
+
+pub struct Resampler {
+    from: u32,
+    to: u32,
+    // position between the two centre history frames, in units of 1/`to` input frames
+    acc: u32,
+    // input frames to shift into the history before the next output frame
+    pending: u32,
+    history: [[i16; TAPS]; 2],
+    coefficients: [[i16; TAPS]; PHASES],
+}
+
+impl Resampler {
+    // `None` when either rate is 0, the rates come from the files
+    pub fn new(from: u32, to: u32) -> Option<Self> {
+        if from == 0 || to == 0 {
+            return None;
+        }
+
+        // the filter has to remove everything above the nyquist frequency of the lower rate
+        let cutoff_q15 = if to < from {
+            ROLLOFF_Q15 * to as i64 / from as i64
+        } else {
+            ROLLOFF_Q15
+        };
+
+        let mut coefficients = [[0i16; TAPS]; PHASES];
+        for (phase, taps) in coefficients.iter_mut().enumerate() {
+            let mut raw = [0i64; TAPS];
+            for (tap, value) in raw.iter_mut().enumerate() {
+                // distance of this tap from the output position in 1/PHASES input frames
+                let t = (tap as i64 - (TAPS as i64 / 2 - 1)) * PHASES as i64 - phase as i64;
+                *value = windowed_sinc_q30(t, cutoff_q15);
+            }
+
+            // normalize every phase to unity gain so there is no dc ripple between phases
+            let sum: i64 = raw.iter().sum();
+            for (coefficient, value) in taps.iter_mut().zip(raw) {
+                *coefficient = ((value * ONE_Q15 + sum / 2) / sum)
+                    .clamp(i16::MIN as i64, i16::MAX as i64) as i16;
+            }
+        }
+
+        Some(Self {
+            from,
+            to,
+            acc: 0,
+            // prime the history so the first output frame lines up with the first input frame
+            pending: TAPS as u32 / 2 + 1,
+            history: [[0; TAPS]; 2],
+            coefficients,
+        })
+    }
+
+    // Resamples as many frames as possible from `input` into `output`.
+    // Returns the number of input frames consumed and output frames produced
+    pub fn process(&mut self, input: &[StereoFrame], output: &mut [StereoFrame]) -> (usize, usize) {
+        let mut consumed = 0;
+        let mut produced = 0;
+
+        while produced < output.len() {
+            while self.pending > 0 {
+                let Some(frame) = input.get(consumed) else {
+                    return (consumed, produced);
+                };
+                self.push(*frame);
+                self.pending -= 1;
+                consumed += 1;
+            }
+
+            let phase = (self.acc as u64 * PHASES as u64 / self.to as u64) as usize;
+            let taps = &self.coefficients[phase];
+            let left = convolve(&self.history[0], taps);
+            let right = convolve(&self.history[1], taps);
+            output[produced] = StereoFrame::new(left, right);
+            produced += 1;
+
+            self.acc += self.from;
+            self.pending = self.acc / self.to;
+            self.acc %= self.to;
+        }
+
+        (consumed, produced)
+    }
+
+    fn push(&mut self, frame: StereoFrame) {
+        for (history, sample) in self.history.iter_mut().zip([frame.left(), frame.right()]) {
+            history.copy_within(1.., 0);
+            history[TAPS - 1] = sample;
+        }
+    }
+}
+
+fn convolve(history: &[i16; TAPS], taps: &[i16; TAPS]) -> i16 {
+    let sum: i32 = history
+        .iter()
+        .zip(taps)
+        .map(|(sample, tap)| *sample as i32 * *tap as i32)
+        .sum();
+    ((sum + (1 << 14)) >> 15).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+// Hann windowed sinc evaluated at `t` (in 1/PHASES input frames), scaled by the cutoff. Result in Q30
+fn windowed_sinc_q30(t: i64, cutoff_q15: i64) -> i64 {
+    let half_width = (TAPS as i64 / 2) * PHASES as i64;
+    if t.abs() >= half_width {
+        return 0;
+    }
+
+    // sinc(cutoff * t) * cutoff
+    let x_q30 = t * cutoff_q15 * (ONE_Q30 / ONE_Q15) / PHASES as i64;
+    let sinc_q30 = if x_q30 == 0 {
+        ONE_Q30
+    } else {
+        sin_pi_q30(x_q30) * ONE_Q30 / (x_q30 / ONE_Q15 * PI_Q30 / ONE_Q15)
+    };
+
+    // hann window: 0.5 + 0.5 * cos(pi * t / half_width)
+    let window_q30 = (ONE_Q30 + cos_pi_q30(t * ONE_Q30 / half_width)) / 2;
+
+    sinc_q30 * window_q30 / ONE_Q30 * cutoff_q15 / ONE_Q15
+}
+
+// sin(pi * x) with x and the result in Q30
+pub const fn sin_pi_q30(x: i64) -> i64 {
+    // reduce to a single half turn, sin(pi * (x + 1)) = -sin(pi * x)
+    let x = x.rem_euclid(2 * ONE_Q30);
+    let (x, sign) = if x >= ONE_Q30 {
+        (x - ONE_Q30, -1)
+    } else {
+        (x, 1)
+    };
+    // the half turn is symmetric around 0.5
+    let x = if x > ONE_Q30 / 2 { ONE_Q30 - x } else { x };
+
+    // taylor series up to the 9th power, accurate to ~4e-6 within a quarter turn
+    let a = x * PI_Q30 / ONE_Q30;
+    let a2 = a * a / ONE_Q30;
+    let mut term = a;
+    let mut sum = a;
+    // a while loop keeps this const, for the tables of the vorbis decoder
+    let mut power = 2;
+    while power < 10 {
+        term = -term * a2 / ONE_Q30 / (power * (power + 1));
+        sum += term;
+        power += 2;
+    }
+    sign * sum
+}
+
+// cos(pi * x) with x and the result in Q30
+pub fn cos_pi_q30(x: i64) -> i64 {
+    sin_pi_q30(x + ONE_Q30 / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Resamples one second of a full scale sine and returns the rms of the output,
+    // leaving out the filter settling at the start
+    fn resampled_rms(frequency: f64, from: u32, to: u32) -> f64 {
+        let amplitude = 16_000.0;
+        let input: Vec<StereoFrame> = (0..from)
+            .map(|n| {
+                let t = n as f64 / from as f64;
+                let sample =
+                    (amplitude * (2.0 * core::f64::consts::PI * frequency * t).sin()) as i16;
+                StereoFrame::new(sample, -sample)
+            })
+            .collect();
+
+        let mut resampler = Resampler::new(from, to).unwrap();
+        let mut output = vec![StereoFrame::SILENCE; to as usize + 64];
+        let (consumed, produced) = resampler.process(&input, &mut output);
+        assert_eq!(consumed, input.len());
+        assert!(produced.abs_diff(to as usize) <= TAPS);
+
+        let settled = &output[TAPS * 4..produced - TAPS];
+        for frame in settled {
+            assert_eq!(frame.left() as i32, -(frame.right() as i32));
+        }
+        let power: f64 = settled
+            .iter()
+            .map(|frame| (frame.left() as f64).powi(2))
+            .sum::<f64>()
+            / settled.len() as f64;
+        power.sqrt() / (amplitude / core::f64::consts::SQRT_2)
+    }
+
+    #[test]
+    fn keeps_the_level_of_a_tone_in_the_passband() {
+        let level = resampled_rms(1_000.0, 48_000, 44_100);
+        assert!((level - 1.0).abs() < 0.01, "level {}", level);
+    }
+
+    #[test]
+    fn rejects_tones_above_the_new_nyquist_frequency() {
+        // 23khz has no place at 44.1khz and would fold back to 21.1khz, it has to be 20db down
+        let level = resampled_rms(23_000.0, 48_000, 44_100);
+        assert!(level < 0.1, "alias at {}", level);
+    }
+
+    #[test]
+    fn refuses_a_rate_of_zero() {
+        assert!(Resampler::new(0, 44_100).is_none());
+        assert!(Resampler::new(48_000, 0).is_none());
+    }
+}
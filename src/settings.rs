@@ -0,0 +1,198 @@
+// Helpers for the settings files on the sd card.
+// Values are written as plain decimals so the files can be edited by hand
+
+use crate::crossfade::CROSSFADE;
+use crate::dynamics::DYNAMICS;
+use crate::file_reader::{Library, SdError};
+use crate::queue::SHUFFLE;
+use crate::replaygain::REPLAY_GAIN;
+use core::fmt;
+use defmt::warn;
+use heapless::Vec;
+
+const SETTINGS_FILE: &str = "SETTINGS.TXT";
+const SETTINGS_FILE_LEN: usize = 1024;
+// Written to the card when it has no settings file yet
+const DEFAULT_SETTINGS: &str = "\
+# Playback settings, one `name = value` per line. Levels are in dB and times in ms
+
+# Look-ahead limiter, keeps volume and EQ boosts from clipping in the dac
+limiter = on
+limiter_ceiling = -0.3
+limiter_release = 100
+
+# Compressor for small speakers, a ratio of 1.0 turns it off
+compressor_threshold = -18.0
+compressor_ratio = 1.0
+compressor_attack = 5
+compressor_release = 200
+compressor_makeup = 0.0
+
+# ReplayGain mode (off, track or album), and the level of tracks without ReplayGain tags
+replaygain_mode = album
+replaygain_preamp = -6.0
+
+# Seconds the end of a track is faded into the next one, 0 plays them back to back
+crossfade = 0
+
+# Shuffle mode (off, tracks or albums) and the seed of the shuffled order. The player picks a new
+# seed whenever shuffle is turned on and keeps it here, so the order is the same after a reboot
+shuffle = off
+shuffle_seed = 0
+";
+
+// Applies the settings file on the card, or puts the default one there when it has none.
+// Settings missing from the file keep their defaults
+pub async fn load(library: &Library<'_>) {
+    let mut buf = [0u8; SETTINGS_FILE_LEN];
+    let text = match library.read_file(SETTINGS_FILE, &mut buf).await {
+        Ok(len) => &buf[..len],
+        Err(SdError::NotFound) => {
+            warn!("[SETTINGS] no settings, writing the defaults");
+            if let Err(e) = library
+                .write_file(SETTINGS_FILE, DEFAULT_SETTINGS.as_bytes())
+                .await
+            {
+                warn!("[SETTINGS] cannot write {}: {}", SETTINGS_FILE, e);
+            }
+            DEFAULT_SETTINGS.as_bytes()
+        }
+        // the file is still there, it is not replaced just because this read failed
+        Err(e) => {
+            warn!(
+                "[SETTINGS] cannot read {}, using the defaults: {}",
+                SETTINGS_FILE, e
+            );
+            DEFAULT_SETTINGS.as_bytes()
+        }
+    };
+
+    for line in text.split(|b| *b == b'\n') {
+        let line = line.trim_ascii();
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+        let Some((key, value)) = setting(line) else {
+            warn!("[SETTINGS] skipping line: {=[u8]:a}", line);
+            continue;
+        };
+        if !DYNAMICS.set(key, value)
+            && !CROSSFADE.set(key, value)
+            && !SHUFFLE.set(key, value)
+            && !REPLAY_GAIN.set(key, value)
+        {
+            warn!("[SETTINGS] unknown setting: {=[u8]:a}", key);
+        }
+    }
+}
+
+// Writes the shuffle settings back to the card, once `SHUFFLE.is_changed()`.
+// Their lines are replaced where they are, everything else in the file is kept.
+// When the file cannot be read the change is saved after a later track
+pub async fn save(library: &Library<'_>) {
+    let mut buf = [0u8; SETTINGS_FILE_LEN];
+    let text = match library.read_file(SETTINGS_FILE, &mut buf).await {
+        Ok(len) => &buf[..len],
+        Err(e) => {
+            warn!("[SETTINGS] cannot read {}: {}", SETTINGS_FILE, e);
+            return;
+        }
+    };
+    let Some(shuffle) = SHUFFLE.take_changed() else {
+        return;
+    };
+
+    let mut out: Vec<u8, SETTINGS_FILE_LEN> = Vec::new();
+    let mut replaced = false;
+    let mut fits = true;
+    for line in text.split_inclusive(|b| *b == b'\n') {
+        let key = setting(line.trim_ascii()).map(|(key, _)| key);
+        if matches!(key, Some(b"shuffle" | b"shuffle_seed")) {
+            if !replaced {
+                fits &= out.extend_from_slice(shuffle.as_bytes()).is_ok();
+                replaced = true;
+            }
+            continue;
+        }
+        fits &= out.extend_from_slice(line).is_ok();
+    }
+    if !replaced {
+        if !out.is_empty() && !out.ends_with(b"\n") {
+            fits &= out.push(b'\n').is_ok();
+        }
+        fits &= out.extend_from_slice(shuffle.as_bytes()).is_ok();
+    }
+    if !fits {
+        warn!("[SETTINGS] {} is too long to update", SETTINGS_FILE);
+        return;
+    }
+    if let Err(e) = library.write_file(SETTINGS_FILE, &out).await {
+        warn!("[SETTINGS] cannot write {}: {}", SETTINGS_FILE, e);
+    }
+}
+
+// Splits a `name = value` line, comments have no `=` that counts
+fn setting(line: &[u8]) -> Option<(&[u8], &[u8])> {
+    if line.starts_with(b"#") {
+        return None;
+    }
+    let split = line.iter().position(|b| *b == b'=')?;
+    Some((line[..split].trim_ascii(), line[split + 1..].trim_ascii()))
+}
+
+// Reads a decimal like "-6.52 dB" as an integer with `decimals` digits after the point.
+// Further digits are cut off, anything after the number is ignored
+pub fn parse_fixed(text: &[u8], decimals: u32) -> Option<i32> {
+    let text = text.trim_ascii_start();
+    let (negative, text) = match text.split_first()? {
+        (b'-', rest) => (true, rest),
+        (b'+', rest) => (false, rest),
+        _ => (false, text),
+    };
+
+    let mut value: i32 = 0;
+    let mut digits = 0;
+    let mut fraction = None;
+    for &b in text {
+        match b {
+            b'0'..=b'9' if fraction.is_none_or(|fraction| fraction < decimals) => {
+                value = value.checked_mul(10)?.checked_add((b - b'0') as i32)?;
+                fraction = fraction.map(|fraction| fraction + 1);
+                digits += 1;
+            }
+            b'0'..=b'9' => (),
+            b'.' | b',' if fraction.is_none() => fraction = Some(0),
+            _ => break,
+        }
+    }
+    if digits == 0 {
+        return None;
+    }
+
+    for _ in fraction.unwrap_or(0)..decimals {
+        value = value.checked_mul(10)?;
+    }
+    Some(if negative { -value } else { value })
+}
+
+// Writes an integer with `decimals` digits after the point, the reverse of `parse_fixed`
+pub struct Fixed(pub i32, pub u32);
+
+impl fmt::Display for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Fixed(value, decimals) = *self;
+        let scale = 10u32.pow(decimals);
+        let magnitude = value.unsigned_abs();
+        let sign = if value < 0 { "-" } else { "" };
+        write!(f, "{}{}", sign, magnitude / scale)?;
+        if decimals > 0 {
+            write!(
+                f,
+                ".{:0width$}",
+                magnitude % scale,
+                width = decimals as usize
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+// Reproducible shuffling of the play queue.
+// The order only depends on the seed and what is queued, so the same seed gives the same order
+// after a reboot
+
+use heapless::Vec;
+
+// Used when no seed was chosen, xorshift never leaves a state of 0
+const DEFAULT_SEED: u32 = 0x2545_F491;
+
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            state: if seed == 0 { DEFAULT_SEED } else { seed },
+        }
+    }
+
+    // xorshift32
+    pub fn next(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    // Uniform value below `n`
+    fn below(&mut self, n: usize) -> usize {
+        ((self.next() as u64 * n as u64) >> 32) as usize
+    }
+}
+
+// Fisher-Yates shuffle
+pub fn shuffle<T>(items: &mut [T], rng: &mut Rng) {
+    for i in (1..items.len()).rev() {
+        items.swap(i, rng.below(i + 1));
+    }
+}
+
+// Shuffles whole albums, the tracks of an album keep their order.
+// `album` tells which album a track is on, the tracks of an album have to be next to each other
+pub fn shuffle_albums<const N: usize, K: PartialEq>(
+    order: &mut Vec<u16, N>,
+    album: impl Fn(u16) -> K,
+    rng: &mut Rng,
+) {
+    let mut starts: Vec<u16, N> = order
+        .iter()
+        .enumerate()
+        .filter(|(i, track)| *i == 0 || album(order[i - 1]) != album(**track))
+        .map(|(i, _)| i as u16)
+        .collect();
+    shuffle(&mut starts, rng);
+
+    let tracks = order.clone();
+    order.clear();
+    for start in starts {
+        let first = album(tracks[start as usize]);
+        for &track in tracks[start as usize..]
+            .iter()
+            .take_while(|track| album(**track) == first)
+        {
+            // `order` held all of these tracks before
+            let _ = order.push(track);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shuffled(seed: u32) -> Vec<u16, 64> {
+        let mut order: Vec<u16, 64> = (0..64).collect();
+        shuffle(&mut order, &mut Rng::new(seed));
+        order
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_order() {
+        assert_eq!(shuffled(1234), shuffled(1234));
+        assert_ne!(shuffled(1234), shuffled(1235));
+        // 0 is not a valid xorshift state and falls back to the default seed
+        assert_eq!(shuffled(0), shuffled(DEFAULT_SEED));
+    }
+
+    #[test]
+    fn every_track_is_played_once() {
+        for seed in 1..100 {
+            let mut order = shuffled(seed);
+            assert_ne!(order, (0..64).collect::<Vec<u16, 64>>());
+            order.sort_unstable();
+            assert_eq!(order, (0..64).collect::<Vec<u16, 64>>());
+        }
+    }
+
+    #[test]
+    fn albums_stay_together_and_in_order() {
+        // albums of 5, 1, 7, 3 and 4 tracks
+        let starts: [u16; 6] = [0, 5, 6, 13, 16, 20];
+        let album = |track: u16| starts.partition_point(|start| *start <= track) - 1;
+        for seed in 1..100 {
+            let mut order: Vec<u16, 20> = (0..20).collect();
+            shuffle_albums(&mut order, album, &mut Rng::new(seed));
+
+            // every album shows up once, from its first track to its last
+            let mut played = [false; 5];
+            for run in order.chunk_by(|a, b| album(*a) == album(*b)) {
+                let album = album(run[0]);
+                assert!(!played[album], "seed {}: {:?}", seed, order);
+                played[album] = true;
+                assert!(run.iter().copied().eq(starts[album]..starts[album + 1]));
+            }
+            assert!(played.iter().all(|played| *played));
+        }
+    }
+}
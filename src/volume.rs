@@ -0,0 +1,168 @@
+// Shared playback volume and the gain stage that applies it to the dac frames.
+// The volume is a percentage on a logarithmic curve, every step is the same number of decibels
+
+use crate::frame::StereoFrame;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+pub const MAX_VOLUME: u8 = 100;
+// Volume change of a single button press
+pub const VOLUME_STEP: u8 = 5;
+// Attenuation of a single volume percent in centibels, 1% is -59.4dB
+const STEP_CB: i32 = 6;
+// Frames a gain change from mute to full volume is spread over, about 12ms at 44.1khz
+const RAMP_FRAMES: i32 = 512;
+
+pub const ONE_Q15: i32 = 1 << 15;
+// Largest gain the volume stage applies, +6dB keeps `sample * gain` within an i32
+pub const MAX_GAIN_Q15: i32 = 2 * ONE_Q15;
+const ONE_Q16: i64 = 1 << 16;
+// log2(10) / 200, converts centibels to a power of two in Q16
+const CB_TO_LOG2_Q16: i64 = 1_089;
+// ln(2) in Q16
+const LN_2_Q16: i64 = 45_426;
+
+// Volume shared between the playback loop and the ui
+pub static VOLUME: Volume = Volume::new(MAX_VOLUME);
+
+pub struct Volume {
+    level: AtomicU8,
+}
+
+impl Volume {
+    pub const fn new(level: u8) -> Self {
+        Self {
+            level: AtomicU8::new(level),
+        }
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, level: u8) {
+        self.level.store(level.min(MAX_VOLUME), Ordering::Relaxed);
+    }
+
+    pub fn up(&self) {
+        self.set(self.level().saturating_add(VOLUME_STEP));
+    }
+
+    pub fn down(&self) {
+        self.set(self.level().saturating_sub(VOLUME_STEP));
+    }
+}
+
+// Per channel gain, ramped a little every frame so volume changes do not click.
+// It is the volume and the ReplayGain of the track combined
+pub struct Gain {
+    // left and right gain in Q15
+    current: [i32; 2],
+    target: [i32; 2],
+}
+
+impl Gain {
+    // Starts at the given volume and ReplayGain without a ramp
+    pub fn new(level: u8, replay_gain_q15: i32) -> Self {
+        let gain = combine(level, replay_gain_q15);
+        Self {
+            current: [gain; 2],
+            target: [gain; 2],
+        }
+    }
+
+    pub fn set_level(&mut self, level: u8, replay_gain_q15: i32) {
+        self.target = [combine(level, replay_gain_q15); 2];
+    }
+
+    pub fn process(&mut self, frames: &mut [StereoFrame]) {
+        if self.current == self.target && self.target == [ONE_Q15; 2] {
+            return;
+        }
+        self.process_with(frames, |left, right| {
+            StereoFrame::new(clip(left), clip(right))
+        });
+    }
+
+    // Hands the samples to `output` before they are cut to 16bit,
+    // so a later stage can bring boosted samples back down instead of clipping them
+    pub fn process_with(
+        &mut self,
+        frames: &mut [StereoFrame],
+        mut output: impl FnMut(i32, i32) -> StereoFrame,
+    ) {
+        let step = ONE_Q15 / RAMP_FRAMES;
+        for frame in frames.iter_mut() {
+            for (current, target) in self.current.iter_mut().zip(self.target) {
+                *current += (target - *current).clamp(-step, step);
+            }
+
+            let [left, right] = self.current;
+            *frame = output(amplify(frame.left(), left), amplify(frame.right(), right));
+        }
+    }
+}
+
+fn combine(level: u8, replay_gain_q15: i32) -> i32 {
+    let gain = (volume_to_q15(level) as i64 * replay_gain_q15 as i64) >> 15;
+    gain.clamp(0, MAX_GAIN_Q15 as i64) as i32
+}
+
+// Applies a fixed gain of at most `MAX_GAIN_Q15`
+pub fn scale_frames(frames: &mut [StereoFrame], gain_q15: i32) {
+    let gain = gain_q15.clamp(0, MAX_GAIN_Q15);
+    for frame in frames.iter_mut() {
+        *frame = StereoFrame::new(scale(frame.left(), gain), scale(frame.right(), gain));
+    }
+}
+
+fn scale(sample: i16, gain_q15: i32) -> i16 {
+    clip(amplify(sample, gain_q15))
+}
+
+// At most twice full scale with `MAX_GAIN_Q15`
+fn amplify(sample: i16, gain_q15: i32) -> i32 {
+    (sample as i32 * gain_q15 + (1 << 14)) >> 15
+}
+
+pub fn clip(sample: i32) -> i16 {
+    sample.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+// Gain of a volume percentage in Q15, 0 mutes
+pub fn volume_to_q15(level: u8) -> i32 {
+    let level = level.min(MAX_VOLUME);
+    if level == 0 {
+        return 0;
+    }
+    let attenuation_cb = (MAX_VOLUME - level) as i32 * STEP_CB;
+    centibels_to_q15(-attenuation_cb)
+}
+
+// Gain of `cb` centibels in Q15, positive values amplify
+pub fn centibels_to_q15(cb: i32) -> i32 {
+    let x = cb as i64 * CB_TO_LOG2_Q16;
+    if x <= 0 {
+        return exp2_neg_q15(-x);
+    }
+    // 2^x = 2^whole * 2^-(whole - x) with x rounded up to the whole number
+    let whole = (x + ONE_Q16 - 1) >> 16;
+    exp2_neg_q15((whole << 16) - x) << whole
+}
+
+// 2^-x with x in Q16 and the result in Q15
+fn exp2_neg_q15(x: i64) -> i32 {
+    let (whole, fraction) = (x >> 16, x & (ONE_Q16 - 1));
+    if whole >= 15 {
+        return 0;
+    }
+
+    // taylor series of e^(-fraction * ln 2), accurate to ~0.1% for fractions below 1
+    let a = fraction * LN_2_Q16 / ONE_Q16;
+    let mut term = ONE_Q16;
+    let mut sum = ONE_Q16;
+    for divisor in 1..=4 {
+        term = -term * a / ONE_Q16 / divisor;
+        sum += term;
+    }
+    ((sum >> 1) >> whole) as i32
+}
@@ -0,0 +1,162 @@
+#!/usr/bin/env python3
+"""Prints or checks a library index written by the player (LIBRARY0.IDX or LIBRARY1.IDX).
+
+    libdump.py LIBRARY0.IDX           prints the artists, albums and songs
+    libdump.py --check LIBRARY0.IDX   only checks the files, exits with 1 when they are broken
+
+The names are read from the names file next to the index, LIBRARY0.NAM for LIBRARY0.IDX.
+The layout is described at the top of src/index.rs.
+"""
+
+import argparse
+import struct
+import sys
+
+VERSION = 4
+MAGIC = b"PPLI"
+END_MAGIC = b"PPLE"
+NAMES_MAGIC = b"PPLN"
+HEADER = struct.Struct("<4sHHI")
+NAMES_HEADER = struct.Struct("<4sI")
+FIELDS = struct.Struct("<5IH11s")
+LEVELS = ["artists", "albums", "songs"]
+
+
+class BrokenIndex(Exception):
+    pass
+
+
+def parse(data, names):
+    if len(data) < HEADER.size:
+        raise BrokenIndex("shorter than the header")
+    magic, version, record_len, generation = HEADER.unpack_from(data)
+    if magic != MAGIC:
+        raise BrokenIndex(f"bad magic {magic!r}")
+    if version != VERSION:
+        raise BrokenIndex(f"version {version}, expected {VERSION}")
+    if record_len < FIELDS.size:
+        raise BrokenIndex(f"record length {record_len} is too short")
+
+    position = HEADER.size
+    sections = []
+    for level in LEVELS:
+        if position + 4 > len(data):
+            raise BrokenIndex(f"{level} section is cut short")
+        (count,) = struct.unpack_from("<I", data, position)
+        position += 4
+        end = position + count * record_len
+        if end > len(data):
+            raise BrokenIndex(f"{level} section is cut short")
+        sections.append([FIELDS.unpack_from(data, at) for at in range(position, end, record_len)])
+        position = end
+    if data[position + 4:position + 8] != END_MAGIC:
+        raise BrokenIndex("trailer missing, writing the index was cut short")
+    if position + 8 != len(data):
+        raise BrokenIndex(f"{len(data) - position - 8} bytes after the trailer")
+    (names_len,) = struct.unpack_from("<I", data, position)
+
+    if len(names) < NAMES_HEADER.size:
+        raise BrokenIndex("names file is shorter than its header")
+    names_magic, names_generation = NAMES_HEADER.unpack_from(names)
+    if names_magic != NAMES_MAGIC:
+        raise BrokenIndex(f"bad names magic {names_magic!r}")
+    if names_generation != generation:
+        raise BrokenIndex(f"names are of generation {names_generation}, the index of {generation}")
+    names = names[NAMES_HEADER.size:]
+    if len(names) != names_len:
+        raise BrokenIndex(f"{len(names)} bytes of names, the index has {names_len}")
+    sections = [[record(fields, names) for fields in section] for section in sections]
+    return generation, record_len, sections
+
+
+def record(fields, names):
+    parent, first, count, modified, name_offset, name_len, short_name = fields
+    if name_offset + name_len > len(names):
+        raise BrokenIndex(f"name at {name_offset} of {name_len} bytes is past the names")
+    name = names[name_offset:name_offset + name_len]
+    return {
+        "parent": parent,
+        "first": first,
+        "count": count,
+        "modified": modified,
+        "name": name.decode("utf-8"),
+        "short_name": short(short_name),
+    }
+
+
+# "NAME.EXT" the way the player opens it, the bytes above ascii taken as latin-1
+def short(raw):
+    base, extension = raw[:8].rstrip(b" "), raw[8:].rstrip(b" ")
+    return (base + b"." + extension if extension else base).decode("latin-1")
+
+
+# The albums of an artist and the songs of an album follow each other in the next section,
+# in the order of their parents, and point back at them
+def check(sections):
+    problems = []
+    for level in range(len(LEVELS) - 1):
+        parents, children = sections[level], sections[level + 1]
+        expected = 0
+        for index, parent in enumerate(parents):
+            if parent["first"] != expected:
+                problems.append(f"{LEVELS[level]}[{index}] starts at {parent['first']}, expected {expected}")
+            for child in range(parent["first"], parent["first"] + parent["count"]):
+                if child >= len(children):
+                    problems.append(f"{LEVELS[level]}[{index}] has {LEVELS[level + 1]}[{child}] past the end")
+                elif children[child]["parent"] != index:
+                    problems.append(f"{LEVELS[level + 1]}[{child}] points at {children[child]['parent']}, expected {index}")
+            expected = parent["first"] + parent["count"]
+        if expected != len(children):
+            problems.append(f"{LEVELS[level]} have {expected} {LEVELS[level + 1]}, the section has {len(children)}")
+    return problems
+
+
+def modified(packed):
+    year = 1970 + (packed >> 25)
+    month, day = packed >> 21 & 0xF, packed >> 16 & 0x1F
+    hours, minutes, seconds = packed >> 11 & 0x1F, packed >> 5 & 0x3F, (packed & 0x1F) * 2
+    return f"{year:04}-{month:02}-{day:02} {hours:02}:{minutes:02}:{seconds:02}"
+
+
+def dump(generation, record_len, sections):
+    artists, albums, songs = sections
+    print(f"generation {generation}, {record_len} byte records, "
+          f"{len(artists)} artists, {len(albums)} albums, {len(songs)} songs")
+    for artist in artists:
+        print(f"{artist['name']}  [{artist['short_name']}]")
+        for album in albums[artist["first"]:artist["first"] + artist["count"]]:
+            print(f"  {album['name']}  [{album['short_name']}, "
+                  f"{modified(album['modified'])}]")
+            for song in songs[album["first"]:album["first"] + album["count"]]:
+                print(f"    {song['name']}  [{song['short_name']}]")
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("--check", action="store_true", help="only check the index")
+    parser.add_argument("--names", help="names file, found next to the index when not given")
+    parser.add_argument("index")
+    args = parser.parse_args()
+
+    names_path = args.names or args.index[:-3] + ("nam" if args.index[-3:].islower() else "NAM")
+    with open(args.index, "rb") as file:
+        data = file.read()
+    with open(names_path, "rb") as file:
+        names = file.read()
+    try:
+        generation, record_len, sections = parse(data, names)
+    except (BrokenIndex, UnicodeDecodeError) as e:
+        print(f"{args.index}: {e}", file=sys.stderr)
+        return 1
+    problems = check(sections)
+    for problem in problems:
+        print(f"{args.index}: {problem}", file=sys.stderr)
+    if not args.check:
+        dump(generation, record_len, sections)
+    elif not problems:
+        print(f"{args.index}: ok")
+    return 1 if problems else 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())